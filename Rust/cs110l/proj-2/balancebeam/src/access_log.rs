@@ -0,0 +1,261 @@
+use crate::cache;
+use chrono::{DateTime, Local};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How access log records are written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    /// Common Log Format, followed by the upstream, the request body size, the upstream latency in
+    /// milliseconds and how the response cache was involved
+    Common,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "clf" => Ok(Format::Common),
+            _ => Err(format!(
+                "unknown access log format {:?} (expected json or clf)",
+                s
+            )),
+        }
+    }
+}
+
+/// One request and the response that was sent back for it
+pub struct Record<'a> {
+    pub time: DateTime<Local>,
+    pub client_ip: IpAddr,
+    /// The upstream the request was sent to, or None if balancebeam answered it itself
+    pub upstream: Option<&'a str>,
+    pub request: &'a http::Request<Vec<u8>>,
+    pub status: http::StatusCode,
+    /// Size of the request body (not counting chunk framing)
+    pub bytes_in: u64,
+    /// Size of the response body (not counting chunk framing), or None if it couldn't be sent in
+    /// full
+    pub bytes_out: Option<u64>,
+    /// Time from receiving the request to receiving the head of the upstream's response
+    pub upstream_latency: Option<Duration>,
+    /// How the response cache was involved, or None if the request wasn't one the cache could
+    /// answer
+    pub cache: Option<cache::Status>,
+}
+
+impl Record<'_> {
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "client_ip": self.client_ip.to_string(),
+            "upstream": self.upstream,
+            "method": self.request.method().as_str(),
+            "uri": self.request.uri().to_string(),
+            "version": format!("{:?}", self.request.version()),
+            "status": self.status.as_u16(),
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "upstream_latency_ms": self.upstream_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            "cache": self.cache.map(|status| status.as_str()),
+        })
+        .to_string()
+    }
+
+    fn to_common_log_format(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} {} {} {} {}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request.method(),
+            self.request.uri(),
+            self.request.version(),
+            self.status.as_u16(),
+            optional(self.bytes_out.map(|bytes| bytes.to_string())),
+            self.upstream.unwrap_or("-"),
+            self.bytes_in,
+            optional(
+                self.upstream_latency
+                    .map(|latency| format!("{:.3}", latency.as_secs_f64() * 1000.0))
+            ),
+            self.cache.map_or("-", |status| status.as_str()),
+        )
+    }
+}
+
+/// The file currently being written to
+struct CurrentFile {
+    file: File,
+    size: u64,
+}
+
+/// Writes access log records to a file, rotating it when it gets too big. When the file would go
+/// over `max_size` bytes, it is renamed to `<path>.1` (and `<path>.1` to `<path>.2`, and so on,
+/// keeping at most `max_files` old files) and a new file is started.
+pub struct AccessLog {
+    path: PathBuf,
+    format: Format,
+    max_size: u64,
+    max_files: usize,
+    current: Mutex<CurrentFile>,
+}
+
+impl AccessLog {
+    pub fn open(
+        path: &Path,
+        format: Format,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<AccessLog, std::io::Error> {
+        let current = open_file(path)?;
+        Ok(AccessLog {
+            path: path.to_path_buf(),
+            format,
+            max_size,
+            max_files,
+            current: Mutex::new(current),
+        })
+    }
+
+    pub fn write(&self, record: &Record) {
+        let mut line = match self.format {
+            Format::Json => record.to_json(),
+            Format::Common => record.to_common_log_format(),
+        };
+        line.push('\n');
+
+        let mut current = self.current.lock();
+        if current.size > 0 && current.size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(file) => *current = file,
+                Err(error) => log::error!("Failed to rotate access log: {}", error),
+            }
+        }
+        match current.file.write_all(line.as_bytes()) {
+            Ok(()) => current.size += line.len() as u64,
+            Err(error) => log::error!("Failed to write to access log: {}", error),
+        }
+    }
+
+    /// Shifts the old log files along by one, moves the current file to `<path>.1` and opens a new
+    /// file. The oldest file is deleted if there would be more than max_files old files.
+    fn rotate(&self) -> Result<CurrentFile, std::io::Error> {
+        let rotated_path = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(1))?;
+        }
+        open_file(&self.path)
+    }
+}
+
+fn open_file(path: &Path) -> Result<CurrentFile, std::io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(CurrentFile { file, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn request() -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method(http::Method::POST)
+            .uri("/upload?name=x")
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    fn record(request: &http::Request<Vec<u8>>) -> Record<'_> {
+        Record {
+            time: Local.with_ymd_and_hms(2020, 10, 10, 13, 55, 36).unwrap(),
+            client_ip: "10.1.2.3".parse().unwrap(),
+            upstream: Some("127.0.0.1:8080"),
+            request,
+            status: http::StatusCode::CREATED,
+            bytes_in: 12,
+            bytes_out: Some(34),
+            upstream_latency: Some(Duration::from_micros(1500)),
+            cache: Some(cache::Status::Miss),
+        }
+    }
+
+    #[test]
+    fn formats_json_records() {
+        let request = request();
+        let json: serde_json::Value = serde_json::from_str(&record(&request).to_json()).unwrap();
+        assert_eq!(json["client_ip"], "10.1.2.3");
+        assert_eq!(json["upstream"], "127.0.0.1:8080");
+        assert_eq!(json["method"], "POST");
+        assert_eq!(json["uri"], "/upload?name=x");
+        assert_eq!(json["status"], 201);
+        assert_eq!(json["bytes_in"], 12);
+        assert_eq!(json["bytes_out"], 34);
+        assert_eq!(json["upstream_latency_ms"], 1.5);
+        assert_eq!(json["cache"], "miss");
+    }
+
+    #[test]
+    fn formats_common_log_format_records() {
+        let request = request();
+        let line = record(&request).to_common_log_format();
+        assert!(line.starts_with("10.1.2.3 - - [10/Oct/2020:13:55:36 "));
+        assert!(
+            line.ends_with("\"POST /upload?name=x HTTP/1.1\" 201 34 127.0.0.1:8080 12 1.500 miss")
+        );
+
+        let mut record = record(&request);
+        record.upstream = None;
+        record.bytes_out = None;
+        record.upstream_latency = None;
+        record.cache = None;
+        assert!(record.to_common_log_format().ends_with("\" 201 - - 12 - -"));
+    }
+
+    #[test]
+    fn rotates_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "balancebeam-access-log-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let request = request();
+        let line_len = record(&request).to_common_log_format().len() as u64 + 1;
+
+        // Room for two records per file, and two old files
+        let log = AccessLog::open(&path, Format::Common, 2 * line_len, 2).unwrap();
+        for _ in 0..7 {
+            log.write(&record(&request));
+        }
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.join("access.log.1")), 2);
+        assert_eq!(lines(&dir.join("access.log.2")), 2);
+        assert!(!dir.join("access.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+use crate::config::{self, Config, Timeouts, UpstreamConfig};
+use crate::{body, request, response, timeout};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+
+/// Largest request body the admin API accepts. Bodies are small JSON documents.
+const MAX_BODY_SIZE: u64 = 4096;
+
+/// Something asked for through the admin API
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// List the upstreams and their state
+    List,
+    /// Add an upstream to a pool
+    Add {
+        address: String,
+        weight: u32,
+        pool: String,
+    },
+    /// Stop sending requests to an upstream and forget about it
+    Remove { address: String },
+    /// Stop sending new requests to an upstream, but let the ones it is handling finish
+    Drain { address: String },
+    /// Start sending new requests to a drained upstream again
+    Undrain { address: String },
+    /// Change how many requests an upstream gets relative to the others in its pool
+    SetWeight { address: String, weight: u32 },
+}
+
+/// Why an admin request failed
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The request is missing its bearer token, or has the wrong one
+    Unauthorized,
+    /// There is no such endpoint, or no such upstream or pool
+    NotFound(String),
+    /// The request is malformed, or the change would leave the configuration invalid
+    Invalid(String),
+}
+
+impl Error {
+    fn status(&self) -> http::StatusCode {
+        match self {
+            Error::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            Error::NotFound(_) => http::StatusCode::NOT_FOUND,
+            Error::Invalid(_) => http::StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Unauthorized => write!(f, "missing or incorrect bearer token"),
+            Error::NotFound(message) | Error::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// An upstream as reported by the admin API
+#[derive(Debug, Serialize)]
+pub struct UpstreamInfo {
+    pub address: String,
+    pub pool: String,
+    pub weight: u32,
+    /// False if we couldn't connect to the upstream or it failed its last health check
+    pub healthy: bool,
+    /// True if the upstream has been drained through the admin API
+    pub draining: bool,
+    pub active_connections: usize,
+    /// State of the upstream's circuit breaker (closed, open or half_open)
+    pub circuit_state: &'static str,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddBody {
+    address: String,
+    weight: Option<u32>,
+    pool: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WeightBody {
+    weight: u32,
+}
+
+fn parse_json<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|err| Error::Invalid(format!("invalid body: {}", err)))
+}
+
+/// Works out what a request to the admin API is asking for
+fn parse_command(request: &http::Request<Vec<u8>>, body: &[u8]) -> Result<Command, Error> {
+    let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+    let method = request.method();
+    match segments.as_slice() {
+        ["upstreams"] if method == http::Method::GET => Ok(Command::List),
+        ["upstreams"] if method == http::Method::POST => {
+            let body: AddBody = parse_json(body)?;
+            Ok(Command::Add {
+                address: body.address,
+                weight: body.weight.unwrap_or(1),
+                pool: body
+                    .pool
+                    .unwrap_or_else(|| config::DEFAULT_POOL.to_string()),
+            })
+        }
+        ["upstreams", address] if method == http::Method::DELETE => Ok(Command::Remove {
+            address: address.to_string(),
+        }),
+        ["upstreams", address, "drain"] if method == http::Method::POST => Ok(Command::Drain {
+            address: address.to_string(),
+        }),
+        ["upstreams", address, "drain"] if method == http::Method::DELETE => Ok(Command::Undrain {
+            address: address.to_string(),
+        }),
+        ["upstreams", address, "weight"] if method == http::Method::PUT => {
+            let body: WeightBody = parse_json(body)?;
+            Ok(Command::SetWeight {
+                address: address.to_string(),
+                weight: body.weight,
+            })
+        }
+        _ => Err(Error::NotFound(format!(
+            "no such endpoint: {} {}",
+            method,
+            request.uri().path()
+        ))),
+    }
+}
+
+/// Returns every pool's upstream list, starting with the default pool
+fn upstream_lists(config: &mut Config) -> impl Iterator<Item = &mut Vec<UpstreamConfig>> {
+    std::iter::once(&mut config.upstreams).chain(config.pools.values_mut())
+}
+
+impl Command {
+    /// Makes the change to the upstreams in `config`. Listing, draining and undraining upstreams
+    /// don't change the config. The result still needs validating.
+    pub fn apply(&self, config: &mut Config) -> Result<(), Error> {
+        let not_found = |address: &str| Error::NotFound(format!("no upstream {}", address));
+        match self {
+            Command::List | Command::Drain { .. } | Command::Undrain { .. } => Ok(()),
+            Command::Add {
+                address,
+                weight,
+                pool,
+            } => {
+                if config
+                    .all_upstreams()
+                    .any(|upstream| &upstream.address == address)
+                {
+                    return Err(Error::Invalid(format!(
+                        "{} is already an upstream",
+                        address
+                    )));
+                }
+                let upstreams = if pool == config::DEFAULT_POOL {
+                    &mut config.upstreams
+                } else {
+                    config
+                        .pools
+                        .get_mut(pool)
+                        .ok_or_else(|| Error::NotFound(format!("no pool {}", pool)))?
+                };
+                upstreams.push(UpstreamConfig {
+                    address: address.clone(),
+                    weight: *weight,
+                });
+                Ok(())
+            }
+            Command::Remove { address } => {
+                let mut found = false;
+                for upstreams in upstream_lists(config) {
+                    let before = upstreams.len();
+                    upstreams.retain(|upstream| &upstream.address != address);
+                    found |= upstreams.len() != before;
+                }
+                if found {
+                    Ok(())
+                } else {
+                    Err(not_found(address))
+                }
+            }
+            Command::SetWeight { address, weight } => {
+                let upstream = upstream_lists(config)
+                    .flat_map(|upstreams| upstreams.iter_mut())
+                    .find(|upstream| &upstream.address == address)
+                    .ok_or_else(|| not_found(address))?;
+                upstream.weight = *weight;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compares a bearer token with the expected one without stopping at the first difference, so that
+/// response times don't give away how much of a guess was right
+fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn authorize(request: &http::Request<Vec<u8>>, token: &str) -> Result<(), Error> {
+    let given = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+    if token_matches(given.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized)
+    }
+}
+
+fn json_response<T: Serialize>(status: http::StatusCode, value: &T) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(value).unwrap();
+    let mut builder = http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string());
+    if status == http::StatusCode::UNAUTHORIZED {
+        builder = builder.header("WWW-Authenticate", "Bearer");
+    }
+    builder.body(body).unwrap()
+}
+
+fn error_response(request: &http::Request<Vec<u8>>, error: &Error) -> http::Response<Vec<u8>> {
+    log::info!(
+        "Admin API request {} {} failed: {}",
+        request.method(),
+        request.uri(),
+        error
+    );
+    json_response(
+        error.status(),
+        &serde_json::json!({ "error": error.to_string() }),
+    )
+}
+
+/// Serves the admin API on `listener`, to clients that present `token` as a bearer token.
+/// `timeouts` returns the client timeouts currently configured, which apply to admin clients too.
+/// `handle` carries out each command and returns the upstreams as they are afterwards. This never
+/// returns, so it should be spawned as a separate task.
+pub async fn serve<T, F, R>(mut listener: TcpListener, token: String, timeouts: T, handle: F)
+where
+    T: Fn() -> Timeouts + Send + Sync + 'static,
+    F: Fn(Command) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<UpstreamInfo>, Error>> + Send,
+{
+    let token = Arc::new(token);
+    let timeouts = Arc::new(timeouts);
+    let handle = Arc::new(handle);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let token = token.clone();
+            let timeouts = timeouts.clone();
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                handle_admin_connection(stream, &token, timeouts.as_ref(), handle.as_ref()).await;
+            });
+        }
+    }
+}
+
+/// Answers requests on a connection to the admin listener until the client hangs up, stops sending
+/// or sends a request we can't answer and carry on
+async fn handle_admin_connection<T, F, R>(
+    mut stream: TcpStream,
+    token: &str,
+    timeouts: &T,
+    handle: &F,
+) where
+    T: Fn() -> Timeouts,
+    F: Fn(Command) -> R,
+    R: Future<Output = Result<Vec<UpstreamInfo>, Error>>,
+{
+    // Bytes read past the end of one request, which are the start of the next
+    let mut buffered = Vec::new();
+    loop {
+        let timeouts = timeouts();
+
+        // Hang up on clients that keep the connection open without starting another request
+        if buffered.is_empty()
+            && timeout::optional(timeouts.keep_alive_idle, stream.peek(&mut [0; 1]))
+                .await
+                .is_err()
+        {
+            log::debug!("Admin connection was idle for too long. Shutting down connection");
+            return;
+        }
+
+        let read = timeout::optional(
+            timeouts.header_read,
+            request::read_from_stream(&mut stream, &mut buffered, Some(MAX_BODY_SIZE)),
+        )
+        .await;
+        let (request, framing) = match read {
+            Ok(Ok(request)) => request,
+            Ok(Err(request::Error::RequestBodyTooLarge)) => {
+                let response = response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE);
+                let _ = response::write_to_stream(&response, &mut stream).await;
+                return;
+            }
+            Ok(Err(_)) => return,
+            // The client started a request but is taking too long to finish sending its headers
+            Err(_) => {
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                let _ = response::write_to_stream(&response, &mut stream).await;
+                return;
+            }
+        };
+
+        // Check the token before reading the body, so that nobody without it can make us wait for
+        // one. We don't read the body of a refused request at all, so we can't find the start of
+        // the next request after it and have to hang up.
+        if let Err(error) = authorize(&request, token) {
+            let mut response = error_response(&request, &error);
+            let has_body = framing != body::Framing::Empty;
+            if has_body {
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+            }
+            if response::write_to_stream(&response, &mut stream)
+                .await
+                .is_err()
+                || has_body
+            {
+                return;
+            }
+            continue;
+        }
+
+        // Chunked bodies would need decoding, and nobody needs them for a few bytes of JSON
+        if framing == body::Framing::Chunked {
+            let response = response::make_http_error(http::StatusCode::LENGTH_REQUIRED);
+            let _ = response::write_to_stream(&response, &mut stream).await;
+            return;
+        }
+        let mut body = Vec::new();
+        if body::forward(
+            &mut stream,
+            request.body(),
+            framing,
+            &mut body,
+            Some(MAX_BODY_SIZE),
+            timeouts.body_read,
+            &mut buffered,
+        )
+        .await
+        .is_err()
+        {
+            return;
+        }
+
+        let result = match parse_command(&request, &body) {
+            Ok(command) => {
+                log::info!("Admin API: {:?}", command);
+                handle(command).await
+            }
+            Err(error) => Err(error),
+        };
+        let response = match result {
+            Ok(upstreams) => json_response(http::StatusCode::OK, &upstreams),
+            Err(error) => error_response(&request, &error),
+        };
+        if response::write_to_stream(&response, &mut stream)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("Authorization", "Bearer s3cret")
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    fn config() -> Config {
+        let mut config = Config {
+            upstreams: vec!["10.0.0.1:80".parse().unwrap()],
+            ..crate::config::tests::base_config()
+        };
+        config
+            .pools
+            .insert("api".to_string(), vec!["10.0.1.1:80".parse().unwrap()]);
+        config
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse_command(&request("GET", "/upstreams"), b""),
+            Ok(Command::List)
+        );
+        assert_eq!(
+            parse_command(
+                &request("POST", "/upstreams"),
+                br#"{"address": "10.0.0.2:80"}"#
+            ),
+            Ok(Command::Add {
+                address: "10.0.0.2:80".to_string(),
+                weight: 1,
+                pool: "default".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command(&request("DELETE", "/upstreams/10.0.0.1:80"), b""),
+            Ok(Command::Remove {
+                address: "10.0.0.1:80".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command(&request("POST", "/upstreams/10.0.0.1:80/drain"), b""),
+            Ok(Command::Drain {
+                address: "10.0.0.1:80".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command(&request("DELETE", "/upstreams/10.0.0.1:80/drain"), b""),
+            Ok(Command::Undrain {
+                address: "10.0.0.1:80".to_string()
+            })
+        );
+        assert_eq!(
+            parse_command(
+                &request("PUT", "/upstreams/10.0.0.1:80/weight"),
+                br#"{"weight": 4}"#
+            ),
+            Ok(Command::SetWeight {
+                address: "10.0.0.1:80".to_string(),
+                weight: 4
+            })
+        );
+        assert!(matches!(
+            parse_command(&request("GET", "/upstreams/10.0.0.1:80"), b""),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            parse_command(&request("PUT", "/upstreams/10.0.0.1:80/weight"), b"{}"),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn checks_bearer_token() {
+        assert_eq!(authorize(&request("GET", "/upstreams"), "s3cret"), Ok(()));
+        assert_eq!(
+            authorize(&request("GET", "/upstreams"), "s3cre7"),
+            Err(Error::Unauthorized)
+        );
+        assert_eq!(
+            authorize(&request("GET", "/upstreams"), "s3cret!"),
+            Err(Error::Unauthorized)
+        );
+        let mut anonymous = request("GET", "/upstreams");
+        anonymous.headers_mut().remove("Authorization");
+        assert_eq!(authorize(&anonymous, "s3cret"), Err(Error::Unauthorized));
+    }
+
+    #[test]
+    fn changes_upstreams_in_config() {
+        let mut config = config();
+        Command::Add {
+            address: "10.0.1.2:80".to_string(),
+            weight: 2,
+            pool: "api".to_string(),
+        }
+        .apply(&mut config)
+        .unwrap();
+        assert_eq!(config.pools["api"][1].weight, 2);
+        Command::SetWeight {
+            address: "10.0.0.1:80".to_string(),
+            weight: 3,
+        }
+        .apply(&mut config)
+        .unwrap();
+        assert_eq!(config.upstreams[0].weight, 3);
+        Command::Remove {
+            address: "10.0.1.1:80".to_string(),
+        }
+        .apply(&mut config)
+        .unwrap();
+        assert_eq!(config.pools["api"].len(), 1);
+
+        let add = |address: &str, pool: &str| Command::Add {
+            address: address.to_string(),
+            weight: 1,
+            pool: pool.to_string(),
+        };
+        assert!(matches!(
+            add("10.0.0.1:80", "default").apply(&mut config),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            add("10.0.0.3:80", "web").apply(&mut config),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            Command::Remove {
+                address: "10.9.9.9:80".to_string()
+            }
+            .apply(&mut config),
+            Err(Error::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::upstream::Upstream;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of points each upstream gets on the consistent hashing ring. More points spread keys
+/// more evenly at the cost of a bigger ring.
+const POINTS_PER_UPSTREAM: usize = 160;
+
+/// Information about the request being balanced that a strategy may use to pick an upstream
+pub struct Context<'a> {
+    pub client_ip: IpAddr,
+    pub headers: &'a http::HeaderMap,
+}
+
+/// A policy for choosing which upstream should handle a request
+pub trait Balancer: Send + Sync {
+    /// Picks one of `upstreams` to handle the request described by `context`, returning its index.
+    /// `upstreams` only contains live upstreams and is never empty.
+    fn choose(&self, upstreams: &[&Upstream], context: &Context) -> usize;
+}
+
+/// The balancing strategies that can be selected on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    WeightedRandom,
+    ConsistentHash,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "weighted-random" => Ok(Strategy::WeightedRandom),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            _ => Err(format!(
+                "unknown balancing strategy {:?} (expected random, round-robin, \
+                least-connections, weighted-random or consistent-hash)",
+                s
+            )),
+        }
+    }
+}
+
+/// Creates a balancer for the given strategy. `hash_header` is only used by consistent hashing;
+/// if it is None, requests are hashed on the client's IP address instead.
+pub fn new_balancer(
+    strategy: Strategy,
+    hash_header: Option<http::HeaderName>,
+) -> Box<dyn Balancer> {
+    match strategy {
+        Strategy::Random => Box::new(Random),
+        Strategy::RoundRobin => Box::new(RoundRobin::default()),
+        Strategy::LeastConnections => Box::new(LeastConnections),
+        Strategy::WeightedRandom => Box::new(WeightedRandom),
+        Strategy::ConsistentHash => Box::new(ConsistentHash::new(hash_header)),
+    }
+}
+
+/// Picks an upstream uniformly at random
+pub struct Random;
+
+impl Balancer for Random {
+    fn choose(&self, upstreams: &[&Upstream], _context: &Context) -> usize {
+        rand::thread_rng().gen_range(0, upstreams.len())
+    }
+}
+
+/// Cycles through the upstreams in order
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Balancer for RoundRobin {
+    fn choose(&self, upstreams: &[&Upstream], _context: &Context) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len()
+    }
+}
+
+/// Picks the upstream with the fewest connections currently being proxied to it, breaking ties at
+/// random so that idle upstreams share the load evenly
+pub struct LeastConnections;
+
+impl Balancer for LeastConnections {
+    fn choose(&self, upstreams: &[&Upstream], _context: &Context) -> usize {
+        let fewest = upstreams
+            .iter()
+            .map(|upstream| upstream.active_connections())
+            .min()
+            .unwrap();
+        let candidates: Vec<usize> = (0..upstreams.len())
+            .filter(|idx| upstreams[*idx].active_connections() == fewest)
+            .collect();
+        // The counts may have changed since we computed the minimum. That only makes the choice
+        // slightly less precise, but candidates could be empty, in which case take anyone.
+        if candidates.is_empty() {
+            return 0;
+        }
+        candidates[rand::thread_rng().gen_range(0, candidates.len())]
+    }
+}
+
+/// Picks an upstream at random, with each upstream's chance proportional to its weight
+pub struct WeightedRandom;
+
+impl Balancer for WeightedRandom {
+    fn choose(&self, upstreams: &[&Upstream], _context: &Context) -> usize {
+        // Weights can change at any time, so read each one only once
+        let weights: Vec<u64> = upstreams
+            .iter()
+            .map(|upstream| upstream.weight() as u64)
+            .collect();
+        let mut target = rand::thread_rng().gen_range(0, weights.iter().sum::<u64>());
+        for (idx, weight) in weights.into_iter().enumerate() {
+            if target < weight {
+                return idx;
+            }
+            target -= weight;
+        }
+        upstreams.len() - 1
+    }
+}
+
+/// Maps each request onto a hash ring so that requests with the same key (client IP or header
+/// value) keep going to the same upstream, and adding or removing an upstream only moves the keys
+/// that belonged to it
+pub struct ConsistentHash {
+    header: Option<http::HeaderName>,
+    ring: Mutex<Ring>,
+}
+
+/// Hash ring built for a particular set of upstreams
+#[derive(Default)]
+struct Ring {
+    /// Addresses of the upstreams the ring was built for, in the order they were passed to
+    /// choose()
+    addresses: Vec<String>,
+    /// Points on the ring, sorted by hash. Each point holds an index into `addresses`.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(addresses: Vec<String>) -> Ring {
+        let mut points = Vec::with_capacity(addresses.len() * POINTS_PER_UPSTREAM);
+        for (idx, address) in addresses.iter().enumerate() {
+            for point in 0..POINTS_PER_UPSTREAM {
+                points.push((hash(format!("{}#{}", address, point).as_bytes()), idx));
+            }
+        }
+        points.sort_unstable();
+        Ring { addresses, points }
+    }
+
+    /// Returns the index of the upstream that owns the given key: the first point at or after the
+    /// key's hash, wrapping around to the start of the ring
+    fn lookup(&self, key: &[u8]) -> usize {
+        let key_hash = hash(key);
+        let idx = match self.points.binary_search(&(key_hash, 0)) {
+            Ok(idx) | Err(idx) => idx % self.points.len(),
+        };
+        self.points[idx].1
+    }
+}
+
+impl ConsistentHash {
+    pub fn new(header: Option<http::HeaderName>) -> ConsistentHash {
+        ConsistentHash {
+            header,
+            ring: Mutex::new(Ring::default()),
+        }
+    }
+}
+
+impl Balancer for ConsistentHash {
+    fn choose(&self, upstreams: &[&Upstream], context: &Context) -> usize {
+        let client_ip = context.client_ip.to_string();
+        let key = self
+            .header
+            .as_ref()
+            .and_then(|header| context.headers.get(header))
+            .map(|value| value.as_bytes())
+            .unwrap_or_else(|| client_ip.as_bytes());
+
+        // The set of live upstreams changes rarely, so keep the ring around and only rebuild it
+        // when it does
+        let mut ring = self.ring.lock();
+        if !ring
+            .addresses
+            .iter()
+            .eq(upstreams.iter().map(|upstream| &upstream.address))
+        {
+            *ring = Ring::new(
+                upstreams
+                    .iter()
+                    .map(|upstream| upstream.address.clone())
+                    .collect(),
+            );
+        }
+        ring.lookup(key)
+    }
+}
+
+/// 64-bit FNV-1a, followed by a final mixing step so that similar inputs (like "host#1" and
+/// "host#2") land far apart on the ring
+fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(specs: &[&str]) -> Vec<Upstream> {
+        specs
+            .iter()
+            .map(|spec| Upstream::new(&spec.parse().unwrap()))
+            .collect()
+    }
+
+    fn choose_with_header(balancer: &dyn Balancer, upstreams: &[&Upstream], key: &str) -> usize {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-user", key.parse().unwrap());
+        let context = Context {
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            headers: &headers,
+        };
+        balancer.choose(upstreams, &context)
+    }
+
+    #[test]
+    fn round_robin_cycles_through_upstreams() {
+        let upstreams = upstreams(&["a:80", "b:80", "c:80"]);
+        let upstreams: Vec<&Upstream> = upstreams.iter().collect();
+        let balancer = new_balancer(Strategy::RoundRobin, None);
+        let choices: Vec<usize> = (0..6)
+            .map(|i| choose_with_header(&*balancer, &upstreams, &i.to_string()))
+            .collect();
+        assert_eq!(choices, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn least_connections_avoids_busy_upstreams() {
+        let upstreams = upstreams(&["a:80", "b:80", "c:80"]);
+        let _busy = [
+            upstreams[0].track_connection(),
+            upstreams[2].track_connection(),
+        ];
+        let upstreams: Vec<&Upstream> = upstreams.iter().collect();
+        let balancer = new_balancer(Strategy::LeastConnections, None);
+        for i in 0..10 {
+            assert_eq!(
+                choose_with_header(&*balancer, &upstreams, &i.to_string()),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_random_never_picks_beyond_weights() {
+        let upstreams = upstreams(&["a:80=1", "b:80=1000000"]);
+        let upstreams: Vec<&Upstream> = upstreams.iter().collect();
+        let balancer = new_balancer(Strategy::WeightedRandom, None);
+        let picked_b = (0..100)
+            .filter(|i| choose_with_header(&*balancer, &upstreams, &i.to_string()) == 1)
+            .count();
+        assert!(picked_b >= 99);
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_keys_of_removed_upstream() {
+        let all = upstreams(&["a:80", "b:80", "c:80", "d:80"]);
+        let all_refs: Vec<&Upstream> = all.iter().collect();
+        let without_c: Vec<&Upstream> = all.iter().filter(|u| u.address != "c:80").collect();
+        let balancer = new_balancer(Strategy::ConsistentHash, Some("x-user".parse().unwrap()));
+
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("user-{}", i);
+            let before = &all_refs[choose_with_header(&*balancer, &all_refs, &key)].address;
+            let after = &without_c[choose_with_header(&*balancer, &without_c, &key)].address;
+            // Asking again gives the same answer
+            assert_eq!(
+                &without_c[choose_with_header(&*balancer, &without_c, &key)].address,
+                after
+            );
+            if before != "c:80" {
+                assert_eq!(before, after, "key {} moved off a live upstream", key);
+            } else {
+                moved += 1;
+            }
+        }
+        // Each upstream should own a reasonable share of the keys
+        assert!(moved > 100 && moved < 400, "c:80 owned {} keys", moved);
+    }
+}
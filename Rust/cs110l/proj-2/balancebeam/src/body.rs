@@ -0,0 +1,414 @@
+use crate::{chunked, timeout};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// How many bytes we read from a stream at a time while forwarding a body. This bounds the memory
+/// used for each body in flight, however big the body is.
+const BUFFER_SIZE: usize = 8192;
+
+/// How the end of a message body is marked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes long
+    Length(u64),
+    /// The body is sent with chunked transfer coding and ends with a zero-length chunk
+    Chunked,
+    /// The body ends when the sender closes the connection (only possible for responses)
+    UntilClose,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Encountered an I/O error when reading the body from the sender
+    Read(std::io::Error),
+    /// Encountered an I/O error when writing the body to the receiver
+    Write(std::io::Error),
+    /// The sender hung up before sending the whole body
+    Incomplete,
+    /// The sender stopped sending the body for longer than the body read timeout
+    TimedOut,
+    /// The body is bigger than the configured maximum body size
+    TooLarge,
+    /// A chunk size in a chunked body is not a valid hexadecimal number
+    MalformedChunkSize,
+    /// A chunk in a chunked body is not terminated properly, or the trailers are invalid
+    MalformedChunk,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read(error) => write!(f, "failed to read body: {}", error),
+            Error::Write(error) => write!(f, "failed to write body: {}", error),
+            Error::Incomplete => write!(f, "connection closed before the end of the body"),
+            Error::TimedOut => write!(f, "timed out waiting for more of the body"),
+            Error::TooLarge => write!(f, "body is larger than the maximum body size"),
+            Error::MalformedChunkSize => write!(f, "malformed chunk size"),
+            Error::MalformedChunk => write!(f, "malformed chunk"),
+        }
+    }
+}
+
+/// Reads from a stream through a small buffer, starting with bytes that were already read from it
+/// (i.e. the start of the body that was read along with the headers)
+pub struct BufferedReader<'a, R> {
+    stream: &'a mut R,
+    buffer: Vec<u8>,
+    pos: usize,
+    /// How long to wait for each read from the stream, if limited
+    read_timeout: Option<Duration>,
+}
+
+impl<'a, R: AsyncRead + Unpin> BufferedReader<'a, R> {
+    pub fn new(stream: &'a mut R, already_read: &[u8]) -> BufferedReader<'a, R> {
+        BufferedReader {
+            stream,
+            buffer: already_read.to_vec(),
+            pos: 0,
+            read_timeout: None,
+        }
+    }
+
+    /// Makes reads from the stream fail with Error::TimedOut if nothing arrives for `read_timeout`
+    pub fn with_read_timeout(mut self, read_timeout: Option<Duration>) -> BufferedReader<'a, R> {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Reads more bytes from the stream into the buffer. Returns false if the stream has been
+    /// closed.
+    async fn fill(&mut self) -> Result<bool, Error> {
+        if self.pos > 0 {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+        let mut chunk = [0_u8; BUFFER_SIZE];
+        let bytes_read = timeout::optional(self.read_timeout, self.stream.read(&mut chunk))
+            .await
+            .map_err(|_| Error::TimedOut)?
+            .map_err(Error::Read)?;
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read > 0)
+    }
+
+    /// Reads up to and including the next CRLF, returning the line without the CRLF. Lines longer
+    /// than `max_len` are rejected as malformed chunks. Returns Ok(None) if the stream is closed
+    /// first.
+    pub async fn read_line(&mut self, max_len: usize) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let unread = &self.buffer[self.pos..];
+            if let Some(idx) = unread.windows(2).position(|window| window == b"\r\n") {
+                let line = unread[..idx].to_vec();
+                self.pos += idx + 2;
+                return Ok(Some(line));
+            }
+            if unread.len() > max_len {
+                return Err(Error::MalformedChunk);
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Copies exactly `len` bytes to `to`. Returns false if the stream is closed first.
+    pub async fn copy_exact<W: AsyncWrite + Unpin>(
+        &mut self,
+        len: u64,
+        to: &mut W,
+    ) -> Result<bool, Error> {
+        let mut remaining = len;
+        loop {
+            let available =
+                std::cmp::min(remaining, (self.buffer.len() - self.pos) as u64) as usize;
+            to.write_all(&self.buffer[self.pos..self.pos + available])
+                .await
+                .map_err(Error::Write)?;
+            self.pos += available;
+            remaining -= available as u64;
+            if remaining == 0 {
+                return Ok(true);
+            }
+            if !self.fill().await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Copies everything up to the end of the stream to `to`, failing if that is more than
+    /// `max_size` bytes. Returns the number of bytes copied.
+    async fn copy_to_end<W: AsyncWrite + Unpin>(
+        &mut self,
+        to: &mut W,
+        max_size: Option<u64>,
+    ) -> Result<u64, Error> {
+        let mut copied = 0;
+        loop {
+            let unread = &self.buffer[self.pos..];
+            copied += unread.len() as u64;
+            if max_size.is_some_and(|max_size| copied > max_size) {
+                return Err(Error::TooLarge);
+            }
+            to.write_all(unread).await.map_err(Error::Write)?;
+            self.pos = self.buffer.len();
+            if !self.fill().await? {
+                return Ok(copied);
+            }
+        }
+    }
+
+    /// Returns the bytes that have been buffered but not consumed yet
+    fn into_unconsumed(mut self) -> Vec<u8> {
+        self.buffer.split_off(self.pos)
+    }
+}
+
+/// Removes and returns whatever follows the end of the body in `already_read`, the bytes that were
+/// read along with a message's headers. Those bytes are the start of the next message on the
+/// connection. Only bodies whose length is known up front can be split this way; the end of a
+/// chunked body is found while forwarding it.
+pub fn split_off_next(already_read: &mut Vec<u8>, framing: Framing) -> Vec<u8> {
+    let body_len = match framing {
+        Framing::Empty => 0,
+        Framing::Length(len) => len,
+        Framing::Chunked | Framing::UntilClose => return Vec::new(),
+    };
+    if (already_read.len() as u64) > body_len {
+        already_read.split_off(body_len as usize)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Copies a message body from `from` to `to` as it arrives, without holding more than a small
+/// buffer of it in memory. `already_read` holds the start of the body, which was read from `from`
+/// along with the message's headers. Fails with Error::TooLarge if the body (not counting chunk
+/// framing) turns out to be bigger than `max_size`, or with Error::TimedOut if `from` goes quiet for
+/// longer than `read_timeout`. Returns the size of the body. Bytes read from `from` past the end of
+/// the body belong to the next message on the connection, and are added to `next`.
+///
+/// Chunked bodies are forwarded chunked: each chunk is passed on as it arrives, followed by the
+/// trailers.
+pub async fn forward<R, W>(
+    from: &mut R,
+    already_read: &[u8],
+    framing: Framing,
+    to: &mut W,
+    max_size: Option<u64>,
+    read_timeout: Option<Duration>,
+    next: &mut Vec<u8>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(
+        from,
+        already_read,
+        framing,
+        to,
+        max_size,
+        read_timeout,
+        next,
+        true,
+    )
+    .await
+}
+
+/// Like forward, but takes chunked transfer coding off the body, so that only its data is written
+/// to `to` (and any trailers are dropped). This is for passing the body on in another form, such as
+/// compressed, that is framed afresh.
+pub async fn forward_decoded<R, W>(
+    from: &mut R,
+    already_read: &[u8],
+    framing: Framing,
+    to: &mut W,
+    max_size: Option<u64>,
+    read_timeout: Option<Duration>,
+    next: &mut Vec<u8>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(
+        from,
+        already_read,
+        framing,
+        to,
+        max_size,
+        read_timeout,
+        next,
+        false,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn copy<R, W>(
+    from: &mut R,
+    already_read: &[u8],
+    framing: Framing,
+    to: &mut W,
+    max_size: Option<u64>,
+    read_timeout: Option<Duration>,
+    next: &mut Vec<u8>,
+    keep_chunk_framing: bool,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufferedReader::new(from, already_read).with_read_timeout(read_timeout);
+    let size = match framing {
+        Framing::Empty => 0,
+        Framing::Length(len) => {
+            if max_size.is_some_and(|max_size| len > max_size) {
+                return Err(Error::TooLarge);
+            }
+            if !reader.copy_exact(len, to).await? {
+                return Err(Error::Incomplete);
+            }
+            len
+        }
+        Framing::Chunked if keep_chunk_framing => {
+            chunked::forward(&mut reader, to, max_size).await?
+        }
+        Framing::Chunked => chunked::decode(&mut reader, to, max_size).await?,
+        Framing::UntilClose => reader.copy_to_end(to, max_size).await?,
+    };
+    next.extend_from_slice(&reader.into_unconsumed());
+    to.flush().await.map_err(Error::Write)?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn forward_bytes(
+        already_read: &[u8],
+        rest: &[u8],
+        framing: Framing,
+        max_size: Option<u64>,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        let (forwarded, size, _) = forward_with_next(already_read, rest, framing, max_size).await?;
+        Ok((forwarded, size))
+    }
+
+    /// Like forward_bytes, but also returns the bytes read past the end of the body
+    async fn forward_with_next(
+        already_read: &[u8],
+        rest: &[u8],
+        framing: Framing,
+        max_size: Option<u64>,
+    ) -> Result<(Vec<u8>, u64, Vec<u8>), Error> {
+        let mut rest = rest;
+        let mut forwarded = Vec::new();
+        let mut next = Vec::new();
+        let size = forward(
+            &mut rest,
+            already_read,
+            framing,
+            &mut forwarded,
+            max_size,
+            None,
+            &mut next,
+        )
+        .await?;
+        Ok((forwarded, size, next))
+    }
+
+    #[tokio::test]
+    async fn forwards_fixed_length_bodies() {
+        let body = vec![7_u8; 3 * BUFFER_SIZE];
+        let (forwarded, size) = forward_bytes(
+            &body[..100],
+            &body[100..],
+            Framing::Length(body.len() as u64),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(forwarded, body);
+        assert_eq!(size, body.len() as u64);
+
+        // Bytes after the end of the body are not part of it, but are kept for the next message
+        let (forwarded, _, next) = forward_with_next(b"hel", b"loGET /", Framing::Length(5), None)
+            .await
+            .unwrap();
+        assert_eq!(forwarded, b"hello");
+        assert_eq!(next, b"GET /");
+
+        assert!(matches!(
+            forward_bytes(b"hel", b"", Framing::Length(5), None).await,
+            Err(Error::Incomplete)
+        ));
+        assert!(matches!(
+            forward_bytes(b"hello", b"", Framing::Length(5), Some(4)).await,
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn forwards_bodies_delimited_by_close() {
+        let (forwarded, size) = forward_bytes(b"hel", b"lo", Framing::UntilClose, Some(5))
+            .await
+            .unwrap();
+        assert_eq!(forwarded, b"hello");
+        assert_eq!(size, 5);
+        assert!(matches!(
+            forward_bytes(b"hel", b"lo!", Framing::UntilClose, Some(5)).await,
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn times_out_when_the_sender_goes_quiet() {
+        // The sender sends part of the body and then stalls with the connection still open
+        let (mut sender, mut receiver) = tokio::io::duplex(64);
+        sender.write_all(b"hel").await.unwrap();
+        let mut forwarded = Vec::new();
+        let result = forward(
+            &mut receiver,
+            b"",
+            Framing::Length(5),
+            &mut forwarded,
+            None,
+            Some(Duration::from_millis(50)),
+            &mut Vec::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::TimedOut)));
+        assert_eq!(forwarded, b"hel");
+    }
+
+    #[tokio::test]
+    async fn forwards_nothing_for_empty_bodies() {
+        let (forwarded, size) = forward_bytes(b"", b"GET / HTTP/1.1", Framing::Empty, None)
+            .await
+            .unwrap();
+        assert!(forwarded.is_empty());
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn splits_off_the_next_message() {
+        let mut already_read = b"helloGET /".to_vec();
+        assert_eq!(
+            split_off_next(&mut already_read, Framing::Length(5)),
+            b"GET /"
+        );
+        assert_eq!(already_read, b"hello");
+        let mut already_read = b"hel".to_vec();
+        assert!(split_off_next(&mut already_read, Framing::Length(5)).is_empty());
+        assert_eq!(already_read, b"hel");
+        let mut already_read = b"GET /".to_vec();
+        assert_eq!(split_off_next(&mut already_read, Framing::Empty), b"GET /");
+        assert!(already_read.is_empty());
+        let mut already_read = b"5\r\nhello".to_vec();
+        assert!(split_off_next(&mut already_read, Framing::Chunked).is_empty());
+    }
+}
@@ -0,0 +1,821 @@
+use crate::body;
+use crate::clock::{Clock, SystemClock};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+
+/// Statuses whose responses can be reused for later requests without the upstream saying so
+/// specially (RFC 7231 section 6.1), minus 206, since we don't cache range requests
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+/// Headers a 304 Not Modified response to a client carries over from the cached response (RFC 7232
+/// section 4.1)
+const NOT_MODIFIED_HEADERS: &[HeaderName] = &[
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::ETAG,
+    header::EXPIRES,
+    header::LAST_MODIFIED,
+    header::VARY,
+    header::VIA,
+];
+
+/// Request headers that make a request conditional on the state of the resource
+const CONDITIONAL_HEADERS: &[HeaderName] = &[
+    header::IF_MATCH,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_UNMODIFIED_SINCE,
+    header::IF_RANGE,
+];
+
+/// How the cache was involved in answering a request, for the access log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    /// Answered from the cache without asking an upstream
+    Hit,
+    /// The cached response had gone stale, and an upstream confirmed it was still current
+    Revalidated,
+    /// Could have been answered from the cache, but had to go to an upstream
+    Miss,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Hit => "hit",
+            Status::Revalidated => "revalidated",
+            Status::Miss => "miss",
+        }
+    }
+}
+
+/// The Cache-Control directives we act on
+#[derive(Debug, Default, PartialEq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &HeaderMap) -> CacheControl {
+        let mut cache_control = CacheControl::default();
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap().trim().to_ascii_lowercase();
+            let seconds = parts
+                .next()
+                .and_then(|value| value.trim().trim_matches('"').parse().ok());
+            match name.as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                // A malformed max-age means the response can't be reused without revalidating
+                "max-age" => cache_control.max_age = Some(seconds.unwrap_or(0)),
+                "s-maxage" => cache_control.s_maxage = Some(seconds.unwrap_or(0)),
+                _ => {}
+            }
+        }
+        // HTTP/1.0 caches only understood Pragma
+        if headers
+            .get_all(header::PRAGMA)
+            .iter()
+            .any(|value| value.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+        {
+            cache_control.no_cache = true;
+        }
+        cache_control
+    }
+}
+
+/// Parses an HTTP-date header
+fn date_header(headers: &HeaderMap, name: HeaderName) -> Option<chrono::DateTime<chrono::Utc>> {
+    let value = headers.get(name)?.to_str().ok()?;
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&chrono::Utc))
+}
+
+/// Works out how long a response can be reused for after it was generated (RFC 7234 section 4.2.1)
+fn freshness_lifetime(headers: &HeaderMap, cache_control: &CacheControl) -> Duration {
+    if cache_control.no_cache {
+        return Duration::from_secs(0);
+    }
+    if let Some(seconds) = cache_control.s_maxage.or(cache_control.max_age) {
+        return Duration::from_secs(seconds);
+    }
+    if headers.contains_key(header::EXPIRES) {
+        // An Expires header that can't be parsed means the response has already expired
+        let expires = match date_header(headers, header::EXPIRES) {
+            Some(expires) => expires,
+            None => return Duration::from_secs(0),
+        };
+        let date = date_header(headers, header::DATE).unwrap_or_else(chrono::Utc::now);
+        return (expires - date).to_std().unwrap_or_default();
+    }
+    Duration::from_secs(0)
+}
+
+fn has_validator(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED)
+}
+
+/// Returns true if one of the entity tags in an If-None-Match header matches `etag`. The comparison
+/// is weak (RFC 7232 section 2.3.2), as If-None-Match calls for.
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = match etag.to_str() {
+        Ok(etag) => strip_weak(etag),
+        Err(_) => return false,
+    };
+    if_none_match.to_str().is_ok_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+    })
+}
+
+/// Identifies what a request asks for, regardless of content negotiation
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Resource {
+    method: http::Method,
+    host: String,
+    uri: String,
+}
+
+impl Resource {
+    pub fn new(request: &http::Request<Vec<u8>>) -> Resource {
+        Resource {
+            method: request.method().clone(),
+            host: request
+                .headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("")
+                .to_ascii_lowercase(),
+            uri: request.uri().to_string(),
+        }
+    }
+}
+
+/// Returns true if a request could be answered from the cache. Only GET requests are cached.
+/// Requests for part of a resource, requests that ask us not to store anything, and requests with
+/// credentials (whose responses may be meant for that user alone) are left alone.
+pub fn is_cacheable(request: &http::Request<Vec<u8>>) -> bool {
+    let headers = request.headers();
+    request.method() == http::Method::GET
+        && !headers.contains_key(header::RANGE)
+        && !headers.contains_key(header::AUTHORIZATION)
+        && !CacheControl::from_headers(headers).no_store
+}
+
+/// The values of the request headers that a response's Vary header names, which must match for the
+/// response to be reused
+type VaryValues = Vec<Option<Vec<u8>>>;
+
+fn vary_values(vary: &[HeaderName], request: &http::Request<Vec<u8>>) -> VaryValues {
+    vary.iter()
+        .map(|name| {
+            let values: Vec<&[u8]> = request
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.as_bytes())
+                .collect();
+            if values.is_empty() {
+                None
+            } else {
+                Some(values.join(&b", "[..]))
+            }
+        })
+        .collect()
+}
+
+/// Returns the header names in a response's Vary header, or None if the response varies on
+/// something other than request headers (`Vary: *`)
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for value in headers.get_all(header::VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if !name.is_empty() {
+                names.push(HeaderName::from_bytes(name.as_bytes()).ok()?);
+            }
+        }
+    }
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+struct Entry {
+    status: http::StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    stored_at: Instant,
+    /// How old the response already was when we stored it, according to its Age header
+    initial_age: Duration,
+    freshness_lifetime: Duration,
+    /// When the entry was last used, in `Inner::next_tick` ticks
+    last_used: u64,
+    /// Roughly how much memory the entry takes up
+    size: u64,
+}
+
+impl Entry {
+    fn age(&self, now: Instant) -> Duration {
+        self.initial_age + now.duration_since(self.stored_at)
+    }
+
+    fn to_response(&self, now: Instant) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder()
+            .status(self.status)
+            .version(http::Version::HTTP_11)
+            .body(self.body.clone())
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(self.age(now).as_secs()));
+        response
+    }
+}
+
+/// The cached variants of a resource
+struct Variants {
+    /// The request headers the resource's responses vary on
+    vary: Vec<HeaderName>,
+    entries: HashMap<VaryValues, Entry>,
+}
+
+struct Inner {
+    resources: HashMap<Resource, Variants>,
+    /// Every entry by when it was last used, least recently used first
+    recency: BTreeMap<u64, (Resource, VaryValues)>,
+    next_tick: u64,
+    /// Total size of the entries
+    size: u64,
+}
+
+impl Inner {
+    fn tick(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+
+    fn remove(&mut self, resource: &Resource, values: &[Option<Vec<u8>>]) {
+        let variants = match self.resources.get_mut(resource) {
+            Some(variants) => variants,
+            None => return,
+        };
+        if let Some(entry) = variants.entries.remove(values) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+        if variants.entries.is_empty() {
+            self.resources.remove(resource);
+        }
+    }
+
+    fn remove_resource(&mut self, resource: &Resource) {
+        if let Some(variants) = self.resources.remove(resource) {
+            for entry in variants.entries.values() {
+                self.recency.remove(&entry.last_used);
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+/// The outcome of looking a request up in the cache
+pub enum Lookup {
+    /// Nothing usable is cached
+    Miss,
+    /// This cached response can be sent as it is
+    Fresh(http::Response<Vec<u8>>),
+    /// This cached response has gone stale, but can be sent if the upstream confirms it is still
+    /// current. These headers should be added to the request to ask it.
+    Stale(http::Response<Vec<u8>>, HeaderMap),
+}
+
+/// Keeps the responses to GET requests in memory so that they can be reused for later requests,
+/// following the rules for shared caches in RFC 7234. When the responses add up to more than
+/// `max_size` bytes, the least recently used ones are thrown away.
+pub struct Cache {
+    max_size: u64,
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+impl Cache {
+    pub fn new(max_size: u64) -> Cache {
+        Cache::with_clock(max_size, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_size: u64, clock: Arc<dyn Clock>) -> Cache {
+        Cache {
+            max_size,
+            clock,
+            inner: Mutex::new(Inner {
+                resources: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_tick: 0,
+                size: 0,
+            }),
+        }
+    }
+
+    /// Looks for a cached response to a request for `resource`
+    pub fn lookup(&self, resource: &Resource, request: &http::Request<Vec<u8>>) -> Lookup {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock();
+        let tick = inner.tick();
+        let Inner {
+            resources, recency, ..
+        } = &mut *inner;
+        let variants = match resources.get_mut(resource) {
+            Some(variants) => variants,
+            None => return Lookup::Miss,
+        };
+        let values = vary_values(&variants.vary, request);
+        let entry = match variants.entries.get_mut(&values) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        recency.remove(&entry.last_used);
+        recency.insert(tick, (resource.clone(), values));
+        entry.last_used = tick;
+
+        let request_cache_control = CacheControl::from_headers(request.headers());
+        let max_age = request_cache_control
+            .max_age
+            .map_or(entry.freshness_lifetime, |max_age| {
+                entry.freshness_lifetime.min(Duration::from_secs(max_age))
+            });
+        if !request_cache_control.no_cache && entry.age(now) < max_age {
+            return Lookup::Fresh(entry.to_response(now));
+        }
+        // If the client is revalidating a copy of its own, leave it to the upstream to answer
+        let client_conditional = CONDITIONAL_HEADERS
+            .iter()
+            .any(|name| request.headers().contains_key(name));
+        if client_conditional || !has_validator(&entry.headers) {
+            return Lookup::Miss;
+        }
+        let mut validators = HeaderMap::new();
+        if let Some(etag) = entry.headers.get(header::ETAG) {
+            validators.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = entry.headers.get(header::LAST_MODIFIED) {
+            validators.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        Lookup::Stale(entry.to_response(now), validators)
+    }
+
+    /// Returns true if a response to a cacheable request may be stored. `framing` must give the
+    /// body's length up front, so we know it fits before starting to forward it.
+    pub fn is_storable(&self, response: &http::Response<Vec<u8>>, framing: body::Framing) -> bool {
+        let headers = response.headers();
+        let cache_control = CacheControl::from_headers(headers);
+        let body_size = match framing {
+            body::Framing::Empty => 0,
+            body::Framing::Length(len) => len,
+            body::Framing::Chunked | body::Framing::UntilClose => return false,
+        };
+        CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            && !cache_control.no_store
+            && !cache_control.private
+            && !headers.contains_key(header::SET_COOKIE)
+            && vary_names(headers).is_some()
+            && (freshness_lifetime(headers, &cache_control) > Duration::from_secs(0)
+                || has_validator(headers))
+            && body_size <= self.max_size
+    }
+
+    /// Stores a response to a request for `resource`, replacing any response cached for the same
+    /// request, and evicts the least recently used entries if the cache has grown too big.
+    pub fn store(
+        &self,
+        resource: &Resource,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        let now = self.clock.now();
+        let headers = response.headers().clone();
+        let body = response.body().clone();
+        let vary = match vary_names(&headers) {
+            Some(vary) => vary,
+            None => return,
+        };
+        let values = vary_values(&vary, request);
+        let cache_control = CacheControl::from_headers(&headers);
+        let initial_age = headers
+            .get(header::AGE)
+            .and_then(|age| age.to_str().ok()?.parse().ok())
+            .map_or(Duration::from_secs(0), Duration::from_secs);
+        let size = body.len() as u64
+            + headers
+                .iter()
+                .map(|(name, value)| (name.as_str().len() + value.len()) as u64)
+                .sum::<u64>()
+            + (resource.host.len() + resource.uri.len()) as u64;
+        if size > self.max_size {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        let tick = inner.tick();
+        // A different Vary header means the old variants were cached under different keys, so
+        // they can't be found any more
+        if inner
+            .resources
+            .get(resource)
+            .is_some_and(|variants| variants.vary != vary)
+        {
+            inner.remove_resource(resource);
+        }
+        inner.remove(resource, &values);
+        while inner.size + size > self.max_size {
+            let (resource, values) = match inner.recency.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            inner.remove(&resource, &values);
+        }
+        inner
+            .recency
+            .insert(tick, (resource.clone(), values.clone()));
+        inner.size += size;
+        inner
+            .resources
+            .entry(resource.clone())
+            .or_insert_with(|| Variants {
+                vary,
+                entries: HashMap::new(),
+            })
+            .entries
+            .insert(
+                values,
+                Entry {
+                    status: response.status(),
+                    freshness_lifetime: freshness_lifetime(&headers, &cache_control),
+                    headers,
+                    body,
+                    stored_at: now,
+                    initial_age,
+                    last_used: tick,
+                    size,
+                },
+            );
+    }
+
+    /// Throws away everything cached for a resource that a successful unsafe request (such as a
+    /// POST or DELETE) may have changed (RFC 7234 section 4.4)
+    pub fn invalidate(&self, resource: &Resource) {
+        let resource = Resource {
+            method: http::Method::GET,
+            ..resource.clone()
+        };
+        self.inner.lock().remove_resource(&resource);
+    }
+}
+
+/// Updates a stale cached response with the headers from the 304 Not Modified response that
+/// confirmed it is still current (RFC 7234 section 4.3.4)
+pub fn freshen(
+    mut cached: http::Response<Vec<u8>>,
+    not_modified: &http::Response<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    for name in not_modified.headers().keys() {
+        // The 304 describes the current representation, except for its length
+        if name == header::CONTENT_LENGTH {
+            continue;
+        }
+        cached.headers_mut().remove(name);
+        for value in not_modified.headers().get_all(name) {
+            cached.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    cached.headers_mut().remove(header::AGE);
+    cached
+}
+
+/// Turns a cached response into 304 Not Modified if the request says the client already has it
+pub fn answer_conditional(
+    request: &http::Request<Vec<u8>>,
+    response: http::Response<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let matches = match (
+        request.headers().get(header::IF_NONE_MATCH),
+        response.headers().get(header::ETAG),
+    ) {
+        (Some(if_none_match), Some(etag)) => etag_matches(if_none_match, etag),
+        _ => false,
+    };
+    if !matches || response.status() != http::StatusCode::OK {
+        return response;
+    }
+    let mut not_modified = http::Response::builder()
+        .status(http::StatusCode::NOT_MODIFIED)
+        .version(response.version())
+        .body(Vec::new())
+        .unwrap();
+    for name in NOT_MODIFIED_HEADERS.iter().chain(&[header::AGE]) {
+        for value in response.headers().get_all(name) {
+            not_modified
+                .headers_mut()
+                .append(name.clone(), value.clone());
+        }
+    }
+    not_modified
+}
+
+/// Passes writes through to a stream, optionally keeping a copy of everything written so that a
+/// response body can be cached as it is forwarded
+pub struct Recorder<'a, W> {
+    inner: &'a mut W,
+    recorded: Option<Vec<u8>>,
+}
+
+impl<'a, W> Recorder<'a, W> {
+    pub fn new(inner: &'a mut W, record: bool) -> Recorder<'a, W> {
+        Recorder {
+            inner,
+            recorded: if record { Some(Vec::new()) } else { None },
+        }
+    }
+
+    /// Returns everything written, if recording
+    pub fn into_recorded(self) -> Option<Vec<u8>> {
+        self.recorded
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Recorder<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(recorded)) = (&poll, &mut this.recorded) {
+            recorded.extend_from_slice(&buf[..*written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    fn setup(max_size: u64) -> (Cache, Arc<TestClock>) {
+        let clock = TestClock::new();
+        (Cache::with_clock(max_size, clock.clone()), clock)
+    }
+
+    fn get(uri: &str, headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut builder = http::Request::builder()
+            .uri(uri)
+            .header("Host", "example.com");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    fn response(headers: &[(&str, &str)], body: &str) -> http::Response<Vec<u8>> {
+        let mut builder = http::Response::builder().status(200);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    /// Stores a response for `request` if it is storable, returning whether it was
+    fn store(
+        cache: &Cache,
+        request: &http::Request<Vec<u8>>,
+        response: http::Response<Vec<u8>>,
+    ) -> bool {
+        let framing = body::Framing::Length(response.body().len() as u64);
+        if !cache.is_storable(&response, framing) {
+            return false;
+        }
+        cache.store(&Resource::new(request), request, &response);
+        true
+    }
+
+    fn lookup(cache: &Cache, request: &http::Request<Vec<u8>>) -> Lookup {
+        assert!(is_cacheable(request));
+        cache.lookup(&Resource::new(request), request)
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let headers: HeaderMap = [
+            (header::CACHE_CONTROL, "private, max-age=\"60\""),
+            (header::CACHE_CONTROL, "S-MaxAge=30, no-cache"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+        .collect();
+        let cache_control = CacheControl::from_headers(&headers);
+        assert_eq!(
+            cache_control,
+            CacheControl {
+                no_store: false,
+                no_cache: true,
+                private: true,
+                max_age: Some(60),
+                s_maxage: Some(30),
+            }
+        );
+    }
+
+    #[test]
+    fn serves_fresh_responses_until_they_expire() {
+        let (cache, clock) = setup(1000);
+        let request = get("/a", &[]);
+        assert!(store(
+            &cache,
+            &request,
+            response(&[("Cache-Control", "max-age=10"), ("Age", "2")], "hello")
+        ));
+        clock.advance(Duration::from_secs(7));
+        match lookup(&cache, &request) {
+            Lookup::Fresh(response) => {
+                assert_eq!(response.body(), b"hello");
+                assert_eq!(response.headers()["age"], "9");
+            }
+            _ => panic!("expected a fresh response"),
+        }
+        clock.advance(Duration::from_secs(1));
+        // Without a validator, a stale response is no use
+        assert!(matches!(lookup(&cache, &request), Lookup::Miss));
+        // Other URIs are cached separately
+        assert!(matches!(lookup(&cache, &get("/b", &[])), Lookup::Miss));
+    }
+
+    #[test]
+    fn uses_expires() {
+        let (cache, _) = setup(1000);
+        let request = get("/a", &[]);
+        assert!(store(
+            &cache,
+            &request,
+            response(
+                &[
+                    ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                    ("Expires", "Sun, 06 Nov 1994 08:50:37 GMT")
+                ],
+                "hello"
+            )
+        ));
+        assert!(matches!(lookup(&cache, &request), Lookup::Fresh(_)));
+        // Without freshness information or a validator, there's no point storing the response
+        assert!(!store(
+            &cache,
+            &request,
+            response(&[("Expires", "0")], "hello")
+        ));
+    }
+
+    #[test]
+    fn refuses_uncacheable_responses() {
+        let (cache, _) = setup(1000);
+        let request = get("/a", &[]);
+        for headers in &[
+            &[("Cache-Control", "max-age=60, no-store")][..],
+            &[("Cache-Control", "private, max-age=60")],
+            &[("Cache-Control", "max-age=60"), ("Set-Cookie", "a=b")],
+            &[("Cache-Control", "max-age=60"), ("Vary", "*")],
+        ] {
+            assert!(
+                !store(&cache, &request, response(headers, "hello")),
+                "{:?}",
+                headers
+            );
+        }
+        assert!(!is_cacheable(&get("/a", &[("Range", "bytes=0-1")])));
+        assert!(!is_cacheable(&get(
+            "/a",
+            &[("Authorization", "Basic eA==")]
+        )));
+    }
+
+    #[test]
+    fn revalidates_stale_responses() {
+        let (cache, _) = setup(1000);
+        let request = get("/a", &[]);
+        assert!(store(
+            &cache,
+            &request,
+            response(
+                &[("Cache-Control", "no-cache"), ("ETag", "\"v1\"")],
+                "hello"
+            )
+        ));
+        let (stale, validators) = match lookup(&cache, &request) {
+            Lookup::Stale(stale, validators) => (stale, validators),
+            _ => panic!("expected a stale response"),
+        };
+        assert_eq!(validators["if-none-match"], "\"v1\"");
+        let not_modified = http::Response::builder()
+            .status(304)
+            .header("Cache-Control", "max-age=60")
+            .header("ETag", "\"v1\"")
+            .body(Vec::new())
+            .unwrap();
+        let freshened = freshen(stale, &not_modified);
+        assert_eq!(freshened.status(), 200);
+        assert_eq!(freshened.headers()["cache-control"], "max-age=60");
+        assert_eq!(freshened.body(), b"hello");
+
+        // A client that already has the response gets 304 Not Modified
+        let conditional = get("/a", &[("If-None-Match", "W/\"v0\", \"v1\"")]);
+        let answer = answer_conditional(&conditional, freshened);
+        assert_eq!(answer.status(), 304);
+        assert_eq!(answer.headers()["etag"], "\"v1\"");
+        assert!(answer.body().is_empty());
+    }
+
+    #[test]
+    fn keeps_variants_apart() {
+        let (cache, _) = setup(1000);
+        let gzip = get("/a", &[("Accept-Encoding", "gzip")]);
+        let plain = get("/a", &[]);
+        let headers = [("Cache-Control", "max-age=60"), ("Vary", "Accept-Encoding")];
+        assert!(store(&cache, &gzip, response(&headers, "zipped")));
+        assert!(matches!(lookup(&cache, &plain), Lookup::Miss));
+        assert!(store(&cache, &plain, response(&headers, "plain")));
+        match (lookup(&cache, &gzip), lookup(&cache, &plain)) {
+            (Lookup::Fresh(gzip), Lookup::Fresh(plain)) => {
+                assert_eq!(gzip.body(), b"zipped");
+                assert_eq!(plain.body(), b"plain");
+            }
+            _ => panic!("expected both variants to be cached"),
+        }
+        // An unsafe request to the resource throws all of its variants away
+        let mut delete = get("/a", &[]);
+        *delete.method_mut() = http::Method::DELETE;
+        cache.invalidate(&Resource::new(&delete));
+        assert!(matches!(lookup(&cache, &gzip), Lookup::Miss));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let headers = [("Cache-Control", "max-age=60")];
+        let entry_size = {
+            let (cache, _) = setup(1000);
+            store(&cache, &get("/a", &[]), response(&headers, "0123456789"));
+            let size = cache.inner.lock().size;
+            size
+        };
+        let (cache, _) = setup(entry_size * 2);
+        for uri in &["/a", "/b"] {
+            assert!(store(
+                &cache,
+                &get(uri, &[]),
+                response(&headers, "0123456789")
+            ));
+        }
+        // Using /a makes /b the least recently used
+        assert!(matches!(lookup(&cache, &get("/a", &[])), Lookup::Fresh(_)));
+        assert!(store(
+            &cache,
+            &get("/c", &[]),
+            response(&headers, "0123456789")
+        ));
+        assert!(matches!(lookup(&cache, &get("/a", &[])), Lookup::Fresh(_)));
+        assert!(matches!(lookup(&cache, &get("/b", &[])), Lookup::Miss));
+        assert!(matches!(lookup(&cache, &get("/c", &[])), Lookup::Fresh(_)));
+        assert!(cache.inner.lock().size <= entry_size * 2);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::body::{BufferedReader, Error};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Longest chunk size line (size plus any chunk extensions) or trailer line we are willing to read
+const MAX_LINE_SIZE: usize = 4096;
+/// Largest trailer section we are willing to read
+const MAX_TRAILERS_SIZE: usize = 8000;
+const MAX_NUM_TRAILERS: usize = 32;
+
+/// Returns true if the message body is sent with chunked transfer coding, i.e. if chunked is the
+/// last coding listed in Transfer-Encoding
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .rfind(|coding| !coding.is_empty())
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Parses a chunk size line, ignoring any chunk extensions after the size
+fn parse_chunk_size(line: &[u8]) -> Result<u64, Error> {
+    let size = match line.iter().position(|byte| *byte == b';') {
+        Some(idx) => &line[..idx],
+        None => line,
+    };
+    let size = std::str::from_utf8(size)
+        .map_err(|_| Error::MalformedChunkSize)?
+        .trim();
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::MalformedChunkSize);
+    }
+    u64::from_str_radix(size, 16).map_err(|_| Error::MalformedChunkSize)
+}
+
+/// Parses the trailer section (without the blank line that ends it)
+fn parse_trailers(section: &[u8]) -> Result<http::HeaderMap, Error> {
+    let mut trailers = http::HeaderMap::new();
+    if section.is_empty() {
+        return Ok(trailers);
+    }
+    let mut buffer = section.to_vec();
+    buffer.extend_from_slice(b"\r\n");
+    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_TRAILERS];
+    match httparse::parse_headers(&buffer, &mut headers) {
+        Ok(httparse::Status::Complete((_, headers))) => {
+            for header in headers {
+                let name = http::HeaderName::from_bytes(header.name.as_bytes())
+                    .map_err(|_| Error::MalformedChunk)?;
+                let value = http::HeaderValue::from_bytes(header.value)
+                    .map_err(|_| Error::MalformedChunk)?;
+                trailers.append(name, value);
+            }
+            Ok(trailers)
+        }
+        _ => Err(Error::MalformedChunk),
+    }
+}
+
+/// Forwards a chunked body from `reader` to `to`, one chunk at a time. Chunk extensions are
+/// dropped; the chunks themselves and the trailers are passed on unchanged. Returns the size of the
+/// body, not counting the chunk framing.
+pub async fn forward<R, W>(
+    reader: &mut BufferedReader<'_, R>,
+    to: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(reader, to, max_size, true).await
+}
+
+/// Like forward, but takes the chunked coding off: only the data in the chunks is written to `to`,
+/// and the trailers are dropped
+pub async fn decode<R, W>(
+    reader: &mut BufferedReader<'_, R>,
+    to: &mut W,
+    max_size: Option<u64>,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy(reader, to, max_size, false).await
+}
+
+/// Reads a chunked body from `reader`, writing it to `to` with its chunk framing and trailers if
+/// `keep_framing` is set, or just the data otherwise
+async fn copy<R, W>(
+    reader: &mut BufferedReader<'_, R>,
+    to: &mut W,
+    max_size: Option<u64>,
+    keep_framing: bool,
+) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut size = 0;
+    loop {
+        let line = reader
+            .read_line(MAX_LINE_SIZE)
+            .await?
+            .ok_or(Error::Incomplete)?;
+        let chunk_size = parse_chunk_size(&line)?;
+        if chunk_size == 0 {
+            break;
+        }
+        size += chunk_size;
+        if max_size.is_some_and(|max_size| size > max_size) {
+            return Err(Error::TooLarge);
+        }
+        if keep_framing {
+            to.write_all(format!("{:x}\r\n", chunk_size).as_bytes())
+                .await
+                .map_err(Error::Write)?;
+        }
+        if !reader.copy_exact(chunk_size, to).await? {
+            return Err(Error::Incomplete);
+        }
+        // Every chunk's data is followed by CRLF
+        match reader.read_line(MAX_LINE_SIZE).await? {
+            Some(line) if line.is_empty() => {}
+            Some(_) => return Err(Error::MalformedChunk),
+            None => return Err(Error::Incomplete),
+        }
+        if keep_framing {
+            to.write_all(b"\r\n").await.map_err(Error::Write)?;
+        }
+    }
+
+    // The last chunk is followed by zero or more trailer fields and a blank line
+    let mut trailer_section = Vec::new();
+    loop {
+        let line = reader
+            .read_line(MAX_LINE_SIZE)
+            .await?
+            .ok_or(Error::Incomplete)?;
+        if line.is_empty() {
+            break;
+        }
+        trailer_section.extend_from_slice(&line);
+        trailer_section.extend_from_slice(b"\r\n");
+        if trailer_section.len() > MAX_TRAILERS_SIZE {
+            return Err(Error::MalformedChunk);
+        }
+    }
+    let trailers = parse_trailers(&trailer_section)?;
+    if !keep_framing {
+        return Ok(size);
+    }
+
+    to.write_all(b"0\r\n").await.map_err(Error::Write)?;
+    for (name, value) in &trailers {
+        to.write_all(format!("{}: ", name).as_bytes())
+            .await
+            .map_err(Error::Write)?;
+        to.write_all(value.as_bytes()).await.map_err(Error::Write)?;
+        to.write_all(b"\r\n").await.map_err(Error::Write)?;
+    }
+    to.write_all(b"\r\n").await.map_err(Error::Write)?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn reencode(encoded: &[u8], max_size: Option<u64>) -> Result<(Vec<u8>, u64), Error> {
+        // Hand over the first few bytes as if they had been read along with the headers
+        let split = std::cmp::min(3, encoded.len());
+        let mut rest = &encoded[split..];
+        let mut reader = BufferedReader::new(&mut rest, &encoded[..split]);
+        let mut forwarded = Vec::new();
+        let size = forward(&mut reader, &mut forwarded, max_size).await?;
+        Ok((forwarded, size))
+    }
+
+    #[tokio::test]
+    async fn forwards_chunks_and_trailers() {
+        let (forwarded, size) = reencode(
+            b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nx-checksum: abc\r\nx-other: 1\r\n\r\n",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(size, 12);
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "5\r\nhello\r\n7\r\n, world\r\n0\r\nx-checksum: abc\r\nx-other: 1\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_chunks() {
+        let encoded = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nx-checksum: abc\r\n\r\n";
+        let mut rest = &encoded[..];
+        let mut reader = BufferedReader::new(&mut rest, b"");
+        let mut decoded = Vec::new();
+        let size = decode(&mut reader, &mut decoded, None).await.unwrap();
+        assert_eq!(size, 12);
+        assert_eq!(decoded, b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn forwards_empty_body() {
+        let (forwarded, size) = reencode(b"0\r\n\r\n", None).await.unwrap();
+        assert_eq!(forwarded, b"0\r\n\r\n");
+        assert_eq!(size, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_bodies() {
+        assert!(matches!(
+            reencode(b"zz\r\nhello\r\n0\r\n\r\n", None).await,
+            Err(Error::MalformedChunkSize)
+        ));
+        assert!(matches!(
+            reencode(b"\r\n0\r\n\r\n", None).await,
+            Err(Error::MalformedChunkSize)
+        ));
+        assert!(matches!(
+            reencode(b"3\r\nhello\r\n0\r\n\r\n", None).await,
+            Err(Error::MalformedChunk)
+        ));
+        assert!(matches!(
+            reencode(b"5\r\nhel", None).await,
+            Err(Error::Incomplete)
+        ));
+        assert!(matches!(
+            reencode(b"5\r\nhello\r\n5\r\nhello\r\n0\r\n\r\n", Some(8)).await,
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn detects_chunked_transfer_encoding() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!is_chunked(&headers));
+        headers.insert("transfer-encoding", "gzip, Chunked".parse().unwrap());
+        assert!(is_chunked(&headers));
+        headers.insert("transfer-encoding", "chunked, gzip".parse().unwrap());
+        assert!(!is_chunked(&headers));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::CircuitBreakerConfig;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Where a circuit breaker is in its cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Requests flow normally, and their outcomes are watched
+    Closed,
+    /// Too many requests failed recently, so requests are turned away without trying the upstream
+    Open,
+    /// The breaker has been open for a while, and a few trial requests are being let through to see
+    /// whether the upstream has recovered
+    HalfOpen,
+}
+
+impl State {
+    pub const ALL: [State; 3] = [State::Closed, State::Open, State::HalfOpen];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    /// Outcomes (true for success) of the requests that finished recently while closed, oldest
+    /// first
+    outcomes: VecDeque<(Instant, bool)>,
+    /// When the breaker last opened
+    opened_at: Instant,
+    /// Trial requests let through since going half-open that haven't finished yet
+    trials_in_flight: usize,
+    /// Trial requests that have succeeded since going half-open
+    trial_successes: usize,
+    /// Number of times the breaker has opened
+    times_opened: u64,
+    /// Bumped every time the breaker changes state, so that permits handed out before the change
+    /// can be told apart from the ones handed out since
+    generation: u64,
+}
+
+/// Stops sending requests to an upstream that keeps failing, so that it gets a chance to recover
+/// (and clients get a quick error instead of waiting on it). Trips when the share of failed
+/// requests in the recent window gets too high, then after a while lets a few trial requests
+/// through and closes again if they all succeed.
+pub struct CircuitBreaker {
+    /// The upstream this breaker is for, for logging
+    address: String,
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(address: &str) -> CircuitBreaker {
+        CircuitBreaker::with_clock(address, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(address: &str, clock: Arc<dyn Clock>) -> CircuitBreaker {
+        let now = clock.now();
+        CircuitBreaker {
+            address: address.to_string(),
+            clock,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: now,
+                trials_in_flight: 0,
+                trial_successes: 0,
+                times_opened: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().state
+    }
+
+    pub fn times_opened(&self) -> u64 {
+        self.inner.lock().times_opened
+    }
+
+    fn transition(&self, inner: &mut Inner, state: State) {
+        match state {
+            State::Open => {
+                log::warn!("Circuit breaker for {} opened", self.address);
+                inner.opened_at = self.clock.now();
+                inner.times_opened += 1;
+            }
+            State::HalfOpen => {
+                log::info!(
+                    "Circuit breaker for {} is half-open, sending trial requests",
+                    self.address
+                );
+                inner.trials_in_flight = 0;
+                inner.trial_successes = 0;
+            }
+            State::Closed => log::info!("Circuit breaker for {} closed", self.address),
+        }
+        inner.state = state;
+        inner.outcomes.clear();
+        inner.generation += 1;
+    }
+
+    /// Returns true if a request sent to the upstream now would be let through. This doesn't
+    /// reserve anything; use `acquire` when actually sending a request.
+    pub fn is_available(&self, config: &CircuitBreakerConfig) -> bool {
+        if !config.is_enabled() {
+            return true;
+        }
+        let inner = self.inner.lock();
+        match inner.state {
+            State::Closed => true,
+            State::Open => self.clock.now() >= inner.opened_at + config.open_duration,
+            State::HalfOpen => {
+                inner.trials_in_flight + inner.trial_successes < config.half_open_requests
+            }
+        }
+    }
+
+    /// Asks to send a request to the upstream. Returns a permit to record the request's outcome
+    /// with, or None if the breaker is turning requests away.
+    pub fn acquire(&self, config: &CircuitBreakerConfig) -> Option<Permit<'_>> {
+        let mut inner = self.inner.lock();
+        if !config.is_enabled() {
+            if inner.state != State::Closed {
+                self.transition(&mut inner, State::Closed);
+            }
+            return Some(Permit::new(self, *config, inner.generation, false));
+        }
+        if inner.state == State::Open && self.clock.now() >= inner.opened_at + config.open_duration
+        {
+            self.transition(&mut inner, State::HalfOpen);
+        }
+        match inner.state {
+            State::Closed => Some(Permit::new(self, *config, inner.generation, false)),
+            State::Open => None,
+            State::HalfOpen => {
+                if inner.trials_in_flight + inner.trial_successes < config.half_open_requests {
+                    inner.trials_in_flight += 1;
+                    Some(Permit::new(self, *config, inner.generation, true))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn record(&self, config: &CircuitBreakerConfig, generation: u64, trial: bool, success: bool) {
+        if !config.is_enabled() {
+            return;
+        }
+        let now = self.clock.now();
+        let mut inner = self.inner.lock();
+        // Requests that were sent before the breaker last changed state don't say anything about
+        // the upstream's current health, and trials from an earlier half-open spell aren't counted
+        // in this one's trials_in_flight
+        if generation != inner.generation {
+            return;
+        }
+        match inner.state {
+            State::Closed => {
+                inner.outcomes.push_back((now, success));
+                while let Some((time, _)) = inner.outcomes.front() {
+                    if now.duration_since(*time) <= config.window {
+                        break;
+                    }
+                    inner.outcomes.pop_front();
+                }
+                let requests = inner.outcomes.len();
+                let failures = inner.outcomes.iter().filter(|(_, ok)| !ok).count();
+                if requests >= config.min_requests
+                    && failures * 100 >= requests * config.failure_rate_percent as usize
+                {
+                    self.transition(&mut inner, State::Open);
+                }
+            }
+            State::HalfOpen => {
+                debug_assert!(trial);
+                inner.trials_in_flight -= 1;
+                if !success {
+                    self.transition(&mut inner, State::Open);
+                } else {
+                    inner.trial_successes += 1;
+                    if inner.trial_successes >= config.half_open_requests {
+                        self.transition(&mut inner, State::Closed);
+                    }
+                }
+            }
+            // No permits are handed out while open
+            State::Open => {}
+        }
+    }
+
+    fn release_trial(&self, generation: u64) {
+        let mut inner = self.inner.lock();
+        if generation == inner.generation && inner.state == State::HalfOpen {
+            inner.trials_in_flight -= 1;
+        }
+    }
+}
+
+/// Permission to send one request to an upstream. The request's outcome should be recorded with
+/// `record`. If the permit is dropped without recording anything (because the request failed for
+/// reasons that say nothing about the upstream), the request doesn't count either way.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    config: CircuitBreakerConfig,
+    /// The breaker's generation when the permit was handed out
+    generation: u64,
+    /// Whether this is one of the trial requests let through while half-open
+    trial: bool,
+    recorded: bool,
+}
+
+impl<'a> Permit<'a> {
+    fn new(
+        breaker: &'a CircuitBreaker,
+        config: CircuitBreakerConfig,
+        generation: u64,
+        trial: bool,
+    ) -> Permit<'a> {
+        Permit {
+            breaker,
+            config,
+            generation,
+            trial,
+            recorded: false,
+        }
+    }
+
+    pub fn record(mut self, success: bool) {
+        self.breaker
+            .record(&self.config, self.generation, self.trial, success);
+        self.recorded = true;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            self.breaker.release_trial(self.generation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use std::time::Duration;
+
+    fn setup() -> (CircuitBreaker, Arc<TestClock>, CircuitBreakerConfig) {
+        let clock = TestClock::new();
+        let config = CircuitBreakerConfig {
+            failure_rate_percent: 50,
+            min_requests: 4,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 2,
+        };
+        (
+            CircuitBreaker::with_clock("upstream", clock.clone()),
+            clock,
+            config,
+        )
+    }
+
+    /// Sends requests with the given outcomes through the breaker, as long as it lets them through
+    fn send(breaker: &CircuitBreaker, config: &CircuitBreakerConfig, outcomes: &[bool]) {
+        for success in outcomes {
+            breaker.acquire(config).unwrap().record(*success);
+        }
+    }
+
+    #[test]
+    fn opens_when_failure_rate_is_too_high() {
+        let (breaker, clock, config) = setup();
+        // Too few requests to judge by
+        send(&breaker, &config, &[false, false, false]);
+        assert_eq!(breaker.state(), State::Closed);
+        // Failures that have left the window don't count
+        clock.advance(Duration::from_secs(11));
+        send(&breaker, &config, &[true, true, false]);
+        assert_eq!(breaker.state(), State::Closed);
+        send(&breaker, &config, &[false]);
+        assert_eq!(breaker.state(), State::Open);
+        assert_eq!(breaker.times_opened(), 1);
+        assert!(!breaker.is_available(&config));
+        assert!(breaker.acquire(&config).is_none());
+    }
+
+    #[test]
+    fn closes_after_successful_trials() {
+        let (breaker, clock, config) = setup();
+        send(&breaker, &config, &[false; 4]);
+        clock.advance(Duration::from_secs(30));
+        assert!(breaker.is_available(&config));
+
+        // Only half_open_requests trial requests are let through at once
+        let first = breaker.acquire(&config).unwrap();
+        assert_eq!(breaker.state(), State::HalfOpen);
+        let second = breaker.acquire(&config).unwrap();
+        assert!(breaker.acquire(&config).is_none());
+        // A trial that ends without an outcome frees up its place
+        drop(second);
+        let second = breaker.acquire(&config).unwrap();
+        first.record(true);
+        assert_eq!(breaker.state(), State::HalfOpen);
+        second.record(true);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn reopens_when_a_trial_fails() {
+        let (breaker, clock, config) = setup();
+        send(&breaker, &config, &[false; 4]);
+        clock.advance(Duration::from_secs(30));
+        let trial = breaker.acquire(&config).unwrap();
+        trial.record(false);
+        assert_eq!(breaker.state(), State::Open);
+        assert_eq!(breaker.times_opened(), 2);
+        clock.advance(Duration::from_secs(29));
+        assert!(breaker.acquire(&config).is_none());
+    }
+
+    #[test]
+    fn ignores_trials_from_an_earlier_half_open_spell() {
+        let (breaker, clock, config) = setup();
+        send(&breaker, &config, &[false; 4]);
+        clock.advance(Duration::from_secs(30));
+        let first = breaker.acquire(&config).unwrap();
+        let late = breaker.acquire(&config).unwrap();
+        first.record(false);
+        assert_eq!(breaker.state(), State::Open);
+
+        clock.advance(Duration::from_secs(30));
+        let trial = breaker.acquire(&config).unwrap();
+        assert_eq!(breaker.state(), State::HalfOpen);
+        // The trial from the last spell finishing doesn't free up or fill a place in this one
+        late.record(true);
+        let other = breaker.acquire(&config).unwrap();
+        assert!(breaker.acquire(&config).is_none());
+        drop(other);
+        trial.record(true);
+        assert_eq!(breaker.state(), State::HalfOpen);
+        breaker.acquire(&config).unwrap().record(true);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn does_nothing_when_disabled() {
+        let (breaker, _, mut config) = setup();
+        config.failure_rate_percent = 0;
+        send(&breaker, &config, &[false; 10]);
+        assert_eq!(breaker.state(), State::Closed);
+    }
+}
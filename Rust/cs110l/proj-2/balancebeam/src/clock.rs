@@ -0,0 +1,42 @@
+use std::time::Instant;
+
+/// A source of the current time. Anything that keeps track of time asks a Clock for it instead of
+/// calling Instant::now() directly, so that tests can control the passage of time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Clock that reports the real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when the test tells it to
+#[cfg(test)]
+pub struct TestClock {
+    now: parking_lot::Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new() -> std::sync::Arc<TestClock> {
+        std::sync::Arc::new(TestClock {
+            now: parking_lot::Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
@@ -0,0 +1,577 @@
+use crate::body;
+use crate::config::CompressionConfig;
+use http::header::{self, HeaderMap, HeaderValue};
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Brotli quality (0-11). Higher levels compress better but are too slow to do on the fly.
+const BROTLI_QUALITY: u32 = 5;
+/// Base 2 logarithm of the brotli window size
+const BROTLI_WINDOW_BITS: u32 = 22;
+/// Size of the buffer the brotli compressor works through
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Content codings we can compress response bodies with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// Returns the name of the coding as it appears in Accept-Encoding and Content-Encoding
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn matches(self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+/// Picks the coding the client likes best out of the ones we support, going by its Accept-Encoding
+/// header. Brotli wins ties, since it usually makes for smaller bodies.
+fn negotiate(request: &http::Request<Vec<u8>>) -> Option<Encoding> {
+    let accepted: Vec<(&str, f32)> = request
+        .headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        value.trim().parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((coding, quality))
+        })
+        .collect();
+    let quality = |encoding: Encoding| {
+        accepted
+            .iter()
+            .find(|(coding, _)| encoding.matches(coding))
+            .or_else(|| accepted.iter().find(|(coding, _)| *coding == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+        let quality = quality(encoding);
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Returns true if an allow-list entry covers a (lowercased) media type. Entries ending in /* cover
+/// every subtype.
+fn type_allowed(allowed: &str, media_type: &str) -> bool {
+    match allowed.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with('/') => {
+            media_type.starts_with(&prefix.to_ascii_lowercase())
+        }
+        _ => allowed.eq_ignore_ascii_case(media_type),
+    }
+}
+
+/// Returns true if we would compress the response for a client that accepts it: its media type is
+/// on the allow-list, the upstream hasn't already encoded it, and it doesn't ask proxies to leave it
+/// alone with Cache-Control: no-transform
+pub fn is_compressible(config: &CompressionConfig, response: &http::Response<Vec<u8>>) -> bool {
+    let status = response.status();
+    if !config.enabled
+        || status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    let headers = response.headers();
+    let encoded = headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .any(|value| !value.as_bytes().eq_ignore_ascii_case(b"identity"));
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if encoded || no_transform || headers.contains_key(header::CONTENT_RANGE) {
+        return false;
+    }
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap().trim().to_ascii_lowercase())
+        .is_some_and(|media_type| {
+            config
+                .types
+                .iter()
+                .any(|allowed| type_allowed(allowed, &media_type))
+        })
+}
+
+/// Adds Accept-Encoding to a response's Vary header, since whether we compress the response depends
+/// on it
+pub fn add_vary(headers: &mut HeaderMap) {
+    let already_varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim())
+        .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"));
+    if !already_varies {
+        headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// Updates the headers of a response whose body is being compressed with `encoding`
+fn set_encoding(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.remove(header::CONTENT_LENGTH);
+    // The compressed body isn't byte-for-byte the same as the upstream's, so it can only share a
+    // weak ETag with it
+    if let Some(etag) = headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+}
+
+/// Decides whether to compress a response as it is forwarded from the upstream, and if so, updates
+/// its headers to match: the compressed body is sent chunked, since we don't know how long it will
+/// be until it has all gone. That needs an HTTP/1.1 client. A chunked body from the upstream has to
+/// be decoded (with body::forward_decoded) before it goes through the Encoder, which chunks the
+/// compressed body afresh.
+pub fn start(
+    config: &CompressionConfig,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+    framing: body::Framing,
+) -> Option<Encoding> {
+    if !is_compressible(config, response) {
+        return None;
+    }
+    add_vary(response.headers_mut());
+    let worth_compressing = match framing {
+        body::Framing::Length(len) => len >= config.min_size,
+        // We can't tell how big these are until they have all arrived
+        body::Framing::Chunked | body::Framing::UntilClose => true,
+        body::Framing::Empty => false,
+    };
+    if !worth_compressing || request.version() < http::Version::HTTP_11 {
+        return None;
+    }
+    let encoding = negotiate(request)?;
+    let headers = response.headers_mut();
+    set_encoding(headers, encoding);
+    headers.insert(
+        header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+    Some(encoding)
+}
+
+/// Compresses a response whose whole body is in memory (such as one from the cache) if the client
+/// accepts it, setting Content-Length to the size of the compressed body
+pub fn compress_response(
+    config: &CompressionConfig,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    if !is_compressible(config, response) {
+        return;
+    }
+    add_vary(response.headers_mut());
+    if request.method() == http::Method::HEAD || (response.body().len() as u64) < config.min_size {
+        return;
+    }
+    let encoding = match negotiate(request) {
+        Some(encoding) => encoding,
+        None => return,
+    };
+    let mut compressor = Compressor::new(encoding);
+    let compressed = compressor
+        .write(response.body())
+        .and_then(|()| compressor.finish());
+    // Compressing into memory can't really fail, but if it does, the uncompressed body will do
+    if let Ok(compressed) = compressed {
+        let headers = response.headers_mut();
+        set_encoding(headers, encoding);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+        *response.body_mut() = compressed;
+    }
+}
+
+/// Compresses into memory, handing over its output as it goes
+enum Compressor {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Compressor {
+    fn new(encoding: Encoding) -> Compressor {
+        match encoding {
+            Encoding::Gzip => Compressor::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Brotli => Compressor::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.write_all(data),
+            Compressor::Brotli(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Takes the compressed output produced so far
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(match self {
+            Compressor::Gzip(encoder) => encoder.get_mut(),
+            Compressor::Brotli(encoder) => encoder.get_mut(),
+        })
+    }
+
+    /// Ends the compressed stream, returning the output that hasn't been taken yet
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.finish(),
+            Compressor::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+/// Appends `data` to `buffer` as a chunk in chunked transfer coding. Nothing is added for empty
+/// data, since an empty chunk would end the body.
+fn frame_chunk(buffer: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    buffer.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    buffer.extend_from_slice(data);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+/// Compresses a body on its way to `inner`, sending the compressed bytes with chunked transfer
+/// coding as the compressor produces them. `finish` must be called once the whole body has been
+/// written, to send the end of it. Without an encoding, the body is passed through untouched.
+pub struct Encoder<'a, W> {
+    inner: &'a mut W,
+    compressor: Option<Compressor>,
+    /// Chunks that haven't been written to `inner` yet
+    pending: Vec<u8>,
+    /// How much of `pending` has been written
+    written: usize,
+    /// Size of the compressed body so far, not counting chunk framing
+    size: u64,
+}
+
+impl<'a, W: AsyncWrite + Unpin> Encoder<'a, W> {
+    pub fn new(inner: &'a mut W, encoding: Option<Encoding>) -> Encoder<'a, W> {
+        Encoder {
+            inner,
+            compressor: encoding.map(Compressor::new),
+            pending: Vec::new(),
+            written: 0,
+            size: 0,
+        }
+    }
+
+    /// Writes out the pending chunks
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.pending.len() {
+            match Pin::new(&mut *self.inner).poll_write(cx, &self.pending[self.written..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(written)) => self.written += written,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends the rest of the compressed body and the last chunk. Returns the size of the compressed
+    /// body, or None if it wasn't compressed.
+    pub async fn finish(self) -> std::io::Result<Option<u64>> {
+        let Encoder {
+            inner,
+            compressor,
+            mut pending,
+            written,
+            size,
+        } = self;
+        let compressor = match compressor {
+            Some(compressor) => compressor,
+            None => return Ok(None),
+        };
+        let rest = compressor.finish()?;
+        frame_chunk(&mut pending, &rest);
+        pending.extend_from_slice(b"0\r\n\r\n");
+        inner.write_all(&pending[written..]).await?;
+        inner.flush().await?;
+        Ok(Some(size + rest.len() as u64))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Encoder<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let this = &mut *self;
+        if this.compressor.is_none() {
+            return Pin::new(&mut *this.inner).poll_write(cx, buf);
+        }
+        // Don't take on more until the last of the output has gone, so that a slow client can't
+        // make us buffer the whole body
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let compressor = this.compressor.as_mut().unwrap();
+        compressor.write(buf)?;
+        let compressed = compressor.take_output();
+        this.size += compressed.len() as u64;
+        frame_chunk(&mut this.pending, &compressed);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut *this.inner).poll_flush(cx),
+            poll => poll,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut *this.inner).poll_shutdown(cx),
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn config() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            types: vec!["text/*".to_string(), "application/json".to_string()],
+            min_size: 10,
+        }
+    }
+
+    fn request(accept_encoding: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .uri("/")
+            .header("Accept-Encoding", accept_encoding)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    fn response(content_type: &str, body: &[u8]) -> http::Response<Vec<u8>> {
+        http::Response::builder()
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len().to_string())
+            .header("ETag", "\"v1\"")
+            .body(body.to_vec())
+            .unwrap()
+    }
+
+    fn decompress(encoding: Encoding, compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        match encoding {
+            Encoding::Gzip => flate2::read::GzDecoder::new(compressed)
+                .read_to_end(&mut decompressed)
+                .unwrap(),
+            Encoding::Brotli => brotli::Decompressor::new(compressed, BROTLI_BUFFER_SIZE)
+                .read_to_end(&mut decompressed)
+                .unwrap(),
+        };
+        decompressed
+    }
+
+    /// Removes chunked transfer coding from a body
+    fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let line_end = chunked
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .unwrap();
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&chunked[..line_end]).unwrap(), 16)
+                    .unwrap();
+            if size == 0 {
+                assert_eq!(&chunked[line_end..], b"\r\n\r\n");
+                return body;
+            }
+            chunked = &chunked[line_end + 2..];
+            body.extend_from_slice(&chunked[..size]);
+            assert_eq!(&chunked[size..size + 2], b"\r\n");
+            chunked = &chunked[size + 2..];
+        }
+    }
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(negotiate(&request("gzip")), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(&request("gzip, deflate, br")),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(&request("br;q=0.5, gzip;q=0.8")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(&request("*")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&request("br;q=0, *")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&request("deflate")), None);
+        assert_eq!(negotiate(&request("identity")), None);
+    }
+
+    #[test]
+    fn compresses_allowed_types_only() {
+        let config = config();
+        assert!(is_compressible(
+            &config,
+            &response("text/html; charset=utf-8", b"")
+        ));
+        assert!(is_compressible(&config, &response("Application/JSON", b"")));
+        assert!(!is_compressible(&config, &response("image/png", b"")));
+
+        let mut encoded = response("text/plain", b"");
+        encoded
+            .headers_mut()
+            .insert("Content-Encoding", "gzip".parse().unwrap());
+        assert!(!is_compressible(&config, &encoded));
+        let mut no_transform = response("text/plain", b"");
+        no_transform
+            .headers_mut()
+            .insert("Cache-Control", "public, no-transform".parse().unwrap());
+        assert!(!is_compressible(&config, &no_transform));
+
+        let disabled = CompressionConfig {
+            enabled: false,
+            ..config
+        };
+        assert!(!is_compressible(&disabled, &response("text/plain", b"")));
+    }
+
+    #[test]
+    fn compresses_whole_responses() {
+        let config = config();
+        let body = b"hello hello hello hello hello hello".to_vec();
+        let mut response = response("text/plain", &body);
+        compress_response(&config, &request("gzip"), &mut response);
+        let headers = response.headers();
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(headers["vary"], "Accept-Encoding");
+        assert_eq!(headers["etag"], "W/\"v1\"");
+        assert_eq!(
+            headers["content-length"],
+            response.body().len().to_string().as_str()
+        );
+        assert_eq!(decompress(Encoding::Gzip, response.body()), body);
+
+        // Small bodies aren't worth it, but still vary
+        let mut small = self::response("text/plain", b"hi");
+        compress_response(&config, &request("gzip"), &mut small);
+        assert!(!small.headers().contains_key("content-encoding"));
+        assert_eq!(small.headers()["vary"], "Accept-Encoding");
+    }
+
+    #[test]
+    fn updates_headers_for_streamed_bodies() {
+        let config = config();
+        let mut response = response("text/plain", &[b'a'; 100]);
+        response
+            .headers_mut()
+            .insert("Vary", "Accept-Language".parse().unwrap());
+        let encoding = start(
+            &config,
+            &request("br"),
+            &mut response,
+            body::Framing::Length(100),
+        );
+        assert_eq!(encoding, Some(Encoding::Brotli));
+        let headers = response.headers();
+        assert_eq!(headers["content-encoding"], "br");
+        assert_eq!(headers["transfer-encoding"], "chunked");
+        assert!(!headers.contains_key("content-length"));
+        let vary: Vec<_> = headers.get_all("vary").iter().collect();
+        assert_eq!(vary, vec!["Accept-Language", "Accept-Encoding"]);
+
+        // HTTP/1.0 clients can't be sent chunked bodies
+        let mut request = request("br");
+        *request.version_mut() = http::Version::HTTP_10;
+        let mut response = self::response("text/plain", &[b'a'; 100]);
+        assert_eq!(
+            start(&config, &request, &mut response, body::Framing::Length(100)),
+            None
+        );
+        assert!(response.headers().contains_key("content-length"));
+    }
+
+    #[tokio::test]
+    async fn streams_compressed_chunks() {
+        let body: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        for encoding in [Encoding::Gzip, Encoding::Brotli] {
+            let mut output = Vec::new();
+            let mut encoder = Encoder::new(&mut output, Some(encoding));
+            for piece in body.chunks(8192) {
+                encoder.write_all(piece).await.unwrap();
+            }
+            let size = encoder.finish().await.unwrap().unwrap();
+            let compressed = dechunk(&output);
+            assert_eq!(size, compressed.len() as u64);
+            assert_eq!(decompress(encoding, &compressed), body);
+        }
+    }
+}
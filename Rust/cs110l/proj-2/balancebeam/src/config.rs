@@ -0,0 +1,925 @@
+use crate::{rate_limit, retry};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+/// An upstream server and how much traffic it should get
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamConfig {
+    /// Address (host:port) of the server
+    pub address: String,
+    /// How many requests this upstream should get relative to the others when using weighted
+    /// balancing
+    pub weight: u32,
+}
+
+impl std::str::FromStr for UpstreamConfig {
+    type Err = String;
+
+    /// Parses an upstream given on the command line, either as `host:port` or as
+    /// `host:port=weight`. Upstreams without an explicit weight get a weight of 1.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (address, weight) = match spec.rfind('=') {
+            Some(idx) => {
+                let weight = spec[idx + 1..]
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|weight| *weight > 0)
+                    .ok_or_else(|| format!("invalid weight in upstream {:?}", spec))?;
+                (&spec[..idx], weight)
+            }
+            None => (spec, 1),
+        };
+        if address.is_empty() {
+            return Err(format!("missing address in upstream {:?}", spec));
+        }
+        Ok(UpstreamConfig {
+            address: address.to_string(),
+            weight,
+        })
+    }
+}
+
+/// The name of the pool made up of the upstreams given with --upstream or [[upstream]]
+pub const DEFAULT_POOL: &str = "default";
+
+/// How a route changes the path of the requests it matches before they are forwarded
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathRewrite {
+    /// Remove the route's path prefix
+    StripPrefix,
+    /// Replace the route's path prefix with this one
+    ReplacePrefix(String),
+}
+
+/// Sends requests matching all of the given conditions to a pool of upstreams
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteConfig {
+    /// Host the request is for, ignoring any port. `*.example.com` matches any subdomain of
+    /// example.com.
+    pub host: Option<String>,
+    /// Path prefix, matched on whole path segments (so `/api` matches `/api/users` but not
+    /// `/apiary`)
+    pub path_prefix: Option<String>,
+    /// Regular expression the path must match
+    pub path_regex: Option<String>,
+    /// Name of the pool to send matching requests to
+    pub pool: String,
+    pub rewrite: Option<PathRewrite>,
+}
+
+/// How long to wait for the various stages of handling a request. None means wait forever.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// Opening a connection to an upstream
+    pub connect: Option<Duration>,
+    /// Receiving the rest of a request's headers from a client, once the request has started
+    pub header_read: Option<Duration>,
+    /// Waiting for more of a request or response body to arrive
+    pub body_read: Option<Duration>,
+    /// Waiting for an upstream to send the head of its response, once the request has been sent
+    pub upstream_response: Option<Duration>,
+    /// Waiting for a client to start its next request on a keep-alive connection
+    pub keep_alive_idle: Option<Duration>,
+    /// Waiting for in-flight requests to finish when shutting down
+    pub drain: Option<Duration>,
+}
+
+/// When to stop sending requests to an upstream that keeps failing, and how to find out when it has
+/// recovered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// Percentage of requests in the window that must fail (with a 5xx response, a timeout or a
+    /// broken connection) to trip the breaker. 0 disables circuit breaking.
+    pub failure_rate_percent: u32,
+    /// Fewest requests in the window for the failure rate to count
+    pub min_requests: usize,
+    /// How far back to look when working out the failure rate
+    pub window: Duration,
+    /// How long a tripped breaker turns requests away before trying the upstream again
+    pub open_duration: Duration,
+    /// Number of trial requests let through once the breaker stops turning requests away. The
+    /// breaker closes if they all succeed.
+    pub half_open_requests: usize,
+}
+
+impl CircuitBreakerConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.failure_rate_percent > 0
+    }
+}
+
+/// When to send a failed request again, and how much retrying to allow overall. Only idempotent
+/// requests whose body has been read in full are ever retried.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryConfig {
+    /// Most times to send a request, including the first. 1 disables retries.
+    pub max_attempts: usize,
+    /// Failures that are worth retrying
+    pub retry_on: Vec<retry::Condition>,
+    /// Longest wait before the first retry. The limit doubles for each retry after that.
+    pub backoff: Duration,
+    /// Longest wait before any retry
+    pub max_backoff: Duration,
+    /// Retries allowed across all requests, as a percentage of recent requests
+    pub budget_percent: u32,
+    /// Retries allowed each second on top of `budget_percent`
+    pub budget_min_per_second: u32,
+}
+
+/// Which responses to compress on their way to clients that accept gzip or brotli
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Media types to compress, such as text/html. An entry ending in /* covers every subtype.
+    pub types: Vec<String>,
+    /// Smallest body (in bytes) worth compressing. Bodies whose size isn't known up front are always
+    /// compressed.
+    pub min_size: u64,
+}
+
+/// Converts a timeout given in seconds into a Duration, with 0 meaning no timeout
+pub fn timeout_from_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// The settings that can be changed while balancebeam is running, by editing the config file and
+/// sending balancebeam SIGHUP
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Servers that we are proxying to, unless a route sends requests to another pool
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Other named groups of servers that routes can send requests to
+    pub pools: BTreeMap<String, Vec<UpstreamConfig>>,
+    /// Rules for picking the pool each request goes to, tried in order. If there are none, every
+    /// request goes to the default pool.
+    pub routes: Vec<RouteConfig>,
+    /// How frequently we check whether upstream servers are alive
+    pub active_health_check_interval: Duration,
+    /// Where we should send requests when doing active health checks
+    pub active_health_check_path: String,
+    /// How frequently we look up upstream hostnames again to find backends that have been added or
+    /// removed
+    pub dns_refresh_interval: Duration,
+    /// Maximum number of requests to accept per IP per minute (0 = unlimited)
+    pub max_requests_per_minute: usize,
+    /// How to count requests for rate limiting
+    pub rate_limit_algorithm: rate_limit::Algorithm,
+    /// How long pooled upstream connections may sit idle before they are closed
+    pub upstream_pool_idle_timeout: Duration,
+    pub timeouts: Timeouts,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub retry: RetryConfig,
+    /// Largest total size (in bytes) of the responses kept in the response cache (0 = no caching)
+    pub cache_size: u64,
+    pub compression: CompressionConfig,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file could not be read
+    Read(std::io::Error),
+    /// The config file is not valid TOML, or has settings we don't know about
+    Parse(toml::de::Error),
+    /// The settings are well-formed but don't make sense
+    Invalid(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read(error) => write!(f, "failed to read config file: {}", error),
+            Error::Parse(error) => write!(f, "failed to parse config file: {}", error),
+            Error::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+/// The contents of a config file. Every setting is optional; settings that the file leaves out
+/// keep the value given on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    upstream: Option<Vec<UpstreamSection>>,
+    pools: Option<BTreeMap<String, Vec<UpstreamSection>>>,
+    route: Option<Vec<RouteSection>>,
+    #[serde(default)]
+    health_check: HealthCheckSection,
+    #[serde(default)]
+    dns: DnsSection,
+    #[serde(default)]
+    rate_limit: RateLimitSection,
+    #[serde(default)]
+    timeouts: TimeoutsSection,
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerSection,
+    #[serde(default)]
+    retry: RetrySection,
+    #[serde(default)]
+    cache: CacheSection,
+    #[serde(default)]
+    compression: CompressionSection,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamSection {
+    address: String,
+    weight: Option<u32>,
+}
+
+impl From<UpstreamSection> for UpstreamConfig {
+    fn from(section: UpstreamSection) -> UpstreamConfig {
+        UpstreamConfig {
+            address: section.address,
+            weight: section.weight.unwrap_or(1),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteSection {
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<String>,
+    pool: Option<String>,
+    #[serde(default)]
+    strip_prefix: bool,
+    rewrite_prefix: Option<String>,
+}
+
+impl RouteSection {
+    fn into_config(self) -> Result<RouteConfig, Error> {
+        let rewrite = match (self.strip_prefix, self.rewrite_prefix) {
+            (false, None) => None,
+            (true, None) => Some(PathRewrite::StripPrefix),
+            (false, Some(prefix)) => Some(PathRewrite::ReplacePrefix(prefix)),
+            (true, Some(_)) => {
+                return Err(Error::Invalid(
+                    "a route can't have both strip_prefix and rewrite_prefix".to_string(),
+                ))
+            }
+        };
+        Ok(RouteConfig {
+            host: self.host,
+            path_prefix: self.path_prefix,
+            path_regex: self.path_regex,
+            pool: self.pool.unwrap_or_else(|| DEFAULT_POOL.to_string()),
+            rewrite,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckSection {
+    /// In seconds
+    interval: Option<u64>,
+    path: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DnsSection {
+    /// In seconds
+    refresh_interval: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitSection {
+    max_requests_per_minute: Option<usize>,
+    algorithm: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSection {
+    // All in seconds. 0 means no timeout, except for upstream_pool_idle.
+    upstream_pool_idle: Option<u64>,
+    connect: Option<u64>,
+    header_read: Option<u64>,
+    body_read: Option<u64>,
+    upstream_response: Option<u64>,
+    keep_alive_idle: Option<u64>,
+    drain: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerSection {
+    failure_rate_percent: Option<u32>,
+    min_requests: Option<usize>,
+    /// In seconds
+    window: Option<u64>,
+    /// In seconds
+    open_duration: Option<u64>,
+    half_open_requests: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetrySection {
+    max_attempts: Option<usize>,
+    /// Conditions as accepted by retry::Condition::from_str
+    retry_on: Option<Vec<String>>,
+    backoff_ms: Option<u64>,
+    max_backoff_ms: Option<u64>,
+    budget_percent: Option<u32>,
+    budget_min_per_second: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheSection {
+    /// In bytes
+    max_size: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSection {
+    enabled: Option<bool>,
+    types: Option<Vec<String>>,
+    /// In bytes
+    min_size: Option<u64>,
+}
+
+impl RouteConfig {
+    fn validate(&self, config: &Config) -> Result<(), Error> {
+        match config.pool(&self.pool) {
+            None => {
+                return Err(Error::Invalid(format!(
+                    "route refers to unknown pool {}",
+                    self.pool
+                )))
+            }
+            Some([]) => {
+                return Err(Error::Invalid(format!(
+                    "route sends requests to the {} pool, which has no upstreams",
+                    self.pool
+                )))
+            }
+            Some(_) => {}
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !prefix.starts_with('/') {
+                return Err(Error::Invalid(format!(
+                    "route path prefix {:?} does not start with /",
+                    prefix
+                )));
+            }
+        }
+        if let Some(regex) = &self.path_regex {
+            if let Err(error) = regex::Regex::new(regex) {
+                return Err(Error::Invalid(format!(
+                    "bad route path regex {:?}: {}",
+                    regex, error
+                )));
+            }
+        }
+        match &self.rewrite {
+            Some(_) if self.path_prefix.is_none() => Err(Error::Invalid(
+                "a route can only rewrite paths if it has a path_prefix".to_string(),
+            )),
+            Some(PathRewrite::ReplacePrefix(prefix)) if !prefix.starts_with('/') => {
+                Err(Error::Invalid(format!(
+                    "route rewrite prefix {:?} does not start with /",
+                    prefix
+                )))
+            }
+            // The rest of the request's path is added to the end of the prefix, so it has to be a
+            // valid path on its own, without a query
+            Some(PathRewrite::ReplacePrefix(prefix)) => {
+                match prefix.parse::<http::uri::PathAndQuery>() {
+                    Ok(path) if path.query().is_none() => Ok(()),
+                    _ => Err(Error::Invalid(format!(
+                        "route rewrite prefix {:?} is not a valid URI path",
+                        prefix
+                    ))),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Config {
+    /// Checks that the settings make sense together
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.routes.is_empty() && self.upstreams.is_empty() {
+            return Err(Error::Invalid(
+                "at least one upstream server must be specified".to_string(),
+            ));
+        }
+        if self.pools.contains_key(DEFAULT_POOL) {
+            return Err(Error::Invalid(format!(
+                "the {} pool is made up of the [[upstream]] servers and can't be redefined",
+                DEFAULT_POOL
+            )));
+        }
+        if let Some((name, _)) = self
+            .pools
+            .iter()
+            .find(|(_, upstreams)| upstreams.is_empty())
+        {
+            return Err(Error::Invalid(format!("pool {} has no upstreams", name)));
+        }
+        // Each upstream can only be in one pool, so that its health and connection counts are
+        // tracked in one place
+        let mut addresses = HashSet::new();
+        for upstream in self.all_upstreams() {
+            if upstream.address.is_empty() {
+                return Err(Error::Invalid("upstream address is empty".to_string()));
+            }
+            if upstream.weight == 0 {
+                return Err(Error::Invalid(format!(
+                    "upstream {} has a weight of 0",
+                    upstream.address
+                )));
+            }
+            if !addresses.insert(&upstream.address) {
+                return Err(Error::Invalid(format!(
+                    "upstream {} is listed more than once",
+                    upstream.address
+                )));
+            }
+        }
+        if self.active_health_check_interval == Duration::from_secs(0) {
+            return Err(Error::Invalid(
+                "health check interval must be at least 1 second".to_string(),
+            ));
+        }
+        if !self.active_health_check_path.starts_with('/') {
+            return Err(Error::Invalid(format!(
+                "health check path {:?} does not start with /",
+                self.active_health_check_path
+            )));
+        }
+        if self.dns_refresh_interval == Duration::from_secs(0) {
+            return Err(Error::Invalid(
+                "DNS refresh interval must be at least 1 second".to_string(),
+            ));
+        }
+        for route in &self.routes {
+            route.validate(self)?;
+        }
+        let circuit_breaker = &self.circuit_breaker;
+        if circuit_breaker.failure_rate_percent > 100 {
+            return Err(Error::Invalid(format!(
+                "circuit breaker failure rate {}% is over 100%",
+                circuit_breaker.failure_rate_percent
+            )));
+        }
+        if circuit_breaker.is_enabled()
+            && (circuit_breaker.min_requests == 0
+                || circuit_breaker.half_open_requests == 0
+                || circuit_breaker.window == Duration::from_secs(0))
+        {
+            return Err(Error::Invalid(
+                "circuit breaker min requests, half-open requests and window must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(Error::Invalid(
+                "retry max attempts must be at least 1".to_string(),
+            ));
+        }
+        if self.retry.backoff > self.retry.max_backoff {
+            return Err(Error::Invalid(
+                "retry backoff is longer than the maximum backoff".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the upstreams in every pool, starting with the default pool
+    pub fn all_upstreams(&self) -> impl Iterator<Item = &UpstreamConfig> {
+        self.upstreams.iter().chain(self.pools.values().flatten())
+    }
+
+    /// Returns the upstreams in the named pool, or None if there is no such pool
+    pub fn pool(&self, name: &str) -> Option<&[UpstreamConfig]> {
+        if name == DEFAULT_POOL {
+            Some(&self.upstreams)
+        } else {
+            self.pools.get(name).map(Vec::as_slice)
+        }
+    }
+
+    /// Applies the settings in a config file on top of these settings (which normally come from
+    /// the command line) and returns the result. The result is only returned if it is valid.
+    pub fn with_file_contents(&self, contents: &str) -> Result<Config, Error> {
+        let file: ConfigFile = toml::from_str(contents).map_err(Error::Parse)?;
+        let mut config = self.clone();
+        if let Some(upstreams) = file.upstream {
+            config.upstreams = upstreams.into_iter().map(UpstreamConfig::from).collect();
+        }
+        if let Some(pools) = file.pools {
+            config.pools = pools
+                .into_iter()
+                .map(|(name, upstreams)| {
+                    (
+                        name,
+                        upstreams.into_iter().map(UpstreamConfig::from).collect(),
+                    )
+                })
+                .collect();
+        }
+        if let Some(routes) = file.route {
+            config.routes = routes
+                .into_iter()
+                .map(RouteSection::into_config)
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(interval) = file.health_check.interval {
+            config.active_health_check_interval = Duration::from_secs(interval);
+        }
+        if let Some(path) = file.health_check.path {
+            config.active_health_check_path = path;
+        }
+        if let Some(interval) = file.dns.refresh_interval {
+            config.dns_refresh_interval = Duration::from_secs(interval);
+        }
+        if let Some(max_requests_per_minute) = file.rate_limit.max_requests_per_minute {
+            config.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(algorithm) = file.rate_limit.algorithm {
+            config.rate_limit_algorithm = algorithm.parse().map_err(Error::Invalid)?;
+        }
+        if let Some(idle_timeout) = file.timeouts.upstream_pool_idle {
+            config.upstream_pool_idle_timeout = Duration::from_secs(idle_timeout);
+        }
+        let timeouts = &mut config.timeouts;
+        for (secs, timeout) in [
+            (file.timeouts.connect, &mut timeouts.connect),
+            (file.timeouts.header_read, &mut timeouts.header_read),
+            (file.timeouts.body_read, &mut timeouts.body_read),
+            (
+                file.timeouts.upstream_response,
+                &mut timeouts.upstream_response,
+            ),
+            (file.timeouts.keep_alive_idle, &mut timeouts.keep_alive_idle),
+            (file.timeouts.drain, &mut timeouts.drain),
+        ] {
+            if let Some(secs) = secs {
+                *timeout = timeout_from_secs(secs);
+            }
+        }
+        let circuit_breaker = &mut config.circuit_breaker;
+        if let Some(failure_rate_percent) = file.circuit_breaker.failure_rate_percent {
+            circuit_breaker.failure_rate_percent = failure_rate_percent;
+        }
+        if let Some(min_requests) = file.circuit_breaker.min_requests {
+            circuit_breaker.min_requests = min_requests;
+        }
+        if let Some(window) = file.circuit_breaker.window {
+            circuit_breaker.window = Duration::from_secs(window);
+        }
+        if let Some(open_duration) = file.circuit_breaker.open_duration {
+            circuit_breaker.open_duration = Duration::from_secs(open_duration);
+        }
+        if let Some(half_open_requests) = file.circuit_breaker.half_open_requests {
+            circuit_breaker.half_open_requests = half_open_requests;
+        }
+        let retry = &mut config.retry;
+        if let Some(max_attempts) = file.retry.max_attempts {
+            retry.max_attempts = max_attempts;
+        }
+        if let Some(retry_on) = file.retry.retry_on {
+            retry.retry_on = retry_on
+                .iter()
+                .map(|condition| condition.parse())
+                .collect::<Result<_, _>>()
+                .map_err(Error::Invalid)?;
+        }
+        if let Some(backoff) = file.retry.backoff_ms {
+            retry.backoff = Duration::from_millis(backoff);
+        }
+        if let Some(max_backoff) = file.retry.max_backoff_ms {
+            retry.max_backoff = Duration::from_millis(max_backoff);
+        }
+        if let Some(budget_percent) = file.retry.budget_percent {
+            retry.budget_percent = budget_percent;
+        }
+        if let Some(budget_min_per_second) = file.retry.budget_min_per_second {
+            retry.budget_min_per_second = budget_min_per_second;
+        }
+        if let Some(max_size) = file.cache.max_size {
+            config.cache_size = max_size;
+        }
+        let compression = &mut config.compression;
+        if let Some(enabled) = file.compression.enabled {
+            compression.enabled = enabled;
+        }
+        if let Some(types) = file.compression.types {
+            compression.types = types;
+        }
+        if let Some(min_size) = file.compression.min_size {
+            compression.min_size = min_size;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the config file at `path` and applies it on top of these settings
+    pub fn with_file(&self, path: &str) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path).map_err(Error::Read)?;
+        self.with_file_contents(&contents)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn base_config() -> Config {
+        Config {
+            upstreams: vec!["127.0.0.1:8080".parse().unwrap()],
+            active_health_check_interval: Duration::from_secs(10),
+            active_health_check_path: "/".to_string(),
+            dns_refresh_interval: Duration::from_secs(30),
+            max_requests_per_minute: 0,
+            rate_limit_algorithm: rate_limit::Algorithm::FixedWindow,
+            upstream_pool_idle_timeout: Duration::from_secs(60),
+            pools: BTreeMap::new(),
+            routes: Vec::new(),
+            timeouts: Timeouts {
+                connect: Some(Duration::from_secs(10)),
+                header_read: Some(Duration::from_secs(30)),
+                body_read: Some(Duration::from_secs(30)),
+                upstream_response: Some(Duration::from_secs(60)),
+                keep_alive_idle: Some(Duration::from_secs(60)),
+                drain: Some(Duration::from_secs(30)),
+            },
+            circuit_breaker: CircuitBreakerConfig {
+                failure_rate_percent: 0,
+                min_requests: 10,
+                window: Duration::from_secs(30),
+                open_duration: Duration::from_secs(30),
+                half_open_requests: 1,
+            },
+            retry: RetryConfig {
+                max_attempts: 3,
+                retry_on: vec![retry::Condition::ConnectionError],
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(1000),
+                budget_percent: 20,
+                budget_min_per_second: 10,
+            },
+            cache_size: 0,
+            compression: CompressionConfig {
+                enabled: false,
+                types: vec!["text/html".to_string()],
+                min_size: 256,
+            },
+        }
+    }
+
+    #[test]
+    fn parse_upstream_weights() {
+        let upstream: UpstreamConfig = "127.0.0.1:8080=3".parse().unwrap();
+        assert_eq!(upstream.address, "127.0.0.1:8080");
+        assert_eq!(upstream.weight, 3);
+        assert_eq!(
+            "127.0.0.1:8080".parse::<UpstreamConfig>().unwrap().weight,
+            1
+        );
+        assert!("127.0.0.1:8080=0".parse::<UpstreamConfig>().is_err());
+        assert!("127.0.0.1:8080=heavy".parse::<UpstreamConfig>().is_err());
+        assert!("=2".parse::<UpstreamConfig>().is_err());
+    }
+
+    #[test]
+    fn file_overrides_command_line() {
+        let config = base_config()
+            .with_file_contents(
+                r#"
+                [[upstream]]
+                address = "10.0.0.1:80"
+                weight = 3
+
+                [[upstream]]
+                address = "10.0.0.2:80"
+
+                [health_check]
+                path = "/healthz"
+
+                [dns]
+                refresh_interval = 5
+
+                [rate_limit]
+                max_requests_per_minute = 100
+                algorithm = "token-bucket"
+
+                [timeouts]
+                upstream_pool_idle = 5
+                connect = 2
+                upstream_response = 0
+                drain = 5
+
+                [circuit_breaker]
+                failure_rate_percent = 50
+                open_duration = 10
+
+                [retry]
+                retry_on = ["timeout", "503"]
+                max_backoff_ms = 200
+
+                [cache]
+                max_size = 1048576
+
+                [compression]
+                enabled = true
+                types = ["text/*", "application/json"]
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            config.upstreams,
+            vec![
+                UpstreamConfig {
+                    address: "10.0.0.1:80".to_string(),
+                    weight: 3
+                },
+                UpstreamConfig {
+                    address: "10.0.0.2:80".to_string(),
+                    weight: 1
+                },
+            ]
+        );
+        assert_eq!(config.active_health_check_path, "/healthz");
+        assert_eq!(config.dns_refresh_interval, Duration::from_secs(5));
+        assert_eq!(config.max_requests_per_minute, 100);
+        assert_eq!(
+            config.rate_limit_algorithm,
+            rate_limit::Algorithm::TokenBucket
+        );
+        assert_eq!(config.upstream_pool_idle_timeout, Duration::from_secs(5));
+        assert_eq!(config.timeouts.connect, Some(Duration::from_secs(2)));
+        assert_eq!(config.timeouts.upstream_response, None);
+        assert_eq!(config.timeouts.body_read, Some(Duration::from_secs(30)));
+        assert_eq!(config.timeouts.drain, Some(Duration::from_secs(5)));
+        assert_eq!(config.circuit_breaker.failure_rate_percent, 50);
+        assert_eq!(
+            config.circuit_breaker.open_duration,
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.circuit_breaker.min_requests, 10);
+        assert_eq!(
+            config.retry.retry_on,
+            vec![
+                retry::Condition::Timeout,
+                retry::Condition::Status(http::StatusCode::SERVICE_UNAVAILABLE)
+            ]
+        );
+        assert_eq!(config.retry.max_backoff, Duration::from_millis(200));
+        assert_eq!(config.retry.max_attempts, 3);
+        assert_eq!(config.cache_size, 1048576);
+        assert!(config.compression.enabled);
+        assert_eq!(
+            config.compression.types,
+            vec!["text/*".to_string(), "application/json".to_string()]
+        );
+        assert_eq!(config.compression.min_size, 256);
+        // Settings the file leaves out keep their command-line values
+        assert_eq!(config.active_health_check_interval, Duration::from_secs(10));
+
+        assert_eq!(base_config().with_file_contents("").unwrap(), base_config());
+    }
+
+    #[test]
+    fn parse_pools_and_routes() {
+        let config = base_config()
+            .with_file_contents(
+                r#"
+                [pools]
+                api = [{ address = "10.0.1.1:80", weight = 2 }, { address = "10.0.1.2:80" }]
+
+                [[route]]
+                host = "api.example.com"
+                path_prefix = "/v1"
+                rewrite_prefix = "/"
+                pool = "api"
+
+                [[route]]
+                path_regex = "^/static/"
+                "#,
+            )
+            .unwrap();
+        assert_eq!(config.pools["api"].len(), 2);
+        assert_eq!(config.pools["api"][0].weight, 2);
+        assert_eq!(config.all_upstreams().count(), 3);
+        assert_eq!(
+            config.routes,
+            vec![
+                RouteConfig {
+                    host: Some("api.example.com".to_string()),
+                    path_prefix: Some("/v1".to_string()),
+                    path_regex: None,
+                    pool: "api".to_string(),
+                    rewrite: Some(PathRewrite::ReplacePrefix("/".to_string())),
+                },
+                RouteConfig {
+                    host: None,
+                    path_prefix: None,
+                    path_regex: Some("^/static/".to_string()),
+                    pool: DEFAULT_POOL.to_string(),
+                    rewrite: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_routes() {
+        let base = base_config();
+        for contents in &[
+            // Unknown pool
+            "[[route]]\npool = \"api\"",
+            // The default pool can't be redefined
+            "[pools]\ndefault = [{ address = \"a:1\" }]",
+            // Empty pool
+            "[pools]\napi = []",
+            // Upstream in two pools
+            "[pools]\napi = [{ address = \"127.0.0.1:8080\" }]",
+            // Rewriting without a prefix to rewrite
+            "[[route]]\nstrip_prefix = true",
+            "[[route]]\npath_prefix = \"/a\"\nstrip_prefix = true\nrewrite_prefix = \"/b\"",
+            "[[route]]\npath_prefix = \"/a\"\nrewrite_prefix = \"b\"",
+            "[[route]]\npath_prefix = \"/a\"\nrewrite_prefix = \"/a b\"",
+            "[[route]]\npath_prefix = \"/a\"\nrewrite_prefix = \"/b?c=d\"",
+            "[[route]]\npath_prefix = \"a\"",
+            "[[route]]\npath_regex = \"(\"",
+        ] {
+            assert!(
+                matches!(base.with_file_contents(contents), Err(Error::Invalid(_))),
+                "{:?} should be rejected",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let base = base_config();
+        assert!(matches!(
+            base.with_file_contents("[health_check]\ninterval = \"soon\""),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[healthcheck]\ninterval = 5"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("upstream = []"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[[upstream]]\naddress = \"a:1\"\nweight = 0"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents(
+                "[[upstream]]\naddress = \"a:1\"\n[[upstream]]\naddress = \"a:1\""
+            ),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[health_check]\npath = \"healthz\""),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[dns]\nrefresh_interval = 0"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[rate_limit]\nalgorithm = \"leaky-bucket\""),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[circuit_breaker]\nfailure_rate_percent = 101"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents(
+                "[circuit_breaker]\nfailure_rate_percent = 50\nhalf_open_requests = 0"
+            ),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[retry]\nretry_on = [\"5xx\"]"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            base.with_file_contents("[retry]\nmax_attempts = 0"),
+            Err(Error::Invalid(_))
+        ));
+    }
+}
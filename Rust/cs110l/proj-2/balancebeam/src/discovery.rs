@@ -0,0 +1,242 @@
+use crate::config::Config;
+use crate::timeout;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// Looks up the IP addresses behind upstream hostnames
+pub trait Resolver: Send + Sync {
+    /// Returns every address `host` resolves to. An empty list or an error both mean there is
+    /// nothing to connect to right now.
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+/// Creates the resolver to use. Hostnames are looked up through the system resolver, unless a file
+/// in /etc/hosts format is given to take its place.
+pub fn new_resolver(hosts_file: Option<&str>) -> Box<dyn Resolver> {
+    match hosts_file {
+        Some(path) => Box::new(HostsFile {
+            path: PathBuf::from(path),
+        }),
+        None => Box::new(System),
+    }
+}
+
+/// Resolves hostnames the way the rest of the system does (through getaddrinfo)
+pub struct System;
+
+impl Resolver for System {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|address| address.ip())
+                .collect())
+        })
+    }
+}
+
+/// Resolves hostnames from a file in /etc/hosts format. The file is read again for every lookup, so
+/// that editing it has the same effect as DNS records changing.
+pub struct HostsFile {
+    path: PathBuf,
+}
+
+impl Resolver for HostsFile {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let contents = tokio::fs::read_to_string(&self.path).await?;
+            Ok(parse_hosts(&contents, host))
+        })
+    }
+}
+
+/// Returns the addresses listed for `host` in the contents of a hosts file, in the order they
+/// appear
+fn parse_hosts(contents: &str, host: &str) -> Vec<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap();
+            let mut fields = line.split_whitespace();
+            let address: IpAddr = fields.next()?.parse().ok()?;
+            if fields.any(|name| name.eq_ignore_ascii_case(host)) {
+                Some(address)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Splits an upstream address into its hostname and port, or returns None if the address is an
+/// IP address (which needs no resolving) or has no port
+pub fn hostname(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let unbracketed = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || unbracketed.parse::<IpAddr>().is_ok() {
+        None
+    } else {
+        Some((host, port))
+    }
+}
+
+/// The backend addresses (ip:port) that each hostname upstream resolved to, keyed by the upstream's
+/// address as configured
+pub type Resolved = HashMap<String, Vec<String>>;
+
+/// Returns the addresses to send an upstream's requests to. Upstreams given by IP address, and
+/// hostnames that haven't resolved to anything yet, are used as they are.
+pub fn backends(address: &str, resolved: &Resolved) -> Vec<String> {
+    match resolved.get(address) {
+        Some(backends) if !backends.is_empty() => backends.clone(),
+        _ => vec![address.to_string()],
+    }
+}
+
+/// Looks up every hostname upstream in `config`. A hostname whose lookup fails keeps its addresses
+/// from `previous`, so that a DNS outage doesn't take working upstreams out of rotation. Lookups
+/// are subject to the connect timeout.
+pub async fn resolve_all(
+    resolver: &dyn Resolver,
+    config: &Config,
+    previous: &Resolved,
+) -> Resolved {
+    let lookups = config.all_upstreams().filter_map(|upstream| {
+        let (host, port) = hostname(&upstream.address)?;
+        Some(async move {
+            let lookup = timeout::optional(config.timeouts.connect, resolver.lookup(host)).await;
+            let backends = match lookup {
+                Ok(Ok(addresses)) => {
+                    let mut backends: Vec<String> = addresses
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip, port).to_string())
+                        .collect();
+                    backends.sort();
+                    backends.dedup();
+                    backends
+                }
+                Ok(Err(err)) => {
+                    log::warn!("Could not resolve upstream {}: {}", upstream.address, err);
+                    previous.get(&upstream.address).cloned().unwrap_or_default()
+                }
+                Err(_) => {
+                    log::warn!("Timed out resolving upstream {}", upstream.address);
+                    previous.get(&upstream.address).cloned().unwrap_or_default()
+                }
+            };
+            (upstream.address.clone(), backends)
+        })
+    });
+    futures::future::join_all(lookups)
+        .await
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::base_config;
+
+    /// Answers lookups from a fixed table, failing for hostnames that aren't in it
+    struct Stub(HashMap<&'static str, Vec<IpAddr>>);
+
+    impl Resolver for Stub {
+        fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+            let result = self
+                .0
+                .get(host)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such host"));
+            Box::pin(async move { result })
+        }
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_hostnames() {
+        assert_eq!(
+            hostname("backend.local:8080"),
+            Some(("backend.local", 8080))
+        );
+        assert_eq!(hostname("localhost:80"), Some(("localhost", 80)));
+        assert_eq!(hostname("127.0.0.1:8080"), None);
+        assert_eq!(hostname("[::1]:8080"), None);
+        assert_eq!(hostname("backend.local"), None);
+        assert_eq!(hostname("backend.local:http"), None);
+    }
+
+    #[test]
+    fn parses_hosts_files() {
+        let contents = "127.0.0.1 localhost\n\
+                        # 10.0.0.9 backend.local\n\
+                        10.0.0.1 backend.local backend # first\n\
+                        not-an-ip backend.local\n\
+                        ::1 localhost BACKEND.local\n";
+        assert_eq!(
+            parse_hosts(contents, "backend.local"),
+            vec![ip("10.0.0.1"), ip("::1")]
+        );
+        assert_eq!(parse_hosts(contents, "backend"), vec![ip("10.0.0.1")]);
+        assert!(parse_hosts(contents, "missing.local").is_empty());
+    }
+
+    #[test]
+    fn expands_resolved_hostnames() {
+        let mut resolved = Resolved::new();
+        resolved.insert(
+            "backend.local:80".to_string(),
+            vec!["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()],
+        );
+        resolved.insert("empty.local:80".to_string(), Vec::new());
+        assert_eq!(
+            backends("backend.local:80", &resolved),
+            vec!["10.0.0.1:80", "10.0.0.2:80"]
+        );
+        assert_eq!(
+            backends("empty.local:80", &resolved),
+            vec!["empty.local:80"]
+        );
+        assert_eq!(backends("10.0.0.3:80", &resolved), vec!["10.0.0.3:80"]);
+    }
+
+    #[tokio::test]
+    async fn resolves_hostname_upstreams() {
+        let mut config = base_config();
+        config.upstreams = vec![
+            "backend.local:80".parse().unwrap(),
+            "10.0.0.9:80".parse().unwrap(),
+            "flaky.local:8080".parse().unwrap(),
+        ];
+        let mut stub = Stub(HashMap::new());
+        stub.0.insert(
+            "backend.local",
+            vec![ip("10.0.0.2"), ip("10.0.0.1"), ip("10.0.0.2"), ip("::1")],
+        );
+        let resolved = resolve_all(&stub, &config, &Resolved::new()).await;
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(
+            resolved["backend.local:80"],
+            vec!["10.0.0.1:80", "10.0.0.2:80", "[::1]:80"]
+        );
+        assert!(resolved["flaky.local:8080"].is_empty());
+
+        // Failed lookups keep the last addresses we knew about, but records going away are noticed
+        stub.0.insert("backend.local", vec![ip("10.0.0.3")]);
+        let mut previous = resolved;
+        previous.insert(
+            "flaky.local:8080".to_string(),
+            vec!["10.0.1.1:8080".to_string()],
+        );
+        let resolved = resolve_all(&stub, &config, &previous).await;
+        assert_eq!(resolved["backend.local:80"], vec!["10.0.0.3:80"]);
+        assert_eq!(resolved["flaky.local:8080"], vec!["10.0.1.1:8080"]);
+    }
+}
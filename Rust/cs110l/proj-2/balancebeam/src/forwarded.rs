@@ -0,0 +1,286 @@
+use crate::request;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const FORWARDED: &str = "forwarded";
+
+/// The proxies (such as a CDN or another load balancer in front of us) whose forwarding headers we
+/// believe. Forwarding headers from anyone else are thrown away, since clients can put whatever
+/// they like in them.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl std::str::FromStr for TrustedProxies {
+    type Err = String;
+
+    /// Parses a comma-separated list of CIDR networks (or single IP addresses)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let networks = s
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid network {:?}", network))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+}
+
+/// Pulls the node identifiers out of a Forwarded header's `for=` parameters, in order
+fn forwarded_for_values(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_at(pair.find('=')?);
+                if name.trim().eq_ignore_ascii_case("for") {
+                    Some(value[1..].trim().trim_matches('"').to_string())
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parses an IP address from X-Forwarded-For or a Forwarded `for=` parameter, which may have a
+/// port and (for IPv6) brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest[..rest.find(']')?].parse().ok();
+    }
+    node[..node.rfind(':')?].parse().ok()
+}
+
+/// Formats a value for a Forwarded header parameter, quoting it if it isn't a plain token
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Works out the IP address of the client that sent a request. If it came straight from the
+    /// client, that's the peer address. If it came through trusted proxies, it's the last address
+    /// they recorded that isn't another trusted proxy. The Forwarded header is preferred over
+    /// X-Forwarded-For if both are present.
+    pub fn client_ip(&self, peer: IpAddr, request: &http::Request<Vec<u8>>) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let headers = request.headers();
+        let chain: Vec<String> = if headers.contains_key(FORWARDED) {
+            headers
+                .get_all(FORWARDED)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(forwarded_for_values)
+                .collect()
+        } else {
+            headers
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(',').map(str::to_string).collect::<Vec<_>>())
+                .collect()
+        };
+        let mut client = peer;
+        for node in chain.iter().rev() {
+            // A node we can't make sense of (such as an obfuscated identifier) ends the chain;
+            // the last address we believed is as close to the client as we can get
+            match parse_node(node) {
+                Some(ip) => {
+                    client = ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+
+    /// Sets the forwarding headers on a request received from `peer` over `scheme` (http or
+    /// https), before it is sent to an upstream. Forwarding headers from trusted proxies are kept
+    /// and added to; anyone else's are replaced.
+    pub fn set_forwarding_headers(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+        peer: IpAddr,
+        scheme: &'static str,
+    ) {
+        if !self.contains(peer) {
+            for name in &[
+                X_FORWARDED_FOR,
+                X_FORWARDED_PROTO,
+                X_FORWARDED_HOST,
+                FORWARDED,
+            ] {
+                request.headers_mut().remove(*name);
+            }
+        }
+        let host = request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(str::to_string);
+
+        request::extend_header_value(request, X_FORWARDED_FOR, &peer.to_string());
+        // A trusted proxy knows better than we do how the client connected
+        let headers = request.headers_mut();
+        if !headers.contains_key(X_FORWARDED_PROTO) {
+            headers.insert(X_FORWARDED_PROTO, http::HeaderValue::from_static(scheme));
+        }
+        if let Some(host) = &host {
+            if !headers.contains_key(X_FORWARDED_HOST) {
+                if let Ok(value) = http::HeaderValue::from_str(host) {
+                    headers.insert(X_FORWARDED_HOST, value);
+                }
+            }
+        }
+
+        let node = match peer {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        let mut element = format!("for={};proto={}", forwarded_value(&node), scheme);
+        if let Some(host) = &host {
+            element += &format!(";host={}", forwarded_value(host));
+        }
+        request::extend_header_value(request, FORWARDED, &element);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut builder = http::Request::builder().uri("/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    fn header<'a>(request: &'a http::Request<Vec<u8>>, name: &str) -> Option<&'a str> {
+        request
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn parses_networks() {
+        let trusted: TrustedProxies = "10.0.0.0/8, 192.168.1.1,::1".parse().unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("192.168.1.1".parse().unwrap()));
+        assert!(!trusted.contains("192.168.1.2".parse().unwrap()));
+        assert!(trusted.contains("::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("proxy.example.com".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn resolves_client_ip() {
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.9".parse().unwrap();
+        let spoofed = request(&[("X-Forwarded-For", "1.2.3.4")]);
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(trusted.client_ip(stranger, &spoofed), stranger);
+        // Trusted proxies are skipped over, and anything before the client is ignored
+        let forwarded = request(&[("X-Forwarded-For", "1.2.3.4, 198.51.100.7, 10.0.0.2")]);
+        assert_eq!(
+            trusted.client_ip(proxy, &forwarded),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        // Forwarded takes precedence over X-Forwarded-For
+        let forwarded = request(&[
+            ("X-Forwarded-For", "1.2.3.4"),
+            (
+                "Forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            trusted.client_ip(proxy, &forwarded),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        // With no forwarding headers, the proxy is the client
+        assert_eq!(trusted.client_ip(proxy, &request(&[])), proxy);
+    }
+
+    #[test]
+    fn replaces_untrusted_headers() {
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let mut request = request(&[
+            ("Host", "example.com:8080"),
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("X-Forwarded-Proto", "https"),
+            ("Forwarded", "for=1.2.3.4"),
+        ]);
+        trusted.set_forwarding_headers(&mut request, "203.0.113.9".parse().unwrap(), "http");
+        assert_eq!(header(&request, "x-forwarded-for"), Some("203.0.113.9"));
+        assert_eq!(header(&request, "x-forwarded-proto"), Some("http"));
+        assert_eq!(
+            header(&request, "x-forwarded-host"),
+            Some("example.com:8080")
+        );
+        assert_eq!(
+            header(&request, "forwarded"),
+            Some("for=203.0.113.9;proto=http;host=\"example.com:8080\"")
+        );
+    }
+
+    #[test]
+    fn extends_trusted_headers() {
+        let trusted: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let mut request = request(&[
+            ("Host", "example.com"),
+            ("X-Forwarded-For", "198.51.100.7"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "www.example.com"),
+            ("Forwarded", "for=198.51.100.7;proto=https"),
+        ]);
+        trusted.set_forwarding_headers(&mut request, "10.0.0.1".parse().unwrap(), "http");
+        assert_eq!(
+            header(&request, "x-forwarded-for"),
+            Some("198.51.100.7, 10.0.0.1")
+        );
+        assert_eq!(header(&request, "x-forwarded-proto"), Some("https"));
+        assert_eq!(
+            header(&request, "x-forwarded-host"),
+            Some("www.example.com")
+        );
+        assert_eq!(
+            header(&request, "forwarded"),
+            Some("for=198.51.100.7;proto=https, for=10.0.0.1;proto=http;host=example.com")
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+/// The name we give ourselves in Via headers
+const PSEUDONYM: &str = "balancebeam";
+
+/// Headers that only apply to a single connection (RFC 7230 section 6.1), so must not be passed on
+/// by a proxy. Transfer-Encoding and Trailer are hop-by-hop too, but we pass chunked bodies and
+/// their trailers through untouched, so they still describe the message we send on.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+/// Returns the (lowercased) options listed in a message's Connection headers
+fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Returns true if the sender of a message with the given version and headers expects the
+/// connection to stay open afterwards. HTTP/1.1 connections are persistent unless either side says
+/// "close"; HTTP/1.0 connections are closed unless the sender asks for "keep-alive".
+pub fn keep_alive(version: http::Version, headers: &HeaderMap) -> bool {
+    let options = connection_options(headers);
+    if options.iter().any(|option| option == "close") {
+        return false;
+    }
+    match version {
+        http::Version::HTTP_09 => false,
+        http::Version::HTTP_10 => options.iter().any(|option| option == "keep-alive"),
+        _ => true,
+    }
+}
+
+/// Removes the headers that were meant for us rather than whoever we pass the message on to: the
+/// standard hop-by-hop headers, and any others the sender listed in its Connection header
+pub fn strip(headers: &mut HeaderMap) {
+    for option in connection_options(headers) {
+        // Never let a client make us drop the framing of the message
+        if option == "transfer-encoding" || option == "content-length" {
+            continue;
+        }
+        if let Ok(name) = HeaderName::from_bytes(option.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+/// Returns the protocols a request asks to switch its connection to, if it asks to. Upgrade is
+/// hop-by-hop, so the request must also list it in its Connection header, and HTTP/1.0 requests
+/// can't be upgraded at all.
+pub fn upgrade(version: http::Version, headers: &HeaderMap) -> Option<HeaderValue> {
+    if version < http::Version::HTTP_11
+        || !connection_options(headers)
+            .iter()
+            .any(|option| option == "upgrade")
+    {
+        return None;
+    }
+    headers.get(header::UPGRADE).cloned()
+}
+
+/// Puts back the Upgrade header (and the Connection header that goes with it) after the message has
+/// been stripped, so that the upgrade handshake can pass through us
+pub fn set_upgrade(headers: &mut HeaderMap, protocols: HeaderValue) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, protocols);
+}
+
+/// Adds ourselves to the end of a message's Via header, recording the version of HTTP the message
+/// was received with
+pub fn add_via(headers: &mut HeaderMap, received_version: http::Version) {
+    let protocol = match received_version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        _ => "1.1",
+    };
+    let entry = format!("{} {}", protocol, PSEUDONYM);
+    let value = match headers.get(header::VIA) {
+        Some(existing) => [existing.as_bytes(), b", ", entry.as_bytes()].concat(),
+        None => entry.into_bytes(),
+    };
+    headers.insert(header::VIA, HeaderValue::from_bytes(&value).unwrap());
+}
+
+/// Sets the Connection header on a response to a client that sent a `request_version` request, so
+/// the client knows whether we'll keep the connection open. HTTP/1.0 messages are only persistent
+/// if they say so.
+pub fn set_connection(
+    response: &mut http::Response<Vec<u8>>,
+    request_version: http::Version,
+    keep_alive: bool,
+) {
+    let value = if !keep_alive {
+        "close"
+    } else if request_version == http::Version::HTTP_10
+        || response.version() == http::Version::HTTP_10
+    {
+        "keep-alive"
+    } else {
+        return;
+    };
+    response
+        .headers_mut()
+        .insert(header::CONNECTION, HeaderValue::from_static(value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version() {
+        let none = headers(&[]);
+        let close = headers(&[("Connection", "Close")]);
+        let keep_alive_header = headers(&[("Connection", "foo, Keep-Alive")]);
+        assert!(keep_alive(http::Version::HTTP_11, &none));
+        assert!(!keep_alive(http::Version::HTTP_11, &close));
+        assert!(!keep_alive(http::Version::HTTP_10, &none));
+        assert!(keep_alive(http::Version::HTTP_10, &keep_alive_header));
+        assert!(!keep_alive(http::Version::HTTP_10, &close));
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("Connection", "keep-alive, X-Secret, Content-Length"),
+            ("Keep-Alive", "timeout=5"),
+            ("X-Secret", "hunter2"),
+            ("Proxy-Authorization", "Basic Zm9vOmJhcg=="),
+            ("TE", "trailers"),
+            ("Upgrade", "h2c"),
+            ("Transfer-Encoding", "chunked"),
+            ("Content-Length", "12"),
+            ("X-Kept", "yes"),
+        ]);
+        strip(&mut headers);
+        let mut remaining: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+        remaining.sort_unstable();
+        assert_eq!(
+            remaining,
+            vec!["content-length", "transfer-encoding", "x-kept"]
+        );
+    }
+
+    #[test]
+    fn finds_upgrades() {
+        let upgrading = headers(&[("Connection", "Upgrade"), ("Upgrade", "websocket")]);
+        assert_eq!(
+            upgrade(http::Version::HTTP_11, &upgrading),
+            Some(HeaderValue::from_static("websocket"))
+        );
+        assert_eq!(upgrade(http::Version::HTTP_10, &upgrading), None);
+        let unlisted = headers(&[("Upgrade", "websocket")]);
+        assert_eq!(upgrade(http::Version::HTTP_11, &unlisted), None);
+    }
+
+    #[test]
+    fn extends_via() {
+        let mut headers = headers(&[]);
+        add_via(&mut headers, http::Version::HTTP_10);
+        assert_eq!(headers["via"], "1.0 balancebeam");
+        add_via(&mut headers, http::Version::HTTP_11);
+        assert_eq!(headers["via"], "1.0 balancebeam, 1.1 balancebeam");
+    }
+}
@@ -1,9 +1,53 @@
+mod access_log;
+mod admin;
+mod balancer;
+mod body;
+mod cache;
+mod chunked;
+mod circuit_breaker;
+mod clock;
+mod compression;
+mod config;
+mod discovery;
+mod forwarded;
+mod hop_by_hop;
+mod metrics;
+mod pool;
+mod rate_limit;
 mod request;
 mod response;
+mod retry;
+mod routing;
+mod shutdown;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upstream;
 
+use access_log::AccessLog;
+use balancer::Balancer;
+use cache::Cache;
 use clap::Clap;
-use rand::{Rng, SeedableRng};
-use std::net::{TcpListener, TcpStream};
+use config::Config;
+use forwarded::TrustedProxies;
+use metrics::Metrics;
+use parking_lot::RwLock;
+use pool::{ConnectionPool, PoolConfig};
+use rate_limit::RateLimiter;
+use routing::Router;
+use shutdown::Shutdown;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tls::ClientStream;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::stream::StreamExt;
+use tokio::time::delay_for;
+use tokio_rustls::TlsAcceptor;
+use upstream::{ActiveConnection, Upstream};
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -17,8 +61,59 @@ struct CmdOptions {
         default_value = "0.0.0.0:1100"
     )]
     bind: String,
-    #[clap(short, long, about = "Upstream host to forward requests to")]
+    #[clap(
+        long,
+        about = "PEM certificate chain to serve HTTPS with. Give this more than once (each with its \
+        own --tls-key) to pick a certificate by SNI hostname; the first one is the default."
+    )]
+    tls_cert: Vec<String>,
+    #[clap(
+        long,
+        about = "PEM private key for the --tls-cert given in the same position"
+    )]
+    tls_key: Vec<String>,
+    #[clap(
+        long,
+        about = "Proxy (IP address or CIDR network) in front of balancebeam whose X-Forwarded-* and \
+        Forwarded headers can be believed. Can be given more than once. These headers are \
+        stripped from requests from anyone else."
+    )]
+    trusted_proxy: Vec<String>,
+    #[clap(
+        long,
+        about = "Pass CONNECT requests on to upstreams, and tunnel the connection to the upstream if \
+        it accepts (CONNECT requests are refused with 501 otherwise)"
+    )]
+    allow_connect: bool,
+    #[clap(
+        short,
+        long,
+        about = "Upstream host to forward requests to (host:port, or host:port=weight to set its \
+        weight for weighted-random balancing). A hostname becomes one upstream for each address it \
+        resolves to, each with the given weight."
+    )]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "Look up upstream hostnames again on this interval (in seconds), adding and removing \
+        upstreams as their DNS records change",
+        default_value = "30"
+    )]
+    dns_refresh_interval: u64,
+    #[clap(
+        long,
+        about = "Resolve upstream hostnames using this file, in /etc/hosts format, instead of DNS. \
+        The file is read again for every lookup."
+    )]
+    dns_hosts_file: Option<String>,
+    #[clap(
+        short,
+        long,
+        about = "TOML file with upstream, pool, routing, health check, DNS, rate limit, timeout, \
+        circuit breaker, retry, cache and compression settings, which take precedence over the \
+        command-line options. The file is re-read on SIGHUP."
+    )]
+    config: Option<String>,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
@@ -26,9 +121,9 @@ struct CmdOptions {
     )]
     active_health_check_interval: usize,
     #[clap(
-    long,
-    about = "Path to send request to for active health checks",
-    default_value = "/"
+        long,
+        about = "Path to send request to for active health checks",
+        default_value = "/"
     )]
     active_health_check_path: String,
     #[clap(
@@ -37,165 +132,2108 @@ struct CmdOptions {
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "How to count requests for rate limiting (fixed-window, sliding-window-log or \
+        token-bucket)",
+        default_value = "fixed-window"
+    )]
+    rate_limit_algorithm: rate_limit::Algorithm,
+    #[clap(
+        long,
+        about = "How to choose an upstream for each connection (random, round-robin, \
+        least-connections, weighted-random or consistent-hash)",
+        default_value = "random"
+    )]
+    balance_strategy: balancer::Strategy,
+    #[clap(
+        long,
+        about = "Request header to hash on for consistent-hash balancing (defaults to the client IP)"
+    )]
+    hash_header: Option<String>,
+    #[clap(
+        long,
+        about = "Maximum number of idle connections to keep open to each upstream (0 = no pooling)",
+        default_value = "8"
+    )]
+    upstream_pool_size: usize,
+    #[clap(
+        long,
+        about = "Close pooled upstream connections that have been idle for this long (in seconds)",
+        default_value = "60"
+    )]
+    upstream_pool_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Check pooled upstream connections for expiry or closure on this interval (in \
+        seconds, 0 = only check when a connection is about to be reused)",
+        default_value = "10"
+    )]
+    upstream_pool_eviction_interval: u64,
+    #[clap(
+        long,
+        about = "Reject request bodies larger than this many bytes (unlimited if not set)"
+    )]
+    max_request_body_size: Option<u64>,
+    #[clap(
+        long,
+        about = "Refuse to forward response bodies larger than this many bytes (unlimited if not set)"
+    )]
+    max_response_body_size: Option<u64>,
+    #[clap(
+        long,
+        about = "Give up connecting to an upstream after this many seconds (0 = no timeout)",
+        default_value = "10"
+    )]
+    connect_timeout: u64,
+    #[clap(
+        long,
+        about = "Answer 408 if a client takes longer than this many seconds to send a request's \
+        headers (0 = no timeout)",
+        default_value = "30"
+    )]
+    header_read_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up on a request or response body if no data arrives for this many seconds (0 \
+        = no timeout)",
+        default_value = "30"
+    )]
+    body_read_timeout: u64,
+    #[clap(
+        long,
+        about = "Answer 504 if an upstream takes longer than this many seconds to start responding \
+        (0 = no timeout)",
+        default_value = "60"
+    )]
+    upstream_response_timeout: u64,
+    #[clap(
+        long,
+        about = "Close client connections that sit idle between requests for this many seconds (0 \
+        = no timeout)",
+        default_value = "60"
+    )]
+    keep_alive_timeout: u64,
+    #[clap(
+        long,
+        about = "On SIGTERM or SIGINT, give in-flight requests up to this many seconds to finish \
+        before exiting anyway (0 = no timeout)",
+        default_value = "30"
+    )]
+    drain_timeout: u64,
+    #[clap(
+        long,
+        about = "Stop sending requests to an upstream when this percentage of its recent requests \
+        have failed with a 5xx response, a timeout or a connection error (0 = never)",
+        default_value = "0"
+    )]
+    circuit_breaker_failure_rate: u32,
+    #[clap(
+        long,
+        about = "Fewest recent requests to an upstream before its failure rate can trip its \
+        circuit breaker",
+        default_value = "10"
+    )]
+    circuit_breaker_min_requests: usize,
+    #[clap(
+        long,
+        about = "How far back (in seconds) to look when working out an upstream's failure rate",
+        default_value = "30"
+    )]
+    circuit_breaker_window: u64,
+    #[clap(
+        long,
+        about = "How long (in seconds) a tripped circuit breaker turns requests away before \
+        letting trial requests through",
+        default_value = "30"
+    )]
+    circuit_breaker_open_duration: u64,
+    #[clap(
+        long,
+        about = "Number of trial requests that must succeed to close a circuit breaker again",
+        default_value = "1"
+    )]
+    circuit_breaker_half_open_requests: usize,
+    #[clap(
+        long,
+        about = "Most times to send an idempotent request to upstreams before giving up, including \
+        the first (1 = never retry)",
+        default_value = "3"
+    )]
+    retry_max_attempts: usize,
+    #[clap(
+        long,
+        about = "Failure to retry requests after: connection-error, timeout or a response status \
+        code. Can be given more than once.",
+        default_value = "connection-error"
+    )]
+    retry_on: Vec<retry::Condition>,
+    #[clap(
+        long,
+        about = "Longest wait (in milliseconds) before the first retry. The wait is random up to \
+        this limit, which doubles for each retry after that.",
+        default_value = "10"
+    )]
+    retry_backoff_ms: u64,
+    #[clap(
+        long,
+        about = "Longest wait (in milliseconds) before any retry",
+        default_value = "1000"
+    )]
+    retry_max_backoff_ms: u64,
+    #[clap(
+        long,
+        about = "Retries allowed across all requests, as a percentage of the requests in the last \
+        ten seconds",
+        default_value = "20"
+    )]
+    retry_budget_percent: u32,
+    #[clap(
+        long,
+        about = "Retries allowed each second regardless of how many requests there have been",
+        default_value = "10"
+    )]
+    retry_budget_min_per_second: u32,
+    #[clap(
+        long,
+        about = "Keep up to this many bytes of responses to GET requests in memory, and answer \
+        repeated requests from them while they are fresh (0 = no caching)",
+        default_value = "0"
+    )]
+    cache_size: u64,
+    #[clap(
+        long,
+        about = "Compress responses with gzip or brotli for clients that accept it"
+    )]
+    compression: bool,
+    #[clap(
+        long,
+        about = "Media type to compress responses of, such as text/html (text/* covers every text \
+        type). Can be given more than once.",
+        default_values = &[
+            "text/html",
+            "text/plain",
+            "text/css",
+            "text/javascript",
+            "application/javascript",
+            "application/json",
+            "application/xml",
+            "image/svg+xml",
+        ]
+    )]
+    compression_types: Vec<String>,
+    #[clap(
+        long,
+        about = "Don't compress response bodies smaller than this many bytes",
+        default_value = "256"
+    )]
+    compression_min_size: u64,
+    #[clap(
+        long,
+        about = "IP/port to serve Prometheus metrics on, at /metrics (metrics are not served if not \
+        set)"
+    )]
+    metrics_bind: Option<String>,
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on, for listing, adding, removing, draining, \
+        undraining and reweighting upstreams (no admin API if not set). Changes last until the \
+        config file is next reloaded."
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "Bearer token that admin API requests must present. Can also be given in the \
+        BALANCEBEAM_ADMIN_TOKEN environment variable, which keeps it out of the process list."
+    )]
+    admin_token: Option<String>,
+    #[clap(
+        long,
+        about = "File to write a record of every request to (no access log if not set)"
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        about = "Format of access log records (json or clf)",
+        default_value = "clf"
+    )]
+    access_log_format: access_log::Format,
+    #[clap(
+        long,
+        about = "Start a new access log file when the current one would grow past this many bytes",
+        default_value = "104857600"
+    )]
+    access_log_max_size: u64,
+    #[clap(
+        long,
+        about = "Number of old access log files to keep when starting a new one",
+        default_value = "5"
+    )]
+    access_log_max_files: usize,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
 struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
+    /// Upstreams, health check and rate limit settings, which are replaced when the config file is
+    /// reloaded
+    settings: RwLock<Arc<Settings>>,
+    /// Addresses of upstream servers that we have failed to connect to or that failed their last
+    /// active health check. These are skipped when choosing where to send a connection.
+    dead_upstreams: RwLock<HashSet<String>>,
+    /// Addresses of upstreams that have been drained through the admin API. These are skipped when
+    /// choosing where to send a request, but requests already sent to them are allowed to finish.
+    draining_upstreams: RwLock<HashSet<String>>,
+    /// Decides which live upstream each connection goes to
+    balancer: Box<dyn Balancer>,
+    /// Idle keep-alive connections to upstreams, ready to be reused for new requests
+    pool: ConnectionPool,
+    /// Largest request body we forward to an upstream, if limited
+    max_request_body_size: Option<u64>,
+    /// Largest response body we forward to a client, if limited
+    max_response_body_size: Option<u64>,
+    /// Counters reported on the metrics endpoint
+    metrics: Metrics,
+    /// Where to record each request, if anywhere
+    access_log: Option<AccessLog>,
+    /// Proxies whose forwarding headers we believe
+    trusted_proxies: TrustedProxies,
+    /// Whether CONNECT requests are passed on to upstreams
+    allow_connect: bool,
+    /// Keeps retries to a share of recent requests. This outlives config reloads so that a reload
+    /// in the middle of an outage doesn't hand out a fresh budget.
+    retry_budget: retry::Budget,
+    /// Looks up the addresses behind upstream hostnames
+    resolver: Box<dyn discovery::Resolver>,
+}
+
+impl ProxyState {
+    fn log_access(&self, record: access_log::Record) {
+        if let Some(access_log) = &self.access_log {
+            access_log.write(&record);
+        }
+    }
+
+    /// Returns the current settings. Callers should hold on to the returned settings for the whole
+    /// of a request, so that a reload halfway through doesn't mix old and new settings.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().clone()
+    }
+}
+
+/// The part of the proxy state that is built from the config, and replaced as a whole when the
+/// config file is reloaded
+struct Settings {
+    config: Config,
+    /// What each hostname upstream in the config resolved to
+    resolved: discovery::Resolved,
+    /// Servers that we are proxying to, across all pools. Hostname upstreams are expanded into one
+    /// server per resolved address.
+    upstreams: Vec<Arc<Upstream>>,
+    /// The servers in each pool, by pool name
+    pools: HashMap<String, Vec<Arc<Upstream>>>,
+    /// Picks the pool for each request
+    router: Router,
+    /// Tracks how many requests each IP has made, if a per-IP limit was set (Milestone 5)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Responses kept for answering repeated GET requests, if caching is enabled
+    cache: Option<Arc<Cache>>,
 }
 
-fn main() {
+impl Settings {
+    /// Builds the settings for a config, with hostname upstreams expanded using `resolved`.
+    /// Upstreams that are still in the config, and rate limiting and the response cache if they
+    /// haven't changed since `previous`, are carried over, so that a reload doesn't reset
+    /// connection counts or circuit breakers, hand every client a fresh rate limit or empty the
+    /// cache. Carried-over upstreams take their weight from the new config.
+    fn new(config: Config, resolved: discovery::Resolved, previous: Option<&Settings>) -> Settings {
+        // Several hostnames may resolve to the same address, so only keep the first of each
+        let mut upstreams: Vec<Arc<Upstream>> = Vec::new();
+        for upstream_config in config.all_upstreams() {
+            for address in discovery::backends(&upstream_config.address, &resolved) {
+                if upstreams.iter().any(|upstream| upstream.address == address) {
+                    continue;
+                }
+                let backend_config = config::UpstreamConfig {
+                    address,
+                    weight: upstream_config.weight,
+                };
+                let existing = previous.and_then(|previous| {
+                    previous
+                        .upstreams
+                        .iter()
+                        .find(|upstream| upstream.address == backend_config.address)
+                });
+                upstreams.push(match existing {
+                    Some(upstream) => {
+                        upstream.set_weight(backend_config.weight);
+                        upstream.clone()
+                    }
+                    None => Arc::new(Upstream::new(&backend_config)),
+                });
+            }
+        }
+        // Pools share the Upstreams above, so that each upstream's connection count covers all of
+        // its pools (config validation makes sure each address is only in one pool anyway)
+        let pool_upstreams = |upstream_configs: &[config::UpstreamConfig]| {
+            let mut pool: Vec<Arc<Upstream>> = Vec::new();
+            for upstream_config in upstream_configs {
+                for address in discovery::backends(&upstream_config.address, &resolved) {
+                    if !pool.iter().any(|upstream| upstream.address == address) {
+                        pool.push(
+                            upstreams
+                                .iter()
+                                .find(|upstream| upstream.address == address)
+                                .unwrap()
+                                .clone(),
+                        );
+                    }
+                }
+            }
+            pool
+        };
+        let mut pools: HashMap<String, Vec<Arc<Upstream>>> = config
+            .pools
+            .iter()
+            .map(|(name, upstream_configs)| (name.clone(), pool_upstreams(upstream_configs)))
+            .collect();
+        pools.insert(
+            config::DEFAULT_POOL.to_string(),
+            pool_upstreams(&config.upstreams),
+        );
+        let router = Router::new(&config.routes);
+        let rate_limiter = match previous {
+            Some(previous)
+                if previous.config.max_requests_per_minute == config.max_requests_per_minute
+                    && previous.config.rate_limit_algorithm == config.rate_limit_algorithm =>
+            {
+                previous.rate_limiter.clone()
+            }
+            _ if config.max_requests_per_minute > 0 => Some(Arc::new(RateLimiter::new(
+                config.rate_limit_algorithm,
+                config.max_requests_per_minute,
+            ))),
+            _ => None,
+        };
+        // Cached responses were fetched from wherever the old routes sent their requests, so only
+        // keep them if the routes are the same
+        let cache = match previous {
+            Some(previous)
+                if previous.config.cache_size == config.cache_size
+                    && previous.config.routes == config.routes =>
+            {
+                previous.cache.clone()
+            }
+            _ if config.cache_size > 0 => Some(Arc::new(Cache::new(config.cache_size))),
+            _ => None,
+        };
+        Settings {
+            config,
+            resolved,
+            upstreams,
+            pools,
+            router,
+            rate_limiter,
+            cache,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
+    // Start catching signals before anything else. Until then, SIGHUP, SIGTERM and SIGINT would
+    // kill us outright, which would be a surprise to anyone reloading or stopping us while we're
+    // still starting up. Signals that arrive before we're ready for them wait until we are.
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Could not listen for SIGHUP: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut stop_signals = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminates), Ok(interrupts)) => terminates.merge(interrupts),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("Could not listen for SIGTERM and SIGINT: {}", err);
+            std::process::exit(1);
+        }
+    };
+
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
+    let upstreams = match options
+        .upstream
+        .iter()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(upstreams) => upstreams,
+        Err(err) => {
+            log::error!("Bad --upstream option: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let command_line_config = Config {
+        upstreams,
+        // Pools and routes can only be set in the config file
+        pools: Default::default(),
+        routes: Vec::new(),
+        active_health_check_interval: Duration::from_secs(
+            options.active_health_check_interval as u64,
+        ),
+        active_health_check_path: options.active_health_check_path.clone(),
+        dns_refresh_interval: Duration::from_secs(options.dns_refresh_interval),
+        max_requests_per_minute: options.max_requests_per_minute,
+        rate_limit_algorithm: options.rate_limit_algorithm,
+        upstream_pool_idle_timeout: Duration::from_secs(options.upstream_pool_idle_timeout),
+        timeouts: config::Timeouts {
+            connect: config::timeout_from_secs(options.connect_timeout),
+            header_read: config::timeout_from_secs(options.header_read_timeout),
+            body_read: config::timeout_from_secs(options.body_read_timeout),
+            upstream_response: config::timeout_from_secs(options.upstream_response_timeout),
+            keep_alive_idle: config::timeout_from_secs(options.keep_alive_timeout),
+            drain: config::timeout_from_secs(options.drain_timeout),
+        },
+        circuit_breaker: config::CircuitBreakerConfig {
+            failure_rate_percent: options.circuit_breaker_failure_rate,
+            min_requests: options.circuit_breaker_min_requests,
+            window: Duration::from_secs(options.circuit_breaker_window),
+            open_duration: Duration::from_secs(options.circuit_breaker_open_duration),
+            half_open_requests: options.circuit_breaker_half_open_requests,
+        },
+        retry: config::RetryConfig {
+            max_attempts: options.retry_max_attempts,
+            retry_on: options.retry_on.clone(),
+            backoff: Duration::from_millis(options.retry_backoff_ms),
+            max_backoff: Duration::from_millis(options.retry_max_backoff_ms),
+            budget_percent: options.retry_budget_percent,
+            budget_min_per_second: options.retry_budget_min_per_second,
+        },
+        cache_size: options.cache_size,
+        compression: config::CompressionConfig {
+            enabled: options.compression,
+            types: options.compression_types.clone(),
+            min_size: options.compression_min_size,
+        },
+    };
+    let config = match &options.config {
+        Some(path) => command_line_config.with_file(path),
+        None => command_line_config
+            .validate()
+            .map(|_| command_line_config.clone()),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            if options.config.is_none() && options.upstream.is_empty() {
+                log::error!("Specify upstream servers using the --upstream or --config option.");
+            }
+            std::process::exit(1);
+        }
+    };
+    let trusted_proxies = match options.trusted_proxy.join(",").parse() {
+        Ok(trusted_proxies) => trusted_proxies,
+        Err(err) => {
+            log::error!("Bad --trusted-proxy option: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let hash_header = match &options.hash_header {
+        Some(name) => match http::HeaderName::from_str(name) {
+            Ok(header) => Some(header),
+            Err(err) => {
+                log::error!("Bad --hash-header option: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let access_log = match &options.access_log {
+        Some(path) => match AccessLog::open(
+            std::path::Path::new(path),
+            options.access_log_format,
+            options.access_log_max_size,
+            options.access_log_max_files,
+        ) {
+            Ok(access_log) => Some(access_log),
+            Err(err) => {
+                log::error!("Could not open access log {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Terminate TLS on client connections if we've been given certificates
+    let tls_acceptor = if options.tls_cert.is_empty() && options.tls_key.is_empty() {
+        None
+    } else {
+        match tls::load_config(&options.tls_cert, &options.tls_key) {
+            Ok(tls_config) => Some(TlsAcceptor::from(Arc::new(tls_config))),
+            Err(err) => {
+                log::error!("Bad TLS configuration: {}", err);
+                std::process::exit(1);
+            }
+        }
+    };
 
     // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind) {
+    let mut listener = match TcpListener::bind(&options.bind).await {
         Ok(listener) => listener,
         Err(err) => {
             log::error!("Could not bind to {}: {}", options.bind, err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    log::info!(
+        "Listening for {} requests on {}",
+        if tls_acceptor.is_some() {
+            "HTTPS"
+        } else {
+            "HTTP"
+        },
+        options.bind
+    );
 
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
+    // Handle incoming connections. Each connection is handled in its own task, so a slow client
+    // can't hold up anyone else; the state is shared between tasks through an Arc.
+    let pool_config = PoolConfig {
+        max_idle_per_upstream: options.upstream_pool_size,
+        idle_timeout: config.upstream_pool_idle_timeout,
     };
-    for stream in listener.incoming() {
+    // Find out what upstream hostnames resolve to before taking any requests
+    let resolver = discovery::new_resolver(options.dns_hosts_file.as_deref());
+    let resolved = discovery::resolve_all(&*resolver, &config, &discovery::Resolved::new()).await;
+    let state = Arc::new(ProxyState {
+        settings: RwLock::new(Arc::new(Settings::new(config, resolved, None))),
+        dead_upstreams: RwLock::new(HashSet::new()),
+        draining_upstreams: RwLock::new(HashSet::new()),
+        balancer: balancer::new_balancer(options.balance_strategy, hash_header),
+        pool: ConnectionPool::new(pool_config),
+        max_request_body_size: options.max_request_body_size,
+        max_response_body_size: options.max_response_body_size,
+        metrics: Metrics::new(),
+        access_log,
+        trusted_proxies,
+        allow_connect: options.allow_connect,
+        retry_budget: retry::Budget::new(),
+        resolver,
+    });
+
+    // Start checking the health of the upstream servers in the background
+    let health_check_state = state.clone();
+    tokio::spawn(async move {
+        active_health_check(&health_check_state).await;
+    });
+
+    // Keep up with changes to the DNS records of upstream hostnames
+    let discovery_state = state.clone();
+    tokio::spawn(async move {
+        refresh_upstream_addresses(&discovery_state).await;
+    });
+
+    // Periodically close pooled connections that are no longer worth keeping around
+    if options.upstream_pool_eviction_interval > 0 {
+        let eviction_state = state.clone();
+        let eviction_interval = Duration::from_secs(options.upstream_pool_eviction_interval);
+        tokio::spawn(async move {
+            loop {
+                delay_for(eviction_interval).await;
+                eviction_state.pool.evict_stale();
+            }
+        });
+    }
+
+    // Serve metrics to Prometheus on a separate listener, so that they aren't exposed to clients
+    if let Some(metrics_bind) = &options.metrics_bind {
+        let metrics_listener = match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", metrics_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Serving metrics on {}", metrics_bind);
+        let timeouts_state = state.clone();
+        let metrics_state = state.clone();
+        tokio::spawn(metrics::serve(
+            metrics_listener,
+            move || timeouts_state.settings().config.timeouts,
+            move || render_metrics(&metrics_state),
+        ));
+    }
+
+    // Serve the admin API on a separate listener too, to anyone with the admin token
+    if let Some(admin_bind) = &options.admin_bind {
+        let admin_token = match options
+            .admin_token
+            .clone()
+            .or_else(|| std::env::var("BALANCEBEAM_ADMIN_TOKEN").ok())
+        {
+            Some(token) if !token.is_empty() => token,
+            _ => {
+                log::error!("--admin-bind needs an admin token (--admin-token)");
+                std::process::exit(1);
+            }
+        };
+        let admin_listener = match TcpListener::bind(admin_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Serving the admin API on {}", admin_bind);
+        let timeouts_state = state.clone();
+        let admin_state = state.clone();
+        tokio::spawn(admin::serve(
+            admin_listener,
+            admin_token,
+            move || timeouts_state.settings().config.timeouts,
+            move |command| {
+                let state = admin_state.clone();
+                async move { admin_command(&state, command).await }
+            },
+        ));
+    }
+
+    // Re-read the config file whenever we're sent SIGHUP
+    let reload_state = state.clone();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match &options.config {
+                Some(config_path) => {
+                    reload_config(&reload_state, &command_line_config, config_path).await
+                }
+                None => log::warn!("Ignoring SIGHUP: there is no config file (--config) to reload"),
+            }
+        }
+    });
+
+    // Stop accepting connections and let the open ones finish up when we're told to shut down
+    let shutdown = Shutdown::new();
+    let mut incoming = listener.incoming();
+    loop {
+        let stream = tokio::select! {
+            stream = incoming.next() => stream,
+            _ = stop_signals.next() => None,
+        };
+        let stream = match stream {
+            Some(stream) => stream,
+            None => break,
+        };
         if let Ok(stream) = stream {
             // Handle the connection!
-            handle_connection(stream, &state);
+            let state = state.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            let shutdown_watcher = shutdown.watch();
+            tokio::spawn(async move {
+                let client_conn = match tls_acceptor {
+                    Some(tls_acceptor) => match accept_tls(&state, &tls_acceptor, stream).await {
+                        Some(client_conn) => client_conn,
+                        None => return,
+                    },
+                    None => ClientStream::plain(stream),
+                };
+                handle_connection(client_conn, &state, shutdown_watcher).await;
+            });
         }
     }
+
+    // Close the listening socket, so that new clients are turned away, then wait for in-flight
+    // requests to finish. Another signal means don't wait any longer.
+    drop(incoming);
+    drop(listener);
+    log::info!("Shutting down: waiting for open connections to finish");
+    let drain_timeout = state.settings().config.timeouts.drain;
+    let drained = tokio::select! {
+        drained = shutdown.drain(drain_timeout) => drained,
+        _ = stop_signals.next() => false,
+    };
+    if drained {
+        log::info!("All connections closed. Exiting");
+        std::process::exit(0);
+    } else {
+        log::warn!("Exiting with connections still open");
+        std::process::exit(1);
+    }
 }
 
-fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let upstream_idx = rng.gen_range(0, state.upstream_addresses.len());
-    let upstream_ip = &state.upstream_addresses[upstream_idx];
-    TcpStream::connect(upstream_ip).or_else(|err| {
-        log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-        Err(err)
-    })
-    // TODO: implement failover (milestone 3)
+/// Performs the TLS handshake with a new client. The handshake has to finish within the header read
+/// timeout. Returns None if it fails.
+async fn accept_tls(
+    state: &ProxyState,
+    tls_acceptor: &TlsAcceptor,
+    stream: TcpStream,
+) -> Option<ClientStream> {
+    let client_ip = stream.peer_addr().ok()?.ip();
+    let handshake_timeout = state.settings().config.timeouts.header_read;
+    match timeout::optional(handshake_timeout, tls_acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(ClientStream::tls(stream)),
+        Ok(Err(err)) => {
+            log::info!("TLS handshake with {} failed: {}", client_ip, err);
+            None
+        }
+        Err(_) => {
+            log::info!("TLS handshake with {} timed out", client_ip);
+            None
+        }
+    }
 }
 
-fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("{} <- {}", client_ip, response::format_response_line(&response));
-    if let Err(error) = response::write_to_stream(&response, client_conn) {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+/// Re-reads the config file and switches over to the new settings. Client connections stay open,
+/// and pick up the new settings with their next request. If the file can't be read or is invalid,
+/// the current settings stay in place.
+async fn reload_config(state: &ProxyState, command_line_config: &Config, path: &str) {
+    let config = match command_line_config.with_file(path) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!(
+                "Not reloading {}: {}. Keeping the current configuration.",
+                path,
+                err
+            );
+            return;
+        }
+    };
+    // Resolve any new upstream hostnames before switching over, so that they are expanded straight
+    // away
+    let resolved =
+        discovery::resolve_all(&*state.resolver, &config, &state.settings().resolved).await;
+    let mut settings = state.settings.write();
+    replace_settings(state, &mut settings, config, resolved);
+    let addresses: Vec<&str> = settings
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    log::info!(
+        "Reloaded configuration from {}: proxying to {}",
+        path,
+        addresses.join(", ")
+    );
+}
+
+/// Replaces the settings with ones built from `config` and `resolved`, forgetting about upstreams
+/// that are no longer in them. Callers hold the settings write lock throughout, so that a reload,
+/// changes made through the admin API and DNS refreshes can't interleave.
+fn replace_settings(
+    state: &ProxyState,
+    settings: &mut Arc<Settings>,
+    config: Config,
+    resolved: discovery::Resolved,
+) {
+    let new_settings = Settings::new(config, resolved, Some(settings));
+    let addresses: Vec<&str> = new_settings
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    state
+        .dead_upstreams
+        .write()
+        .retain(|address| addresses.contains(&address.as_str()));
+    state
+        .draining_upstreams
+        .write()
+        .retain(|address| addresses.contains(&address.as_str()));
+    state.pool.retain_upstreams(&addresses);
+    state
+        .pool
+        .set_idle_timeout(new_settings.config.upstream_pool_idle_timeout);
+    *settings = Arc::new(new_settings);
+}
+
+/// Carries out a command from the admin API. Returns the upstreams as they are afterwards.
+async fn admin_command(
+    state: &ProxyState,
+    command: admin::Command,
+) -> Result<Vec<admin::UpstreamInfo>, admin::Error> {
+    match &command {
+        admin::Command::List => {}
+        admin::Command::Drain { address } | admin::Command::Undrain { address } => {
+            let settings = state.settings.write();
+            if !settings
+                .upstreams
+                .iter()
+                .any(|upstream| &upstream.address == address)
+            {
+                return Err(admin::Error::NotFound(format!("no upstream {}", address)));
+            }
+            let mut draining_upstreams = state.draining_upstreams.write();
+            if let admin::Command::Drain { .. } = command {
+                draining_upstreams.insert(address.clone());
+            } else {
+                draining_upstreams.remove(address);
+            }
+        }
+        _ => loop {
+            let settings = state.settings();
+            let mut config = settings.config.clone();
+            command.apply(&mut config)?;
+            config
+                .validate()
+                .map_err(|err| admin::Error::Invalid(err.to_string()))?;
+            // Resolve any new upstream hostnames before switching over, as a reload does, so that
+            // they are expanded straight away
+            let resolved =
+                discovery::resolve_all(&*state.resolver, &config, &settings.resolved).await;
+            let mut current = state.settings.write();
+            // If the config was reloaded or changed some other way while we were looking hostnames
+            // up, make the change again on top of the new config
+            if Arc::ptr_eq(&current, &settings) {
+                replace_settings(state, &mut current, config, resolved);
+                break;
+            }
+        },
+    }
+
+    let settings = state.settings();
+    let dead_upstreams = state.dead_upstreams.read().clone();
+    let draining_upstreams = state.draining_upstreams.read().clone();
+    let mut upstreams: Vec<admin::UpstreamInfo> = settings
+        .pools
+        .iter()
+        .flat_map(|(pool, upstreams)| upstreams.iter().map(move |upstream| (pool, upstream)))
+        .map(|(pool, upstream)| admin::UpstreamInfo {
+            address: upstream.address.clone(),
+            pool: pool.clone(),
+            weight: upstream.weight(),
+            healthy: !dead_upstreams.contains(&upstream.address),
+            draining: draining_upstreams.contains(&upstream.address),
+            active_connections: upstream.active_connections(),
+            circuit_state: upstream.circuit_breaker.state().as_str(),
+        })
+        .collect();
+    upstreams.sort_by(|a, b| (&a.pool, &a.address).cmp(&(&b.pool, &b.address)));
+    Ok(upstreams)
+}
+
+/// Periodically looks up upstream hostnames again, adding upstreams for new addresses and removing
+/// the ones for addresses that have gone away. This never returns, so it should be spawned as a
+/// separate task.
+async fn refresh_upstream_addresses(state: &ProxyState) {
+    loop {
+        delay_for(state.settings().config.dns_refresh_interval).await;
+        let settings = state.settings();
+        let resolved =
+            discovery::resolve_all(&*state.resolver, &settings.config, &settings.resolved).await;
+        if resolved == settings.resolved {
+            continue;
+        }
+        let mut current = state.settings.write();
+        // If the config was reloaded or changed through the admin API while we were looking
+        // hostnames up, our answers may be for the wrong upstreams. Try again next time.
+        if !Arc::ptr_eq(&current, &settings) {
+            continue;
+        }
+        for (address, backends) in &resolved {
+            if settings.resolved.get(address) != Some(backends) {
+                log::info!(
+                    "Upstream {} now resolves to {}",
+                    address,
+                    backends.join(", ")
+                );
+            }
+        }
+        let config = current.config.clone();
+        replace_settings(state, &mut current, config, resolved);
     }
 }
 
-fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Renders the metrics for the metrics endpoint, along with the current state of each upstream
+fn render_metrics(state: &ProxyState) -> String {
+    let settings = state.settings();
+    let dead_upstreams = state.dead_upstreams.read().clone();
+    let upstreams: Vec<metrics::UpstreamStatus> = settings
+        .upstreams
+        .iter()
+        .map(|upstream| metrics::UpstreamStatus {
+            address: &upstream.address,
+            active_connections: upstream.active_connections(),
+            healthy: !dead_upstreams.contains(&upstream.address),
+            circuit_state: upstream.circuit_breaker.state(),
+            circuit_opened: upstream.circuit_breaker.times_opened(),
+        })
+        .collect();
+    state.metrics.render(&upstreams)
+}
+
+/// A connection to an upstream server, either taken from the pool or freshly opened
+struct UpstreamConnection {
+    stream: TcpStream,
+    /// Whether the connection came from the pool (and so may have been closed by the upstream
+    /// while it sat idle)
+    reused: bool,
+    /// Bytes read from the upstream past the end of the last response. A well-behaved upstream
+    /// doesn't send anything we haven't asked for, so the connection is only pooled while this is
+    /// empty.
+    buffered: Vec<u8>,
+}
+
+impl UpstreamConnection {
+    fn new(stream: TcpStream, reused: bool) -> UpstreamConnection {
+        UpstreamConnection {
+            stream,
+            reused,
+            buffered: Vec::new(),
+        }
+    }
+}
+
+/// Error from sending a request to an upstream server and reading back its response
+#[derive(Debug)]
+enum UpstreamError {
+    /// Writing the request to the upstream failed
+    Send(std::io::Error),
+    /// The request body the client sent was incomplete, malformed or too large. This is the
+    /// client's fault, not the upstream's.
+    ClientBody(body::Error),
+    /// Reading the upstream's response failed
+    Receive(response::Error),
+    /// The upstream didn't start responding within the upstream response timeout
+    TimedOut,
+}
+
+impl UpstreamError {
+    /// Returns true if the error means the upstream (or our connection to it) is broken, as opposed
+    /// to the client or the upstream's response being unacceptable
+    fn is_connection_failure(&self) -> bool {
+        match self {
+            UpstreamError::Send(_) => true,
+            UpstreamError::ClientBody(_) => false,
+            UpstreamError::Receive(
+                response::Error::ResponseBodyTooLarge | response::Error::DuplicateContentLength,
+            ) => false,
+            UpstreamError::Receive(_) => true,
+            // A slow response is most likely down to the request, so don't take the upstream out
+            // of rotation for it (or send the request to another upstream to time out there too)
+            UpstreamError::TimedOut => false,
+        }
+    }
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Send(error) => write!(f, "failed to send request: {}", error),
+            UpstreamError::ClientBody(error) => {
+                write!(f, "bad request body from client: {}", error)
+            }
+            UpstreamError::Receive(error) => write!(f, "{}", error),
+            UpstreamError::TimedOut => write!(f, "timed out waiting for a response"),
+        }
+    }
+}
+
+/// Opens a new connection to `address`, failing with ErrorKind::TimedOut if it takes longer than
+/// `connect_timeout`
+async fn connect(
+    address: &str,
+    connect_timeout: Option<Duration>,
+) -> Result<TcpStream, std::io::Error> {
+    timeout::optional(connect_timeout, TcpStream::connect(address))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))?
+}
+
+/// Gets a connection to the given upstream, reusing an idle pooled connection if there is one and
+/// `allow_pooled` is set.
+async fn open_upstream_connection(
+    state: &ProxyState,
+    upstream: &Upstream,
+    connect_timeout: Option<Duration>,
+    allow_pooled: bool,
+) -> Result<UpstreamConnection, std::io::Error> {
+    if allow_pooled {
+        if let Some(stream) = state.pool.checkout(&upstream.address) {
+            return Ok(UpstreamConnection::new(stream, true));
+        }
+    }
+    let stream = connect(&upstream.address, connect_timeout).await?;
+    Ok(UpstreamConnection::new(stream, false))
+}
+
+/// Opens a connection to the live upstream in `pool` picked by the balancer, skipping upstreams
+/// whose circuit breaker is open. If the connection is refused or times out, the upstream is marked
+/// dead and the balancer picks again from the pool's remaining live upstreams, until we either
+/// connect successfully or run out of upstreams. Returns a circuit breaker permit to record the
+/// outcome of the request with. On failure, returns the status to send back to the client.
+async fn connect_to_upstream<'a>(
+    state: &ProxyState,
+    settings: &Settings,
+    pool: &'a [Arc<Upstream>],
+    context: &balancer::Context<'_>,
+    allow_pooled: bool,
+) -> Result<
+    (
+        &'a Upstream,
+        UpstreamConnection,
+        circuit_breaker::Permit<'a>,
+    ),
+    http::StatusCode,
+> {
+    let circuit_breaker = &settings.config.circuit_breaker;
+    loop {
+        let live_upstreams: Vec<&Upstream> = {
+            let dead_upstreams = state.dead_upstreams.read();
+            let draining_upstreams = state.draining_upstreams.read();
+            pool.iter()
+                .filter(|upstream| {
+                    !dead_upstreams.contains(&upstream.address)
+                        && !draining_upstreams.contains(&upstream.address)
+                })
+                .map(|upstream| upstream.as_ref())
+                .collect()
+        };
+        if live_upstreams.is_empty() {
+            log::error!("All upstream servers are dead or draining");
+            return Err(http::StatusCode::BAD_GATEWAY);
+        }
+        // Turn the request away straight away rather than send it to an upstream that has been
+        // failing. The breakers will start letting requests through again soon.
+        let available_upstreams: Vec<&Upstream> = live_upstreams
+            .into_iter()
+            .filter(|upstream| upstream.circuit_breaker.is_available(circuit_breaker))
+            .collect();
+        if available_upstreams.is_empty() {
+            log::warn!("Circuit breakers are open for all live upstream servers");
+            return Err(http::StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        let upstream = available_upstreams[state.balancer.choose(&available_upstreams, context)];
+        let permit = match upstream.circuit_breaker.acquire(circuit_breaker) {
+            Some(permit) => permit,
+            // Other requests took the last trial places since we checked
+            None => continue,
+        };
+        let connect_timeout = settings.config.timeouts.connect;
+        match open_upstream_connection(state, upstream, connect_timeout, allow_pooled).await {
+            Ok(connection) => {
+                state.metrics.record_upstream_request(&upstream.address);
+                return Ok((upstream, connection, permit));
+            }
+            Err(err) => {
+                permit.record(false);
+                state.metrics.record_connect_failure(&upstream.address);
+                log::error!(
+                    "Failed to connect to upstream {}: {}",
+                    upstream.address,
+                    err
+                );
+                state
+                    .dead_upstreams
+                    .write()
+                    .insert(upstream.address.clone());
+            }
+        }
+    }
+}
+
+/// Writes a request to an upstream connection, streaming its body from the client, and reads back
+/// the head of the upstream's final response, passing any interim (1xx) responses on to the client.
+/// Returns the response, how its body is framed, and the size of the request body. Anything the
+/// client sent past the end of the request body is added to `client_buffered`.
+async fn exchange_with_upstream(
+    state: &ProxyState,
+    timeouts: &config::Timeouts,
+    upstream_conn: &mut UpstreamConnection,
+    client_conn: &mut ClientStream,
+    client_buffered: &mut Vec<u8>,
+    request: &http::Request<Vec<u8>>,
+    request_framing: body::Framing,
+) -> Result<(http::Response<Vec<u8>>, body::Framing, u64), UpstreamError> {
+    request::write_head_to_stream(request, &mut upstream_conn.stream)
+        .await
+        .map_err(UpstreamError::Send)?;
+    let request_body_size = body::forward(
+        client_conn,
+        request.body(),
+        request_framing,
+        &mut upstream_conn.stream,
+        state.max_request_body_size,
+        timeouts.body_read,
+        client_buffered,
+    )
+    .await
+    .map_err(|error| match error {
+        body::Error::Write(error) => UpstreamError::Send(error),
+        error => UpstreamError::ClientBody(error),
+    })?;
+    log::debug!("Forwarded request to server");
+    loop {
+        let (mut response, framing) = timeout::optional(
+            timeouts.upstream_response,
+            response::read_from_stream(
+                &mut upstream_conn.stream,
+                &mut upstream_conn.buffered,
+                request.method(),
+                state.max_response_body_size,
+            ),
+        )
+        .await
+        .map_err(|_| UpstreamError::TimedOut)?
+        .map_err(UpstreamError::Receive)?;
+        // Interim responses (such as 100 Continue) come ahead of the real one. 101 is final, since
+        // the connection stops being HTTP after it.
+        let status = response.status();
+        if !status.is_informational() || status == http::StatusCode::SWITCHING_PROTOCOLS {
+            return Ok((response, framing, request_body_size));
+        }
+        log::debug!("Upstream sent interim response {}", status);
+        // HTTP/1.0 clients don't know about interim responses, so they don't get sent any. If the
+        // client has gone away, we'll find out when we send it the final response.
+        if request.version() == http::Version::HTTP_11 {
+            hop_by_hop::strip(response.headers_mut());
+            let response_version = response.version();
+            hop_by_hop::add_via(response.headers_mut(), response_version);
+            if response::write_head_to_stream(&response, client_conn)
+                .await
+                .is_ok()
+            {
+                let _ = client_conn.flush().await;
+            }
+        }
+    }
+}
+
+/// Returns true if sending the request more than once has the same effect as sending it once, so
+/// that it is safe to send it again to another upstream if the first one fails partway through.
+fn is_idempotent(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET | http::Method::HEAD | http::Method::PUT | http::Method::DELETE
+    )
+}
+
+/// Returns true if the whole body of a message arrived along with its headers, so that nothing
+/// more needs to be read from the sender
+fn body_already_read(message_body: &[u8], framing: body::Framing) -> bool {
+    match framing {
+        body::Framing::Empty => true,
+        body::Framing::Length(len) => message_body.len() as u64 >= len,
+        body::Framing::Chunked | body::Framing::UntilClose => false,
+    }
+}
+
+/// A response from an upstream whose body is still waiting to be forwarded to the client
+struct UpstreamResponse<'a> {
+    upstream: &'a Upstream,
+    upstream_conn: UpstreamConnection,
+    response: http::Response<Vec<u8>>,
+    framing: body::Framing,
+    /// Size of the request body that was forwarded to the upstream
+    request_body_size: u64,
+    /// Keeps the request counted against the upstream until the response body has been forwarded
+    _active: ActiveConnection<'a>,
+}
+
+/// Decides whether to send a request again after attempt number `attempt` failed with `condition`.
+/// Retries are counted against the global retry budget.
+fn should_retry(
+    state: &ProxyState,
+    config: &config::RetryConfig,
+    retryable: bool,
+    attempt: usize,
+    condition: retry::Condition,
+) -> bool {
+    if !retryable || attempt >= config.max_attempts || !config.retry_on.contains(&condition) {
+        return false;
+    }
+    if !state.retry_budget.try_retry(config) {
+        log::warn!("Retry budget exhausted, not retrying");
+        state.metrics.record_retry_budget_exhausted();
+        return false;
+    }
+    state.metrics.record_retry();
+    true
+}
+
+/// Sends a request to an upstream in `pool` picked by the balancer and returns the head of the upstream's
+/// response. Every request gets its own pick, so a client on a keep-alive connection moves off an
+/// upstream as soon as it is marked dead. If the attempt fails in a way the retry policy covers,
+/// the request is sent again (after a backoff) to whichever upstream the balancer picks next.
+/// Requests whose body was streamed from the client can't be sent again, and non-idempotent
+/// requests may already have had an effect, so they are never retried. On failure, returns the
+/// status to send back to the client.
+#[allow(clippy::too_many_arguments)]
+async fn forward_to_upstream<'a>(
+    state: &ProxyState,
+    settings: &Settings,
+    pool: &'a [Arc<Upstream>],
+    client_conn: &mut ClientStream,
+    client_buffered: &mut Vec<u8>,
+    client_addr: std::net::IpAddr,
+    request: &http::Request<Vec<u8>>,
+    request_framing: body::Framing,
+) -> Result<UpstreamResponse<'a>, http::StatusCode> {
+    let context = balancer::Context {
+        client_ip: client_addr,
+        headers: request.headers(),
+    };
+    let replayable = body_already_read(request.body(), request_framing);
+    let retryable = replayable && is_idempotent(request.method());
+    let retry_config = &settings.config.retry;
+    state.retry_budget.record_request();
+    let mut attempt = 1;
+    loop {
+        // A pooled connection may turn out to be closed after we've sent the request on it, and
+        // the upstream may already have acted on it by then. Only use one if the request is safe
+        // to send again on a fresh connection.
+        let (upstream, mut upstream_conn, permit) =
+            connect_to_upstream(state, settings, pool, &context, retryable).await?;
+        let active = upstream.track_connection();
+        let attempt_started = Instant::now();
+
+        let mut result = exchange_with_upstream(
+            state,
+            &settings.config.timeouts,
+            &mut upstream_conn,
+            client_conn,
+            client_buffered,
+            request,
+            request_framing,
+        )
+        .await;
+        if upstream_conn.reused
+            && result
+                .as_ref()
+                .err()
+                .is_some_and(UpstreamError::is_connection_failure)
+        {
+            // The upstream may have closed the pooled connection just as we picked it up. Give it
+            // one more go on a fresh connection before giving up on it.
+            log::debug!(
+                "Pooled connection to {} failed ({}), retrying on a new connection",
+                upstream.address,
+                result.as_ref().err().unwrap()
+            );
+            match connect(&upstream.address, settings.config.timeouts.connect).await {
+                Ok(stream) => {
+                    upstream_conn = UpstreamConnection::new(stream, false);
+                    result = exchange_with_upstream(
+                        state,
+                        &settings.config.timeouts,
+                        &mut upstream_conn,
+                        client_conn,
+                        client_buffered,
+                        request,
+                        request_framing,
+                    )
+                    .await;
+                }
+                Err(err) => {
+                    // The upstream has gone away. Move on to another one as if we had failed to
+                    // connect in the first place, without using up an attempt.
+                    permit.record(false);
+                    state.metrics.record_connect_failure(&upstream.address);
+                    log::error!(
+                        "Failed to connect to upstream {}: {}",
+                        upstream.address,
+                        err
+                    );
+                    state
+                        .dead_upstreams
+                        .write()
+                        .insert(upstream.address.clone());
+                    if !retryable {
+                        return Err(http::StatusCode::BAD_GATEWAY);
+                    }
+                    continue;
+                }
+            }
+        }
+
+        match result {
+            Ok((response, framing, request_body_size)) => {
+                let status = response.status();
+                permit.record(!status.is_server_error());
+                if !should_retry(
+                    state,
+                    retry_config,
+                    retryable,
+                    attempt,
+                    retry::Condition::Status(status),
+                ) {
+                    return Ok(UpstreamResponse {
+                        upstream,
+                        upstream_conn,
+                        response,
+                        framing,
+                        request_body_size,
+                        _active: active,
+                    });
+                }
+                log::warn!("Upstream {} responded with {}", upstream.address, status);
+                // The response body is never read, so the connection is closed rather than pooled
+                state.metrics.record_upstream_response(
+                    &upstream.address,
+                    status,
+                    attempt_started.elapsed(),
+                );
+            }
+            Err(UpstreamError::ClientBody(error)) => {
+                log::info!("Error forwarding request body from client: {}", error);
+                return Err(match error {
+                    body::Error::TooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    body::Error::TimedOut => http::StatusCode::REQUEST_TIMEOUT,
+                    _ => http::StatusCode::BAD_REQUEST,
+                });
+            }
+            Err(UpstreamError::TimedOut) => {
+                permit.record(false);
+                log::warn!("Upstream {} timed out before responding", upstream.address);
+                if !should_retry(
+                    state,
+                    retry_config,
+                    retryable,
+                    attempt,
+                    retry::Condition::Timeout,
+                ) {
+                    return Err(http::StatusCode::GATEWAY_TIMEOUT);
+                }
+            }
+            Err(error) if !error.is_connection_failure() => {
+                log::warn!("Bad response from upstream {}: {}", upstream.address, error);
+                return Err(http::StatusCode::BAD_GATEWAY);
+            }
+            Err(error) => {
+                permit.record(false);
+                log::error!(
+                    "Error forwarding request to upstream {}: {}",
+                    upstream.address,
+                    error
+                );
+                state
+                    .dead_upstreams
+                    .write()
+                    .insert(upstream.address.clone());
+                if !should_retry(
+                    state,
+                    retry_config,
+                    retryable,
+                    attempt,
+                    retry::Condition::ConnectionError,
+                ) {
+                    return Err(http::StatusCode::BAD_GATEWAY);
+                }
+            }
+        }
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state) {
+        let backoff = retry::backoff(retry_config, attempt as u32);
+        attempt += 1;
+        log::info!(
+            "Retrying {} request in {:?} (attempt {} of {})",
+            request.method(),
+            backoff,
+            attempt,
+            retry_config.max_attempts
+        );
+        delay_for(backoff).await;
+    }
+}
+
+/// Sends a GET request for the active health check path to the given upstream. Returns true if the
+/// upstream answered with a 2xx status, or false if it could not be reached or returned an error.
+async fn check_upstream_health(settings: &Settings, upstream_ip: &str) -> bool {
+    let timeouts = &settings.config.timeouts;
+    let mut upstream_conn = match connect(upstream_ip, timeouts.connect).await {
         Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response);
-            return;
+        Err(err) => {
+            log::warn!("Health check could not connect to {}: {}", upstream_ip, err);
+            return false;
         }
     };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&settings.config.active_health_check_path)
+        .header("Host", upstream_ip)
+        .body(Vec::new())
+        .unwrap();
+    if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+        log::warn!(
+            "Health check failed to send request to {}: {}",
+            upstream_ip,
+            error
+        );
+        return false;
+    }
+    let response = timeout::optional(
+        timeouts.upstream_response,
+        response::read_from_stream(&mut upstream_conn, &mut Vec::new(), request.method(), None),
+    )
+    .await;
+    match response {
+        Ok(Ok((response, _))) if response.status().is_success() => true,
+        Ok(Ok((response, _))) => {
+            log::warn!(
+                "Health check for {} returned {}",
+                upstream_ip,
+                response.status()
+            );
+            false
+        }
+        Ok(Err(error)) => {
+            log::warn!(
+                "Health check failed to read response from {}: {}",
+                upstream_ip,
+                error
+            );
+            false
+        }
+        Err(_) => {
+            log::warn!("Health check for {} timed out", upstream_ip);
+            false
+        }
+    }
+}
+
+/// Periodically sends a request to every upstream, taking upstreams that fail out of rotation and
+/// putting upstreams that have recovered back in. This never returns, so it should be spawned as a
+/// separate task.
+async fn active_health_check(state: &ProxyState) {
+    loop {
+        delay_for(state.settings().config.active_health_check_interval).await;
+        // The config may have been reloaded while we were waiting
+        let settings = state.settings();
+        for upstream in &settings.upstreams {
+            let upstream_ip = &upstream.address;
+            let healthy = check_upstream_health(&settings, upstream_ip).await;
+            let mut dead_upstreams = state.dead_upstreams.write();
+            if healthy {
+                if dead_upstreams.remove(upstream_ip) {
+                    log::info!("Upstream {} is back up", upstream_ip);
+                }
+            } else if dead_upstreams.insert(upstream_ip.clone()) {
+                log::warn!("Upstream {} failed its health check", upstream_ip);
+            }
+        }
+    }
+}
+
+async fn send_response(client_conn: &mut ClientStream, response: &http::Response<Vec<u8>>) {
+    let client_ip = client_conn
+        .tcp_stream()
+        .peer_addr()
+        .unwrap()
+        .ip()
+        .to_string();
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(response)
+    );
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Answers a request with an error status without involving an upstream. `client_keep_alive` says
+/// whether the connection would otherwise stay open. Returns true if the client can go on to send
+/// another request on the same connection.
+#[allow(clippy::too_many_arguments)]
+async fn reject_request(
+    state: &ProxyState,
+    client_conn: &mut ClientStream,
+    client_addr: std::net::IpAddr,
+    received: chrono::DateTime<chrono::Local>,
+    request: &http::Request<Vec<u8>>,
+    request_framing: body::Framing,
+    client_keep_alive: bool,
+    status: http::StatusCode,
+) -> bool {
+    // The rest of the request body is still on its way. Rather than read it just to throw it away,
+    // hang up.
+    let keep_alive = client_keep_alive && body_already_read(request.body(), request_framing);
+    state.metrics.record_error_response(status);
+    let mut response = response::make_http_error(status);
+    hop_by_hop::set_connection(&mut response, request.version(), keep_alive);
+    send_response(client_conn, &response).await;
+    state.log_access(access_log::Record {
+        time: received,
+        client_ip: client_addr,
+        upstream: None,
+        request,
+        status,
+        bytes_in: request.body().len() as u64,
+        bytes_out: Some(response.body().len() as u64),
+        upstream_latency: None,
+        cache: None,
+    });
+    keep_alive
+}
+
+/// Turns the client connection into a tunnel to the upstream, once the upstream has agreed to switch
+/// protocols or accepted a CONNECT request, and passes bytes back and forth until both sides hang
+/// up. Returns the number of bytes sent each way through the tunnel (client to upstream first).
+async fn tunnel_to_upstream(
+    client_conn: &mut ClientStream,
+    client_buffered: &[u8],
+    upstream_response: &mut UpstreamResponse<'_>,
+) -> Result<(u64, u64), std::io::Error> {
+    let response = &upstream_response.response;
+    let upstream_conn = &mut upstream_response.upstream_conn;
+    response::write_head_to_stream(response, client_conn).await?;
+    // Anything either side sent after its head (and, for the client, its body) already belongs to
+    // the tunnel
+    upstream_conn.stream.write_all(client_buffered).await?;
+    client_conn.write_all(&upstream_conn.buffered).await?;
+    client_conn.flush().await?;
+    let (sent, received) = tunnel::splice(client_conn, &mut upstream_conn.stream).await?;
+    Ok((
+        sent + client_buffered.len() as u64,
+        received + upstream_conn.buffered.len() as u64,
+    ))
+}
+
+async fn handle_connection(
+    mut client_conn: ClientStream,
+    state: &ProxyState,
+    mut shutdown: shutdown::Watcher,
+) {
+    let peer_addr = client_conn.tcp_stream().peer_addr().unwrap().ip();
+    log::info!("Connection received from {}", peer_addr);
+    let _client_connection = state.metrics.track_client_connection();
 
     // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
+    // client hangs up or we get an error. Bytes the client sent past the end of one request are the
+    // start of the next.
+    let mut client_buffered = Vec::new();
     loop {
+        let timeouts = state.settings().config.timeouts;
+
+        // Wait for the client to start sending a request, and hang up if it keeps us waiting too
+        // long or we start shutting down in the meantime. A client that pipelines its requests has
+        // already started.
+        let idle_timed_out = client_buffered.is_empty()
+            && tokio::select! {
+                waited = timeout::optional(timeouts.keep_alive_idle, client_conn.wait_for_data()) => {
+                    waited.is_err()
+                }
+                _ = shutdown.shutting_down() => {
+                    log::debug!("Shutting down idle client connection");
+                    let _ = client_conn.shutdown().await;
+                    return;
+                }
+            };
+        if idle_timed_out {
+            log::debug!("Client connection was idle for too long. Shutting down connection");
+            return;
+        }
+
         // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn) {
-            Ok(request) => request,
+        let read = timeout::optional(
+            timeouts.header_read,
+            request::read_from_stream(
+                &mut client_conn,
+                &mut client_buffered,
+                state.max_request_body_size,
+            ),
+        )
+        .await;
+        let (mut request, request_framing) = match read {
+            Ok(Ok(request)) => request,
             // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
+            Ok(Err(request::Error::IncompleteRequest(0))) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
             // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
+            Ok(Err(request::Error::ConnectionError(io_err))) => {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
+            Ok(Err(error)) => {
+                log::debug!("Error parsing request: {}", error);
+                let status = match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::DuplicateContentLength
+                    | request::Error::UnsupportedTransferEncoding => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response);
+                };
+                state.metrics.record_error_response(status);
+                let response = response::make_http_error(status);
+                send_response(&mut client_conn, &response).await;
+                // We don't know where this request's body ends, so we can't find the start of the
+                // next request
+                return;
+            }
+            // The client started a request but is taking too long to finish sending its headers
+            Err(_) => {
+                log::info!("Timed out reading request from {}", peer_addr);
+                let status = http::StatusCode::REQUEST_TIMEOUT;
+                state.metrics.record_error_response(status);
+                send_response(&mut client_conn, &response::make_http_error(status)).await;
+                return;
+            }
+        };
+
+        let received = chrono::Local::now();
+        // Once we are shutting down, this is the last request on the connection. Shutdown may also
+        // start while the request is being handled, so this is checked again as each response is
+        // built.
+        let client_keep_alive = hop_by_hop::keep_alive(request.version(), request.headers())
+            && !shutdown.is_shutting_down();
+
+        // If the request came through proxies we trust, the client is whoever they say it is
+        let client_addr = state.trusted_proxies.client_ip(peer_addr, &request);
+        let client_ip = client_addr.to_string();
+
+        // Handle the whole request with the settings in place when it arrived, even if the config
+        // is reloaded in the meantime
+        let settings = state.settings();
+
+        // Turn the request away if the client has already sent too many requests recently
+        if let Some(rate_limiter) = &settings.rate_limiter {
+            if !rate_limiter.check(client_addr) {
+                log::info!("Rate limit exceeded for {}", client_ip);
+                state.metrics.record_rate_limited();
+                let status = http::StatusCode::TOO_MANY_REQUESTS;
+                if reject_request(
+                    state,
+                    &mut client_conn,
+                    client_addr,
+                    received,
+                    &request,
+                    request_framing,
+                    client_keep_alive,
+                    status,
+                )
+                .await
+                {
+                    continue;
+                }
+                return;
+            }
+        }
+
+        // A CONNECT request asks us to open a tunnel to wherever the upstream sends it, so only pass
+        // it on if we've been told to
+        if request.method() == http::Method::CONNECT && !state.allow_connect {
+            log::info!("Refusing CONNECT request from {}", client_ip);
+            let status = http::StatusCode::NOT_IMPLEMENTED;
+            if reject_request(
+                state,
+                &mut client_conn,
+                client_addr,
+                received,
+                &request,
+                request_framing,
+                client_keep_alive,
+                status,
+            )
+            .await
+            {
                 continue;
             }
+            return;
+        }
+
+        // The cache knows resources by what the client asked for, before routing rewrites the path
+        let cache_resource = settings
+            .cache
+            .as_ref()
+            .map(|_| cache::Resource::new(&request));
+
+        // Work out which pool of upstreams the request is for
+        let pool = match settings.router.route(&mut request) {
+            Ok(Some(pool)) => &settings.pools[pool],
+            result => {
+                let status = match result {
+                    Err(error) => {
+                        log::info!(
+                            "Can't route {}: {}",
+                            request::format_request_line(&request),
+                            error
+                        );
+                        http::StatusCode::BAD_REQUEST
+                    }
+                    _ => {
+                        log::info!("No route for {}", request::format_request_line(&request));
+                        http::StatusCode::NOT_FOUND
+                    }
+                };
+                if reject_request(
+                    state,
+                    &mut client_conn,
+                    client_addr,
+                    received,
+                    &request,
+                    request_framing,
+                    client_keep_alive,
+                    status,
+                )
+                .await
+                {
+                    continue;
+                }
+                return;
+            }
+        };
+
+        // Add X-Forwarded-* and Forwarded headers so that the upstream server knows the client's
+        // IP address, and the scheme and host it asked for. (We're the ones connecting directly to
+        // the upstream server, so without these headers, the upstream server will only know our
+        // IP, not the client's.)
+        state
+            .trusted_proxies
+            .set_forwarding_headers(&mut request, peer_addr, client_conn.scheme());
+
+        // Whether we keep the connection to the client open is between us and the client, and
+        // likewise with the upstream. Pass the request on with the client's HTTP version, so the
+        // upstream doesn't send a response the client can't read, but ask for the upstream
+        // connection to be kept open either way.
+        // An upgrade request is the exception: the upstream needs to see the Upgrade header to agree
+        // to it.
+        let request_version = request.version();
+        let upgrade = hop_by_hop::upgrade(request_version, request.headers());
+        hop_by_hop::strip(request.headers_mut());
+        hop_by_hop::add_via(request.headers_mut(), request_version);
+        if let Some(protocols) = &upgrade {
+            hop_by_hop::set_upgrade(request.headers_mut(), protocols.clone());
+        } else if request_version == http::Version::HTTP_10 {
+            request.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("keep-alive"),
+            );
+        }
+
+        // Answer from the cache if it has a fresh response. If it has a stale one, ask the upstream
+        // whether that is still current rather than fetching the whole response again.
+        let cache_lookup = settings
+            .cache
+            .as_ref()
+            .zip(cache_resource.as_ref())
+            .filter(|_| {
+                upgrade.is_none()
+                    && body_already_read(request.body(), request_framing)
+                    && cache::is_cacheable(&request)
+            });
+        let mut cache_status = None;
+        let mut stale_response = None;
+        if let Some((cache, resource)) = cache_lookup {
+            match cache.lookup(resource, &request) {
+                cache::Lookup::Fresh(response) => {
+                    let mut response = cache::answer_conditional(&request, response);
+                    let client_keep_alive = client_keep_alive && !shutdown.is_shutting_down();
+                    compression::compress_response(
+                        &settings.config.compression,
+                        &request,
+                        &mut response,
+                    );
+                    hop_by_hop::set_connection(&mut response, request.version(), client_keep_alive);
+                    send_response(&mut client_conn, &response).await;
+                    state.log_access(access_log::Record {
+                        time: received,
+                        client_ip: client_addr,
+                        upstream: None,
+                        request: &request,
+                        status: response.status(),
+                        bytes_in: request.body().len() as u64,
+                        bytes_out: Some(response.body().len() as u64),
+                        upstream_latency: None,
+                        cache: Some(cache::Status::Hit),
+                    });
+                    if !client_keep_alive {
+                        return;
+                    }
+                    continue;
+                }
+                cache::Lookup::Stale(response, validators) => {
+                    request.headers_mut().extend(validators);
+                    stale_response = Some(response);
+                }
+                cache::Lookup::Miss => {}
+            }
+            cache_status = Some(cache::Status::Miss);
+        }
+
+        // Forward the request to an upstream server and read the head of its response
+        let started = Instant::now();
+        let mut upstream_response = match forward_to_upstream(
+            state,
+            &settings,
+            pool,
+            &mut client_conn,
+            &mut client_buffered,
+            client_addr,
+            &request,
+            request_framing,
+        )
+        .await
+        {
+            Ok(upstream_response) => upstream_response,
+            Err(status) => {
+                state.metrics.record_error_response(status);
+                let mut response = response::make_http_error(status);
+                hop_by_hop::set_connection(&mut response, request.version(), false);
+                send_response(&mut client_conn, &response).await;
+                state.log_access(access_log::Record {
+                    time: received,
+                    client_ip: client_addr,
+                    upstream: None,
+                    request: &request,
+                    status,
+                    bytes_in: request.body().len() as u64,
+                    bytes_out: Some(response.body().len() as u64),
+                    upstream_latency: None,
+                    cache: cache_status,
+                });
+                return;
+            }
         };
+        let upstream_latency = started.elapsed();
+        let upstream_address = &upstream_response.upstream.address;
         log::info!(
             "{} -> {}: {}",
             client_ip,
-            upstream_ip,
+            upstream_address,
             request::format_request_line(&request)
         );
+        state.metrics.record_upstream_response(
+            upstream_address,
+            upstream_response.response.status(),
+            upstream_latency,
+        );
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // If the upstream says the stale cached response is still current, send that
+        if let (Some((cache, resource)), Some(stale_response)) = (
+            cache_lookup,
+            stale_response
+                .filter(|_| upstream_response.response.status() == http::StatusCode::NOT_MODIFIED),
+        ) {
+            let upstream_reusable = pool::can_reuse(
+                &request,
+                &upstream_response.response,
+                upstream_response.framing,
+            );
+            hop_by_hop::strip(upstream_response.response.headers_mut());
+            let mut response = cache::freshen(stale_response, &upstream_response.response);
+            if cache.is_storable(
+                &response,
+                body::Framing::Length(response.body().len() as u64),
+            ) {
+                cache.store(resource, &request, &response);
+            }
+            compression::compress_response(&settings.config.compression, &request, &mut response);
+            let client_keep_alive = client_keep_alive && !shutdown.is_shutting_down();
+            hop_by_hop::set_connection(&mut response, request.version(), client_keep_alive);
+            send_response(&mut client_conn, &response).await;
+            state.log_access(access_log::Record {
+                time: received,
+                client_ip: client_addr,
+                upstream: Some(&upstream_response.upstream.address),
+                request: &request,
+                status: response.status(),
+                bytes_in: upstream_response.request_body_size,
+                bytes_out: Some(response.body().len() as u64),
+                upstream_latency: Some(upstream_latency),
+                cache: Some(cache::Status::Revalidated),
+            });
+            if upstream_reusable && upstream_response.upstream_conn.buffered.is_empty() {
+                state.pool.checkin(
+                    &upstream_response.upstream.address,
+                    upstream_response.upstream_conn.stream,
+                );
+            }
+            if !client_keep_alive {
+                return;
+            }
+            continue;
+        }
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn) {
-            log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response);
+        // If the upstream agreed to switch protocols or accepted a CONNECT request, the connection
+        // stops being HTTP, and all that's left to do is pass bytes through until it closes
+        let status = upstream_response.response.status();
+        let switching = if request.method() == http::Method::CONNECT {
+            status.is_success()
+        } else {
+            upgrade.is_some() && status == http::StatusCode::SWITCHING_PROTOCOLS
+        };
+        if switching {
+            let response = &mut upstream_response.response;
+            let protocols = response.headers().get(http::header::UPGRADE).cloned();
+            hop_by_hop::strip(response.headers_mut());
+            let response_version = response.version();
+            hop_by_hop::add_via(response.headers_mut(), response_version);
+            if let Some(protocols) = protocols {
+                hop_by_hop::set_upgrade(response.headers_mut(), protocols);
+            }
+            log::info!(
+                "{} <- {} (tunneling)",
+                client_ip,
+                response::format_response_line(response)
+            );
+            let tunneled =
+                tunnel_to_upstream(&mut client_conn, &client_buffered, &mut upstream_response)
+                    .await;
+            state.log_access(access_log::Record {
+                time: received,
+                client_ip: client_addr,
+                upstream: Some(&upstream_response.upstream.address),
+                request: &request,
+                status,
+                bytes_in: tunneled.as_ref().map_or(0, |(sent, _)| *sent),
+                bytes_out: tunneled.as_ref().ok().map(|(_, received)| *received),
+                upstream_latency: Some(upstream_latency),
+                cache: None,
+            });
+            match tunneled {
+                Ok(_) => log::debug!("Tunnel closed"),
+                Err(error) => log::info!(
+                    "Tunnel to upstream {} failed: {}",
+                    upstream_response.upstream.address,
+                    error
+                ),
+            }
             return;
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()) {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response);
-                return;
-            }
+        // Work out whether the upstream connection can be reused once the response has been
+        // forwarded, before its Connection header is stripped
+        let upstream_reusable = pool::can_reuse(
+            &request,
+            &upstream_response.response,
+            upstream_response.framing,
+        );
+        let response_framing = upstream_response.framing;
+        let response = &mut upstream_response.response;
+        hop_by_hop::strip(response.headers_mut());
+        let response_version = response.version();
+        hop_by_hop::add_via(response.headers_mut(), response_version);
+        // Keep a copy of the response for answering later requests, if it can be reused
+        let cached_headers = cache_lookup
+            .filter(|(cache, _)| cache.is_storable(response, response_framing))
+            .map(|_| response.headers().clone());
+        let encoding = compression::start(
+            &settings.config.compression,
+            &request,
+            response,
+            response_framing,
+        );
+        // If the response body ends when the upstream closes the connection, the client can only
+        // tell where it ends by us closing ours, unless we are compressing it (and so sending it
+        // chunked). If we started shutting down while the upstream was busy, this is the last
+        // response on the connection.
+        let client_keep_alive = client_keep_alive
+            && (response_framing != body::Framing::UntilClose || encoding.is_some())
+            && !shutdown.is_shutting_down();
+        hop_by_hop::set_connection(response, request.version(), client_keep_alive);
+
+        // Forward the response to the client, streaming its body from the upstream
+        let response = &upstream_response.response;
+        log::info!(
+            "{} <- {}",
+            client_ip,
+            response::format_response_line(response)
+        );
+        if let Err(error) = response::write_head_to_stream(response, &mut client_conn).await {
+            log::warn!("Failed to send response to client: {}", error);
+            return;
+        }
+        let mut encoder = compression::Encoder::new(&mut client_conn, encoding);
+        let mut recorder = cache::Recorder::new(&mut encoder, cached_headers.is_some());
+        let upstream_conn = &mut upstream_response.upstream_conn;
+        let forwarded = if encoding.is_some() {
+            // The encoder chunks the compressed body itself, so it needs the body without the
+            // upstream's chunk framing
+            body::forward_decoded(
+                &mut upstream_conn.stream,
+                response.body(),
+                upstream_response.framing,
+                &mut recorder,
+                state.max_response_body_size,
+                settings.config.timeouts.body_read,
+                &mut upstream_conn.buffered,
+            )
+            .await
+        } else {
+            body::forward(
+                &mut upstream_conn.stream,
+                response.body(),
+                upstream_response.framing,
+                &mut recorder,
+                state.max_response_body_size,
+                settings.config.timeouts.body_read,
+                &mut upstream_conn.buffered,
+            )
+            .await
+        };
+        let recorded_body = recorder.into_recorded();
+        // Send the end of the compressed body, and count the bytes the client was actually sent
+        let forwarded = match forwarded {
+            Ok(size) => encoder
+                .finish()
+                .await
+                .map(|compressed_size| compressed_size.unwrap_or(size))
+                .map_err(body::Error::Write),
+            Err(error) => Err(error),
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response);
+        state.log_access(access_log::Record {
+            time: received,
+            client_ip: client_addr,
+            upstream: Some(&upstream_response.upstream.address),
+            request: &request,
+            status: response.status(),
+            bytes_in: upstream_response.request_body_size,
+            bytes_out: forwarded.as_ref().ok().copied(),
+            upstream_latency: Some(upstream_latency),
+            cache: cache_status,
+        });
+        if let Err(error) = forwarded {
+            // The client has already been sent the response head, so all we can do is hang up
+            log::warn!(
+                "Error forwarding response body from upstream {}: {}",
+                upstream_response.upstream.address,
+                error
+            );
+            return;
+        }
         log::debug!("Forwarded response to client");
+        if let (Some((cache, resource)), Some(headers), Some(body)) =
+            (cache_lookup, cached_headers, recorded_body)
+        {
+            let mut cached = http::Response::new(body);
+            *cached.status_mut() = response.status();
+            *cached.headers_mut() = headers;
+            cache.store(resource, &request, &cached);
+        }
+        // A successful unsafe request may have changed the resource, so stop handing out old copies
+        // of it
+        if let (Some(cache), Some(resource)) = (&settings.cache, &cache_resource) {
+            let status = response.status();
+            if !request.method().is_safe() && (status.is_success() || status.is_redirection()) {
+                cache.invalidate(resource);
+            }
+        }
+
+        // Put the upstream connection back in the pool so another request can use it, unless the
+        // upstream has sent more than the response we asked for
+        if upstream_reusable && upstream_response.upstream_conn.buffered.is_empty() {
+            state.pool.checkin(
+                &upstream_response.upstream.address,
+                upstream_response.upstream_conn.stream,
+            );
+        }
+        if !client_keep_alive {
+            log::debug!("Closing client connection after response");
+            return;
+        }
     }
 }
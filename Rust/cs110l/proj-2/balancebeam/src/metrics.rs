@@ -0,0 +1,602 @@
+use crate::circuit_breaker;
+use crate::config::Timeouts;
+use crate::{body, request, response, timeout};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+
+/// Upper bounds (in seconds) of the buckets in the upstream latency histograms. These are the
+/// Prometheus client libraries' default buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Distribution of observed durations, in the shape of a Prometheus histogram
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations that fell into each bucket of LATENCY_BUCKETS (not cumulative), plus
+    /// one for observations bigger than the last bucket
+    bucket_counts: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.bucket_counts[bucket] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.bucket_counts) {
+            cumulative += count;
+            writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )
+        .unwrap();
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(out, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+/// Counters kept for each upstream
+#[derive(Default)]
+struct UpstreamCounters {
+    /// Requests sent to the upstream, including ones that failed and were retried elsewhere
+    requests: u64,
+    /// Failed attempts to connect to the upstream
+    connect_failures: u64,
+    /// Responses received from the upstream, by status code
+    responses: BTreeMap<u16, u64>,
+    /// Time from picking the upstream to receiving the head of its response
+    latency: Histogram,
+}
+
+/// What balancebeam currently knows about an upstream, as opposed to what has happened to it
+pub struct UpstreamStatus<'a> {
+    pub address: &'a str,
+    pub active_connections: usize,
+    pub healthy: bool,
+    pub circuit_state: circuit_breaker::State,
+    /// Number of times the upstream's circuit breaker has opened
+    pub circuit_opened: u64,
+}
+
+/// Counters describing the traffic balancebeam has handled, for reporting to Prometheus
+#[derive(Default)]
+pub struct Metrics {
+    upstreams: Mutex<HashMap<String, UpstreamCounters>>,
+    /// Client connections accepted so far
+    client_connections: AtomicU64,
+    /// Client connections currently open
+    open_client_connections: AtomicUsize,
+    /// Requests turned away with 429 Too Many Requests
+    rate_limited: AtomicU64,
+    /// Requests sent to an upstream again after an attempt failed
+    retries: AtomicU64,
+    /// Retries the retry policy allowed but the retry budget didn't
+    retries_budget_exhausted: AtomicU64,
+    /// Error responses generated by balancebeam itself (as opposed to passed on from an
+    /// upstream), by status code
+    error_responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Records that a client connection was accepted. The connection is counted as open until the
+    /// returned guard is dropped.
+    pub fn track_client_connection(&self) -> ClientConnection<'_> {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+        self.open_client_connections.fetch_add(1, Ordering::Relaxed);
+        ClientConnection { metrics: self }
+    }
+
+    pub fn record_upstream_request(&self, upstream: &str) {
+        self.upstreams
+            .lock()
+            .entry(upstream.to_string())
+            .or_default()
+            .requests += 1;
+    }
+
+    pub fn record_connect_failure(&self, upstream: &str) {
+        self.upstreams
+            .lock()
+            .entry(upstream.to_string())
+            .or_default()
+            .connect_failures += 1;
+    }
+
+    pub fn record_upstream_response(
+        &self,
+        upstream: &str,
+        status: http::StatusCode,
+        latency: Duration,
+    ) {
+        let mut upstreams = self.upstreams.lock();
+        let counters = upstreams.entry(upstream.to_string()).or_default();
+        *counters.responses.entry(status.as_u16()).or_default() += 1;
+        counters.latency.observe(latency);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry_budget_exhausted(&self) {
+        self.retries_budget_exhausted
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error_response(&self, status: http::StatusCode) {
+        *self
+            .error_responses
+            .lock()
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format. `upstreams` describes the
+    /// upstreams that are currently configured.
+    pub fn render(&self, upstreams: &[UpstreamStatus]) -> String {
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_client_connections_total Client connections accepted."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_client_connections_total counter").unwrap();
+        writeln!(
+            out,
+            "balancebeam_client_connections_total {}",
+            self.client_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_open_client_connections Client connections currently open."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_open_client_connections gauge").unwrap();
+        writeln!(
+            out,
+            "balancebeam_open_client_connections {}",
+            self.open_client_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_rate_limited_total Requests rejected by the per-IP rate limit."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_rate_limited_total counter").unwrap();
+        writeln!(
+            out,
+            "balancebeam_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_retries_total Requests sent to an upstream again after an attempt \
+            failed."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_retries_total counter").unwrap();
+        writeln!(
+            out,
+            "balancebeam_retries_total {}",
+            self.retries.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_retry_budget_exhausted_total Retries skipped because the retry \
+            budget was used up."
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE balancebeam_retry_budget_exhausted_total counter"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "balancebeam_retry_budget_exhausted_total {}",
+            self.retries_budget_exhausted.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_error_responses_total Error responses sent by balancebeam itself."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_error_responses_total counter").unwrap();
+        for (code, count) in self.error_responses.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_error_responses_total{{code=\"{}\"}} {}",
+                code, count
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_up Whether the upstream is in rotation (1) or marked dead \
+            (0)."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_up gauge").unwrap();
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_up{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream.address),
+                upstream.healthy as u8
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_active_connections Requests currently being proxied to \
+            the upstream."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_active_connections gauge").unwrap();
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_active_connections{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream.address),
+                upstream.active_connections
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_circuit_state Whether the upstream's circuit breaker is in \
+            the given state (1) or not (0)."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_circuit_state gauge").unwrap();
+        for upstream in upstreams {
+            for state in &circuit_breaker::State::ALL {
+                writeln!(
+                    out,
+                    "balancebeam_upstream_circuit_state{{upstream=\"{}\",state=\"{}\"}} {}",
+                    escape_label_value(upstream.address),
+                    state.as_str(),
+                    (upstream.circuit_state == *state) as u8
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_circuit_opened_total Times the upstream's circuit breaker \
+            has opened."
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE balancebeam_upstream_circuit_opened_total counter"
+        )
+        .unwrap();
+        for upstream in upstreams {
+            writeln!(
+                out,
+                "balancebeam_upstream_circuit_opened_total{{upstream=\"{}\"}} {}",
+                escape_label_value(upstream.address),
+                upstream.circuit_opened
+            )
+            .unwrap();
+        }
+
+        // Sort the upstreams so that the output is stable from one scrape to the next
+        let counters = self.upstreams.lock();
+        let mut addresses: Vec<&String> = counters.keys().collect();
+        addresses.sort();
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_requests_total Requests sent to the upstream."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_requests_total counter").unwrap();
+        for address in &addresses {
+            writeln!(
+                out,
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}} {}",
+                escape_label_value(address),
+                counters[*address].requests
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_connect_failures_total Failed attempts to connect to the \
+            upstream."
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE balancebeam_upstream_connect_failures_total counter"
+        )
+        .unwrap();
+        for address in &addresses {
+            writeln!(
+                out,
+                "balancebeam_upstream_connect_failures_total{{upstream=\"{}\"}} {}",
+                escape_label_value(address),
+                counters[*address].connect_failures
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_responses_total Responses received from the upstream, by \
+            status code."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE balancebeam_upstream_responses_total counter").unwrap();
+        for address in &addresses {
+            for (code, count) in &counters[*address].responses {
+                writeln!(
+                    out,
+                    "balancebeam_upstream_responses_total{{upstream=\"{}\",code=\"{}\"}} {}",
+                    escape_label_value(address),
+                    code,
+                    count
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP balancebeam_upstream_response_seconds Time taken by the upstream to start \
+            responding."
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# TYPE balancebeam_upstream_response_seconds histogram"
+        )
+        .unwrap();
+        for address in &addresses {
+            counters[*address].latency.render(
+                &mut out,
+                "balancebeam_upstream_response_seconds",
+                &format!("upstream=\"{}\"", escape_label_value(address)),
+            );
+        }
+
+        out
+    }
+}
+
+/// Keeps a client connection counted as open for as long as it is alive
+pub struct ClientConnection<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ClientConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .open_client_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes a string for use as a label value in the Prometheus text format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the output of `render` to anyone who sends GET /metrics to `listener`. `timeouts` returns
+/// the client timeouts currently configured, which apply to scrapers too. This never returns, so it
+/// should be spawned as a separate task.
+pub async fn serve<T, F>(mut listener: TcpListener, timeouts: T, render: F)
+where
+    T: Fn() -> Timeouts + Send + Sync + 'static,
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let timeouts = Arc::new(timeouts);
+    let render = Arc::new(render);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let timeouts = timeouts.clone();
+            let render = render.clone();
+            tokio::spawn(async move {
+                handle_scrape(stream, timeouts.as_ref(), render.as_ref()).await;
+            });
+        }
+    }
+}
+
+/// Answers requests on a connection to the metrics listener until the scraper hangs up or stops
+/// sending
+async fn handle_scrape<T, F>(mut stream: TcpStream, timeouts: &T, render: &F)
+where
+    T: Fn() -> Timeouts,
+    F: Fn() -> String,
+{
+    // Bytes read past the end of one request, which are the start of the next
+    let mut buffered = Vec::new();
+    loop {
+        let timeouts = timeouts();
+
+        // Hang up on scrapers that keep the connection open without starting another request
+        if buffered.is_empty()
+            && timeout::optional(timeouts.keep_alive_idle, stream.peek(&mut [0; 1]))
+                .await
+                .is_err()
+        {
+            log::debug!("Metrics connection was idle for too long. Shutting down connection");
+            return;
+        }
+
+        // Scrapers have no reason to send a body, so don't accept one
+        let read = timeout::optional(
+            timeouts.header_read,
+            request::read_from_stream(&mut stream, &mut buffered, Some(0)),
+        )
+        .await;
+        let (request, framing) = match read {
+            Ok(Ok(request)) => request,
+            Ok(Err(_)) => return,
+            // The scraper started a request but is taking too long to finish sending its headers
+            Err(_) => {
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                let _ = response::write_to_stream(&response, &mut stream).await;
+                return;
+            }
+        };
+        let response =
+            if request.method() == http::Method::GET && request.uri().path() == "/metrics" {
+                let body = render().into_bytes();
+                http::Response::builder()
+                    .status(http::StatusCode::OK)
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .header("Content-Length", body.len().to_string())
+                    .body(body)
+                    .unwrap()
+            } else {
+                response::make_http_error(http::StatusCode::NOT_FOUND)
+            };
+        if response::write_to_stream(&response, &mut stream)
+            .await
+            .is_err()
+            || framing != body::Framing::Empty
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_histograms() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(20));
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "upstream=\"a\"");
+        assert!(out.contains("latency_bucket{upstream=\"a\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("latency_bucket{upstream=\"a\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("latency_bucket{upstream=\"a\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("latency_bucket{upstream=\"a\",le=\"10\"} 2\n"));
+        assert!(out.contains("latency_bucket{upstream=\"a\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_count{upstream=\"a\"} 3\n"));
+    }
+
+    #[test]
+    fn renders_upstream_counters() {
+        let metrics = Metrics::new();
+        metrics.record_upstream_request("10.0.0.1:80");
+        metrics.record_upstream_request("10.0.0.1:80");
+        metrics.record_upstream_response(
+            "10.0.0.1:80",
+            http::StatusCode::OK,
+            Duration::from_millis(1),
+        );
+        metrics.record_upstream_response(
+            "10.0.0.1:80",
+            http::StatusCode::NOT_FOUND,
+            Duration::from_millis(1),
+        );
+        metrics.record_connect_failure("10.0.0.2:80");
+        metrics.record_rate_limited();
+        metrics.record_retry();
+        metrics.record_retry();
+        metrics.record_retry_budget_exhausted();
+        {
+            let _connection = metrics.track_client_connection();
+            let out = metrics.render(&[UpstreamStatus {
+                address: "10.0.0.1:80",
+                active_connections: 2,
+                healthy: true,
+                circuit_state: circuit_breaker::State::Open,
+                circuit_opened: 3,
+            }]);
+            assert!(out.contains("balancebeam_open_client_connections 1\n"));
+            assert!(out.contains("balancebeam_upstream_up{upstream=\"10.0.0.1:80\"} 1\n"));
+            assert!(out
+                .contains("balancebeam_upstream_active_connections{upstream=\"10.0.0.1:80\"} 2\n"));
+            assert!(out.contains(
+                "balancebeam_upstream_circuit_state{upstream=\"10.0.0.1:80\",state=\"open\"} 1\n"
+            ));
+            assert!(out.contains(
+                "balancebeam_upstream_circuit_state{upstream=\"10.0.0.1:80\",state=\"closed\"} 0\n"
+            ));
+            assert!(out.contains(
+                "balancebeam_upstream_circuit_opened_total{upstream=\"10.0.0.1:80\"} 3\n"
+            ));
+        }
+        let out = metrics.render(&[]);
+        assert!(out.contains("balancebeam_client_connections_total 1\n"));
+        assert!(out.contains("balancebeam_open_client_connections 0\n"));
+        assert!(out.contains("balancebeam_rate_limited_total 1\n"));
+        assert!(out.contains("balancebeam_retries_total 2\n"));
+        assert!(out.contains("balancebeam_retry_budget_exhausted_total 1\n"));
+        assert!(out.contains("balancebeam_upstream_requests_total{upstream=\"10.0.0.1:80\"} 2\n"));
+        assert!(out.contains(
+            "balancebeam_upstream_responses_total{upstream=\"10.0.0.1:80\",code=\"404\"} 1\n"
+        ));
+        assert!(out
+            .contains("balancebeam_upstream_connect_failures_total{upstream=\"10.0.0.2:80\"} 1\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{body, hop_by_hop};
+use futures::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Settings for the upstream connection pool
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Maximum number of idle connections kept open to each upstream. 0 disables pooling.
+    pub max_idle_per_upstream: usize,
+    /// Idle connections older than this are closed instead of being reused
+    pub idle_timeout: Duration,
+}
+
+/// A connection sitting in the pool waiting to be reused
+struct IdleConnection {
+    stream: TcpStream,
+    idle_since: Instant,
+}
+
+impl IdleConnection {
+    /// Returns true if the connection has been idle too long, or if the upstream has closed it (or
+    /// sent us data we weren't expecting, which means we can't trust it either)
+    fn is_stale(&mut self, idle_timeout: Duration) -> bool {
+        if self.idle_since.elapsed() >= idle_timeout {
+            return true;
+        }
+        // An idle keep-alive connection should have nothing to read. If peeking would block, the
+        // connection is still open and quiet; anything else (EOF, data or an error) means it's no
+        // good anymore.
+        let mut buf = [0_u8; 1];
+        self.stream.peek(&mut buf).now_or_never().is_some()
+    }
+}
+
+/// Keeps idle keep-alive connections to each upstream so that they can be reused for later
+/// requests instead of paying for a new TCP handshake every time
+pub struct ConnectionPool {
+    /// Can be changed while the pool is in use when the config file is reloaded
+    config: RwLock<PoolConfig>,
+    /// Idle connections for each upstream address, most recently used last
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> ConnectionPool {
+        ConnectionPool {
+            config: RwLock::new(config),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to the given upstream out of the pool, if there is a usable one.
+    /// Stale connections found along the way are closed.
+    pub fn checkout(&self, address: &str) -> Option<TcpStream> {
+        let idle_timeout = self.config.read().idle_timeout;
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(address)?;
+        while let Some(mut connection) = connections.pop() {
+            if connection.is_stale(idle_timeout) {
+                log::debug!("Closing stale pooled connection to {}", address);
+                continue;
+            }
+            log::debug!("Reusing pooled connection to {}", address);
+            return Some(connection.stream);
+        }
+        None
+    }
+
+    /// Returns a connection to the pool once a response has been read from it. The connection is
+    /// closed instead if the pool for that upstream is already full.
+    pub fn checkin(&self, address: &str, stream: TcpStream) {
+        let max_idle_per_upstream = self.config.read().max_idle_per_upstream;
+        if max_idle_per_upstream == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() < max_idle_per_upstream {
+            connections.push(IdleConnection {
+                stream,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes every pooled connection that has expired or been closed by its upstream
+    pub fn evict_stale(&self) {
+        let idle_timeout = self.config.read().idle_timeout;
+        let mut idle = self.idle.lock();
+        for (address, connections) in idle.iter_mut() {
+            let before = connections.len();
+            let mut kept = Vec::with_capacity(before);
+            for mut connection in connections.drain(..) {
+                if !connection.is_stale(idle_timeout) {
+                    kept.push(connection);
+                }
+            }
+            *connections = kept;
+            if connections.len() < before {
+                log::debug!(
+                    "Evicted {} stale pooled connections to {}",
+                    before - connections.len(),
+                    address
+                );
+            }
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+
+    /// Changes how long connections may sit idle. Connections already in the pool are held to the
+    /// new timeout.
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        self.config.write().idle_timeout = idle_timeout;
+    }
+
+    /// Closes every pooled connection to an upstream that isn't in `addresses`, e.g. because it
+    /// was removed from the config
+    pub fn retain_upstreams(&self, addresses: &[&str]) {
+        self.idle
+            .lock()
+            .retain(|address, _| addresses.contains(&address.as_str()));
+    }
+}
+
+/// Returns true if the upstream connection a response was read from can be used for another
+/// request once the response body has been forwarded. That's only the case if both the request we
+/// sent and the response (going by their HTTP versions and Connection headers) leave the connection
+/// open, and if the response didn't mark the end of its body by closing the connection.
+pub fn can_reuse(
+    request: &http::Request<Vec<u8>>,
+    response: &http::Response<Vec<u8>>,
+    response_framing: body::Framing,
+) -> bool {
+    hop_by_hop::keep_alive(request.version(), request.headers())
+        && hop_by_hop::keep_alive(response.version(), response.headers())
+        && response_framing != body::Framing::UntilClose
+}
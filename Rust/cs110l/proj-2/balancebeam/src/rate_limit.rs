@@ -0,0 +1,314 @@
+use crate::clock::{Clock, SystemClock};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Length of the window that `max_requests_per_minute` applies to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The algorithm used to decide whether a client has gone over its request limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// Count requests in consecutive one-minute windows, resetting the count when a new window
+    /// starts. Cheap, but lets a client send up to twice the limit around a window boundary.
+    FixedWindow,
+    /// Remember the time of every request in the last minute. Exact, but uses memory proportional
+    /// to the limit for every client.
+    SlidingWindowLog,
+    /// Give each client a bucket of `max_requests_per_minute` tokens that refills continuously.
+    /// Allows short bursts while enforcing the average rate.
+    TokenBucket,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-window" => Ok(Algorithm::FixedWindow),
+            "sliding-window-log" => Ok(Algorithm::SlidingWindowLog),
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            _ => Err(format!(
+                "unknown rate limiting algorithm {:?} (expected fixed-window, \
+                sliding-window-log or token-bucket)",
+                s
+            )),
+        }
+    }
+}
+
+/// What we remember about each client, depending on the algorithm in use
+enum ClientState {
+    FixedWindow { window_start: Instant, count: usize },
+    SlidingWindowLog(VecDeque<Instant>),
+    TokenBucket { tokens: f64, last_refill: Instant },
+}
+
+impl ClientState {
+    /// Returns true if the client hasn't made any requests that still count against its limit,
+    /// so that forgetting about it would make no difference
+    fn is_idle(&self, now: Instant, limit: usize) -> bool {
+        match self {
+            ClientState::FixedWindow { window_start, .. } => {
+                now.duration_since(*window_start) >= WINDOW
+            }
+            ClientState::SlidingWindowLog(log) => match log.back() {
+                Some(newest) => now.duration_since(*newest) >= WINDOW,
+                None => true,
+            },
+            ClientState::TokenBucket {
+                tokens,
+                last_refill,
+            } => {
+                let elapsed = now.duration_since(*last_refill).as_secs_f64();
+                let refill_rate = limit as f64 / WINDOW.as_secs_f64();
+                tokens + elapsed * refill_rate >= limit as f64
+            }
+        }
+    }
+}
+
+/// The clients we are tracking
+struct Clients {
+    states: HashMap<IpAddr, ClientState>,
+    /// When idle clients were last removed from `states`
+    last_swept: Instant,
+}
+
+/// Keeps track of how many requests each client IP has made recently, and decides whether new
+/// requests should be let through.
+pub struct RateLimiter {
+    algorithm: Algorithm,
+    max_requests_per_minute: usize,
+    clock: Arc<dyn Clock>,
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(algorithm: Algorithm, max_requests_per_minute: usize) -> RateLimiter {
+        RateLimiter::with_clock(algorithm, max_requests_per_minute, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        algorithm: Algorithm,
+        max_requests_per_minute: usize,
+        clock: Arc<dyn Clock>,
+    ) -> RateLimiter {
+        let now = clock.now();
+        RateLimiter {
+            algorithm,
+            max_requests_per_minute,
+            clock,
+            clients: Mutex::new(Clients {
+                states: HashMap::new(),
+                last_swept: now,
+            }),
+        }
+    }
+
+    /// Records a request from the given client. Returns true if the request is within the limit
+    /// and should be served, or false if the client should be sent 429 Too Many Requests.
+    pub fn check(&self, client_ip: IpAddr) -> bool {
+        let now = self.clock.now();
+        let limit = self.max_requests_per_minute;
+        let mut clients = self.clients.lock();
+        // Every so often, forget about clients that have gone quiet, so that every IP address we
+        // have ever seen doesn't stay in memory
+        if now.duration_since(clients.last_swept) >= WINDOW {
+            clients
+                .states
+                .retain(|_, client| !client.is_idle(now, limit));
+            clients.last_swept = now;
+        }
+        let client = clients
+            .states
+            .entry(client_ip)
+            .or_insert_with(|| match self.algorithm {
+                Algorithm::FixedWindow => ClientState::FixedWindow {
+                    window_start: now,
+                    count: 0,
+                },
+                Algorithm::SlidingWindowLog => ClientState::SlidingWindowLog(VecDeque::new()),
+                Algorithm::TokenBucket => ClientState::TokenBucket {
+                    tokens: limit as f64,
+                    last_refill: now,
+                },
+            });
+
+        match client {
+            ClientState::FixedWindow {
+                window_start,
+                count,
+            } => {
+                if now.duration_since(*window_start) >= WINDOW {
+                    *window_start = now;
+                    *count = 0;
+                }
+                if *count < limit {
+                    *count += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+            ClientState::SlidingWindowLog(log) => {
+                // Forget about requests that have fallen out of the window
+                while let Some(oldest) = log.front() {
+                    if now.duration_since(*oldest) >= WINDOW {
+                        log.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                if log.len() < limit {
+                    log.push_back(now);
+                    true
+                } else {
+                    false
+                }
+            }
+            ClientState::TokenBucket {
+                tokens,
+                last_refill,
+            } => {
+                let elapsed = now.duration_since(*last_refill).as_secs_f64();
+                let refill_rate = limit as f64 / WINDOW.as_secs_f64();
+                *tokens = (*tokens + elapsed * refill_rate).min(limit as f64);
+                *last_refill = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    fn client(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    /// Sends `n` requests from `ip` and returns how many of them were allowed
+    fn send(limiter: &RateLimiter, ip: IpAddr, n: usize) -> usize {
+        (0..n).filter(|_| limiter.check(ip)).count()
+    }
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!("fixed-window".parse(), Ok(Algorithm::FixedWindow));
+        assert_eq!(
+            "sliding-window-log".parse(),
+            Ok(Algorithm::SlidingWindowLog)
+        );
+        assert_eq!("token-bucket".parse(), Ok(Algorithm::TokenBucket));
+        assert!("leaky-bucket".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn fixed_window_resets_each_window() {
+        let clock = TestClock::new();
+        let limiter = RateLimiter::with_clock(Algorithm::FixedWindow, 5, clock.clone());
+        assert_eq!(send(&limiter, client(1), 8), 5);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(!limiter.check(client(1)));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(send(&limiter, client(1), 8), 5);
+    }
+
+    #[test]
+    fn fixed_window_allows_burst_across_boundary() {
+        let clock = TestClock::new();
+        let limiter = RateLimiter::with_clock(Algorithm::FixedWindow, 5, clock.clone());
+        assert!(limiter.check(client(1)));
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(send(&limiter, client(1), 4), 4);
+        clock.advance(Duration::from_secs(1));
+        // A new window has started, so the client gets a full allowance right away
+        assert_eq!(send(&limiter, client(1), 5), 5);
+    }
+
+    #[test]
+    fn sliding_window_log_forgets_old_requests() {
+        let clock = TestClock::new();
+        let limiter = RateLimiter::with_clock(Algorithm::SlidingWindowLog, 5, clock.clone());
+        assert!(limiter.check(client(1)));
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(send(&limiter, client(1), 4), 4);
+        clock.advance(Duration::from_secs(1));
+        // Only the first request has left the window
+        assert_eq!(send(&limiter, client(1), 5), 1);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(send(&limiter, client(1), 5), 4);
+    }
+
+    #[test]
+    fn token_bucket_refills_gradually() {
+        let clock = TestClock::new();
+        let limiter = RateLimiter::with_clock(Algorithm::TokenBucket, 60, clock.clone());
+        assert_eq!(send(&limiter, client(1), 100), 60);
+
+        // One token is added every second
+        clock.advance(Duration::from_millis(500));
+        assert!(!limiter.check(client(1)));
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.check(client(1)));
+        assert!(!limiter.check(client(1)));
+
+        // The bucket never holds more than the limit
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(send(&limiter, client(1), 100), 60);
+    }
+
+    #[test]
+    fn clients_are_limited_independently() {
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindowLog,
+            Algorithm::TokenBucket,
+        ]
+        .iter()
+        {
+            let limiter = RateLimiter::with_clock(*algorithm, 3, TestClock::new());
+            assert_eq!(send(&limiter, client(1), 5), 3, "{:?}", algorithm);
+            assert_eq!(send(&limiter, client(2), 5), 3, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn forgets_idle_clients() {
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::SlidingWindowLog,
+            Algorithm::TokenBucket,
+        ]
+        .iter()
+        {
+            let clock = TestClock::new();
+            let limiter = RateLimiter::with_clock(*algorithm, 3, clock.clone());
+            send(&limiter, client(1), 5);
+            clock.advance(Duration::from_secs(30));
+            send(&limiter, client(2), 3);
+            assert_eq!(limiter.clients.lock().states.len(), 2, "{:?}", algorithm);
+
+            // Client 1 has been quiet for a whole window, but client 2 still has requests that
+            // count against its limit
+            clock.advance(Duration::from_secs(30));
+            send(&limiter, client(3), 1);
+            let clients = limiter.clients.lock();
+            assert!(!clients.states.contains_key(&client(1)), "{:?}", algorithm);
+            assert!(clients.states.contains_key(&client(2)), "{:?}", algorithm);
+            assert_eq!(clients.states.len(), 2, "{:?}", algorithm);
+        }
+    }
+}
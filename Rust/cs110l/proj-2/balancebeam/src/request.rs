@@ -1,12 +1,12 @@
-use std::cmp::min;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use crate::{body, chunked};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -15,34 +15,66 @@ pub enum Error {
     MalformedRequest(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The Content-Length header is present more than once, so there is no telling which length
+    /// to believe
+    DuplicateContentLength,
+    /// The Content-Length header says the request body is bigger than the maximum request body size
     RequestBodyTooLarge,
+    /// The Transfer-Encoding header is present, but chunked is not the final coding, so there is
+    /// no way to tell where the request body ends
+    UnsupportedTransferEncoding,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
-/// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(bytes_read) => write!(
+                f,
+                "connection closed after {} bytes of the request",
+                bytes_read
+            ),
+            Error::MalformedRequest(error) => write!(f, "malformed request: {}", error),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::DuplicateContentLength => write!(f, "more than one Content-Length"),
+            Error::RequestBodyTooLarge => {
+                write!(f, "request body is larger than the maximum body size")
+            }
+            Error::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            Error::ConnectionError(error) => write!(f, "failed to read request: {}", error),
+        }
+    }
+}
+
+/// Extracts the Content-Length header value from the provided request. Returns Ok(Some(u64)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
-fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
+/// Err(Error) if Content-Length is present but invalid. More than one Content-Length header is an
+/// error even if they agree, since a peer that believes a different one than we do would see a
+/// different message (RFC 7230 section 3.3.3).
+fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<u64>, Error> {
     // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
+    let mut header_values = request
+        .headers()
+        .get_all(http::header::CONTENT_LENGTH)
+        .iter();
+    let header_value = match header_values.next() {
+        Some(header_value) => header_value,
         // If it doesn't exist, return None
-        Ok(None)
+        None => return Ok(None),
+    };
+    if header_values.next().is_some() {
+        return Err(Error::DuplicateContentLength);
     }
+    // If it exists, parse it as a u64 (or return InvalidContentLength if it can't be parsed as
+    // such). A comma-separated list of lengths fails to parse, too.
+    Ok(Some(
+        header_value
+            .to_str()
+            .or(Err(Error::InvalidContentLength))?
+            .parse::<u64>()
+            .or(Err(Error::InvalidContentLength))?,
+    ))
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
@@ -66,24 +98,31 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Converts the minor version number httparse gives us into an http::Version
+pub fn version(minor_version: u8) -> http::Version {
+    match minor_version {
+        0 => http::Version::HTTP_10,
+        _ => http::Version::HTTP_11,
+    }
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
 /// * If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
-///
-/// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(version(req.version.unwrap()));
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -95,124 +134,136 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 }
 
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// This function only reads the request line and headers; the rest of the request body (for a POST
+/// request) can subsequently be forwarded with body::forward.
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
-    // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    // Try parsing repeatedly until we read a valid HTTP request. The request may already have
+    // started arriving along with the previous one.
+    let mut request_buffer = std::mem::take(buffered);
+    let mut chunk = [0_u8; MAX_HEADERS_SIZE];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
-            // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
-        }
-        bytes_read += new_bytes;
-
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) = parse_request(&request_buffer)? {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
             // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+            *request.body_mut() = request_buffer.split_off(headers_len);
             return Ok(request);
         }
+
+        // Read more bytes from the connection, as long as the headers would still fit
+        let space = MAX_HEADERS_SIZE.saturating_sub(request_buffer.len());
+        let new_bytes = stream
+            .read(&mut chunk[..space])
+            .await
+            .map_err(Error::ConnectionError)?;
+        if new_bytes == 0 {
+            // We didn't manage to read a complete request
+            return Err(Error::IncompleteRequest(request_buffer.len()));
+        }
+        request_buffer.extend_from_slice(&chunk[..new_bytes]);
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).or_else(|err| Err(Error::ConnectionError(err)))?;
-
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
+/// Works out how the end of the request's body is marked. Returns an error if the request doesn't
+/// say in a way we understand, or if its declared length is over `max_body_size`.
+fn body_framing(
+    request: &http::Request<Vec<u8>>,
+    max_body_size: Option<u64>,
+) -> Result<body::Framing, Error> {
+    // A chunked body ends with a zero-length chunk. Any other transfer coding leaves us no way of
+    // finding the end of the body.
+    if request
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::UnsupportedTransferEncoding);
         }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+        return Ok(body::Framing::Chunked);
+    }
+    // Otherwise, the client only sends a body if it supplied the Content-Length header (which it
+    // does for POST requests)
+    match get_content_length(request)? {
+        Some(content_length) if max_body_size.is_some_and(|max_size| content_length > max_size) => {
+            Err(Error::RequestBodyTooLarge)
         }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        Some(content_length) if content_length > 0 => Ok(body::Framing::Length(content_length)),
+        _ => Ok(body::Framing::Empty),
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// This function reads an HTTP request's headers from a stream, returning an Error if the client
+/// closes the connection prematurely or sends an invalid request. The body is not read: the
+/// returned request only holds the start of the body that happened to arrive with the headers, and
+/// the rest should be forwarded with body::forward using the returned framing.
 ///
-/// You will need to modify this function in Milestone 2.
-pub fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream)?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length)?;
-        }
+/// `buffered` holds bytes that were read from the stream after the end of the previous request,
+/// which are the start of this one. On return, it holds anything that arrived after this request's
+/// body (when its length is known), i.e. the start of the next request.
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffered: &mut Vec<u8>,
+    max_body_size: Option<u64>,
+) -> Result<(http::Request<Vec<u8>>, body::Framing), Error> {
+    let mut request = read_headers(stream, buffered).await?;
+    let framing = body_framing(&request, max_body_size)?;
+    *buffered = body::split_off_next(request.body_mut(), framing);
+    if framing == body::Framing::Chunked {
+        // Transfer-Encoding overrides Content-Length, and a Content-Length sent along with it
+        // can't be trusted, so don't pass it on
+        request.headers_mut().remove(http::header::CONTENT_LENGTH);
     }
-    Ok(request)
+    Ok((request, framing))
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub fn write_to_stream(
+/// This function serializes a request's request line and headers to bytes and writes those bytes to
+/// the provided stream. The body should be sent separately.
+pub async fn write_head_to_stream(
     request: &http::Request<Vec<u8>>,
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes())?;
-    stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+    stream
+        .write_all(&format_request_line(request).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes())?;
-        stream.write(header_value.as_bytes())?;
-        stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8])?;
-    if request.body().len() > 0 {
-        stream.write(request.body())?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// This function serializes a request to bytes and writes those bytes to the provided stream. The
+/// whole body must be in the request.
+pub async fn write_to_stream(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut TcpStream,
+) -> Result<(), std::io::Error> {
+    write_head_to_stream(request, stream).await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
-    format!("{} {} {:?}", request.method(), request.uri(), request.version())
+    format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri(),
+        request.version()
+    )
 }
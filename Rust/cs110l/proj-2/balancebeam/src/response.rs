@@ -1,11 +1,12 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use crate::{body, chunked, request};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -13,34 +14,61 @@ pub enum Error {
     MalformedResponse(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The Content-Length header is present more than once, so there is no telling which length
+    /// to believe
+    DuplicateContentLength,
+    /// The Content-Length header says the response body is bigger than the maximum response body
+    /// size
     ResponseBodyTooLarge,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
-/// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => {
+                write!(f, "connection closed before the end of the headers")
+            }
+            Error::MalformedResponse(error) => write!(f, "malformed response: {}", error),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::DuplicateContentLength => write!(f, "more than one Content-Length"),
+            Error::ResponseBodyTooLarge => {
+                write!(f, "response body is larger than the maximum body size")
+            }
+            Error::ConnectionError(error) => write!(f, "failed to read response: {}", error),
+        }
+    }
+}
+
+/// Extracts the Content-Length header value from the provided response. Returns Ok(Some(u64)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
-fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
+/// Err(Error) if Content-Length is present but invalid. More than one Content-Length header is an
+/// error even if they agree, since a peer that believes a different one than we do would see a
+/// different message (RFC 7230 section 3.3.3).
+fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<u64>, Error> {
     // Look for content-length header
-    if let Some(header_value) = response.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
+    let mut header_values = response
+        .headers()
+        .get_all(http::header::CONTENT_LENGTH)
+        .iter();
+    let header_value = match header_values.next() {
+        Some(header_value) => header_value,
         // If it doesn't exist, return None
-        Ok(None)
+        None => return Ok(None),
+    };
+    if header_values.next().is_some() {
+        return Err(Error::DuplicateContentLength);
     }
+    // If it exists, parse it as a u64 (or return InvalidContentLength if it can't be parsed as
+    // such). A comma-separated list of lengths fails to parse, too.
+    Ok(Some(
+        header_value
+            .to_str()
+            .or(Err(Error::InvalidContentLength))?
+            .parse::<u64>()
+            .or(Err(Error::InvalidContentLength))?,
+    ))
 }
 
 /// Attempts to parse the data in the supplied buffer as an HTTP response. Returns one of the
@@ -50,19 +78,16 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
 ///   Err(Error)
-///
-/// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(request::version(resp.version.unwrap()));
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -74,128 +99,144 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. This function only reads the response line and headers; the rest of the response body
+/// can subsequently be forwarded with body::forward.
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut TcpStream,
+    buffered: &mut Vec<u8>,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
-    // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    // Try parsing repeatedly until we read a valid HTTP response. The response may already have
+    // started arriving along with the previous one.
+    let mut response_buffer = std::mem::take(buffered);
+    let mut chunk = [0_u8; MAX_HEADERS_SIZE];
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
-            // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
-        }
-        bytes_read += new_bytes;
-
         // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+        if let Some((mut response, headers_len)) = parse_response(&response_buffer)? {
             // We've read a complete set of headers. We may have also read the first part of the
             // response body; take whatever is left over in the response buffer and save that as
             // the start of the response body.
-            response
-                .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+            *response.body_mut() = response_buffer.split_off(headers_len);
             return Ok(response);
         }
-    }
-}
-
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
-///
-/// You will need to modify this function in Milestone 2.
-fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
-
-    while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if bytes_read == 0 {
-            // The server has hung up!
-            if content_length.is_none() {
-                // We've reached the end of the response
-                break;
-            } else {
-                // Content-Length was set, but the server hung up before we managed to read that
-                // number of bytes
-                return Err(Error::ContentLengthMismatch);
-            }
-        }
-
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
-        }
 
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
+        // Read more bytes from the connection, as long as the headers would still fit
+        let space = MAX_HEADERS_SIZE.saturating_sub(response_buffer.len());
+        let new_bytes = stream
+            .read(&mut chunk[..space])
+            .await
+            .map_err(Error::ConnectionError)?;
+        if new_bytes == 0 {
+            // We didn't manage to read a complete response
+            return Err(Error::IncompleteResponse);
         }
-
-        // Append received bytes to the response body
-        response.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        response_buffer.extend_from_slice(&chunk[..new_bytes]);
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
-pub fn read_from_stream(
-    stream: &mut TcpStream,
+/// Works out how the end of the response's body is marked. Returns an error if the response's
+/// declared length is invalid or over `max_body_size`.
+fn body_framing(
+    response: &http::Response<Vec<u8>>,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream)?;
+    max_body_size: Option<u64>,
+) -> Result<body::Framing, Error> {
     // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    // the response status code is not 1xx, 204 (no content), or 304 (not modified). A successful
+    // response to CONNECT has no body either: everything after it belongs to the tunnel.
+    if request_method == http::Method::HEAD
+        || (request_method == http::Method::CONNECT && response.status().is_success())
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+        || response.status() == http::StatusCode::NOT_MODIFIED
     {
-        read_body(stream, &mut response)?;
+        return Ok(body::Framing::Empty);
+    }
+    // A chunked body ends with its last chunk. A response with some other transfer coding ends
+    // when the connection is closed, whatever its Content-Length says.
+    if chunked::is_chunked(response.headers()) {
+        return Ok(body::Framing::Chunked);
+    }
+    if response
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
+    {
+        return Ok(body::Framing::UntilClose);
+    }
+    // The response may or may not supply a Content-Length header. If it provides the header, then
+    // the body is that many bytes long; if it does not, the body ends when the connection is
+    // closed.
+    match get_content_length(response)? {
+        Some(content_length) if max_body_size.is_some_and(|max_size| content_length > max_size) => {
+            Err(Error::ResponseBodyTooLarge)
+        }
+        Some(content_length) => Ok(body::Framing::Length(content_length)),
+        None => Ok(body::Framing::UntilClose),
     }
-    Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// This function reads an HTTP response's headers from a stream, returning an Error if the server
+/// closes the connection prematurely or sends an invalid response. The body is not read: the
+/// returned response only holds the start of the body that happened to arrive with the headers,
+/// and the rest should be forwarded with body::forward using the returned framing.
 ///
-/// You will need to modify this function in Milestone 2.
-pub fn write_to_stream(
-    response: &http::Response<Vec<u8>>,
+/// `buffered` holds bytes that were read from the stream after the end of the previous response,
+/// which are the start of this one. On return, it holds anything that arrived after this response's
+/// body (when its length is known).
+pub async fn read_from_stream(
     stream: &mut TcpStream,
+    buffered: &mut Vec<u8>,
+    request_method: &http::Method,
+    max_body_size: Option<u64>,
+) -> Result<(http::Response<Vec<u8>>, body::Framing), Error> {
+    let mut response = read_headers(stream, buffered).await?;
+    let framing = body_framing(&response, request_method, max_body_size)?;
+    *buffered = body::split_off_next(response.body_mut(), framing);
+    if framing == body::Framing::Chunked {
+        // Transfer-Encoding overrides Content-Length, and a Content-Length sent along with it
+        // can't be trusted, so don't pass it on
+        response.headers_mut().remove(http::header::CONTENT_LENGTH);
+    }
+    Ok((response, framing))
+}
+
+/// This function serializes a response's status line and headers to bytes and writes those bytes
+/// to the provided stream. The body should be sent separately.
+pub async fn write_head_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes())?;
-    stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
+    stream
+        .write_all(&format_response_line(response).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes())?;
-        stream.write(header_value.as_bytes())?;
-        stream.write(&['\r' as u8, '\n' as u8])?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8])?;
-    if response.body().len() > 0 {
-        stream.write(response.body())?;
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
+    stream.write_all(b"\r\n").await?;
     Ok(())
 }
 
+/// This function serializes a response to bytes and writes those bytes to the provided stream. The
+/// whole body must be in the response.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head_to_stream(response, stream).await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
+    }
+    stream.flush().await
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
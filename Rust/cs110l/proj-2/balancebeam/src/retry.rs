@@ -0,0 +1,221 @@
+use crate::clock::{Clock, SystemClock};
+use crate::config::RetryConfig;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of one-second buckets the retry budget counts requests and retries over
+const BUDGET_WINDOW_SECS: usize = 10;
+
+/// Something that can go wrong with an attempt to send a request to an upstream, and that may be
+/// worth trying again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    /// The connection to the upstream broke while sending the request or reading the response
+    ConnectionError,
+    /// The upstream didn't start responding within the upstream response timeout
+    Timeout,
+    /// The upstream responded with this status
+    Status(http::StatusCode),
+}
+
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connection-error" => Ok(Condition::ConnectionError),
+            "timeout" => Ok(Condition::Timeout),
+            _ => s
+                .parse::<u16>()
+                .ok()
+                .and_then(|code| http::StatusCode::from_u16(code).ok())
+                .map(Condition::Status)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown retry condition {:?} (expected connection-error, timeout or a \
+                        status code)",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// Returns the longest we might wait before the given retry (numbered from 1). The limit starts at
+/// the configured backoff and doubles with each retry, up to the configured maximum.
+fn backoff_limit(config: &RetryConfig, retry: u32) -> Duration {
+    let factor = 2_u32.saturating_pow(retry.saturating_sub(1));
+    config
+        .backoff
+        .checked_mul(factor)
+        .map_or(config.max_backoff, |backoff| {
+            backoff.min(config.max_backoff)
+        })
+}
+
+/// Picks how long to wait before the given retry (numbered from 1). The wait is random up to
+/// `backoff_limit`, so that requests that failed together don't all come back at the same time.
+pub fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    backoff_limit(config, retry).mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Requests and retries seen in one second
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    /// Seconds since the budget was created
+    second: u64,
+    requests: u64,
+    retries: u64,
+}
+
+/// Limits retries across all requests to a share of recent traffic, so that when upstreams are
+/// failing we don't make things worse by sending them several times as many requests. Over the last
+/// ten seconds, retries may make up `budget_percent` of requests, plus `budget_min_per_second` a
+/// second so that quiet periods can still retry.
+pub struct Budget {
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    buckets: Mutex<[Bucket; BUDGET_WINDOW_SECS]>,
+}
+
+impl Budget {
+    pub fn new() -> Budget {
+        Budget::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Budget {
+        Budget {
+            started: clock.now(),
+            clock,
+            buckets: Mutex::new([Bucket::default(); BUDGET_WINDOW_SECS]),
+        }
+    }
+
+    /// Returns the bucket for the current second, emptying it first if it was last used for an
+    /// earlier second
+    fn current<'a>(&self, buckets: &'a mut [Bucket; BUDGET_WINDOW_SECS]) -> &'a mut Bucket {
+        let second = self.clock.now().duration_since(self.started).as_secs();
+        let bucket = &mut buckets[second as usize % BUDGET_WINDOW_SECS];
+        if bucket.second != second {
+            *bucket = Bucket {
+                second,
+                requests: 0,
+                retries: 0,
+            };
+        }
+        bucket
+    }
+
+    /// Records a request sent to an upstream for the first time
+    pub fn record_request(&self) {
+        let mut buckets = self.buckets.lock();
+        self.current(&mut buckets).requests += 1;
+    }
+
+    /// Asks to retry a request. Returns true (and counts the retry) if the budget allows it.
+    pub fn try_retry(&self, config: &RetryConfig) -> bool {
+        let mut buckets = self.buckets.lock();
+        let now = self.current(&mut buckets).second;
+        let (requests, retries) = buckets
+            .iter()
+            .filter(|bucket| bucket.second + BUDGET_WINDOW_SECS as u64 > now)
+            .fold((0, 0), |(requests, retries), bucket| {
+                (requests + bucket.requests, retries + bucket.retries)
+            });
+        let allowed = requests * config.budget_percent as u64 / 100
+            + config.budget_min_per_second as u64 * BUDGET_WINDOW_SECS as u64;
+        if retries >= allowed {
+            return false;
+        }
+        self.current(&mut buckets).retries += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            retry_on: vec![Condition::ConnectionError],
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            budget_percent: 20,
+            budget_min_per_second: 0,
+        }
+    }
+
+    #[test]
+    fn parse_conditions() {
+        assert_eq!(
+            "connection-error".parse::<Condition>(),
+            Ok(Condition::ConnectionError)
+        );
+        assert_eq!("timeout".parse::<Condition>(), Ok(Condition::Timeout));
+        assert_eq!(
+            "503".parse::<Condition>(),
+            Ok(Condition::Status(http::StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert!("5xx".parse::<Condition>().is_err());
+        assert!("1000".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = config();
+        assert_eq!(backoff_limit(&config, 1), Duration::from_millis(10));
+        assert_eq!(backoff_limit(&config, 2), Duration::from_millis(20));
+        assert_eq!(backoff_limit(&config, 3), Duration::from_millis(40));
+        assert_eq!(backoff_limit(&config, 4), Duration::from_millis(50));
+        assert_eq!(backoff_limit(&config, 100), Duration::from_millis(50));
+        for retry in 1..5 {
+            assert!(backoff(&config, retry) <= backoff_limit(&config, retry));
+        }
+    }
+
+    #[test]
+    fn budget_limits_retries_to_a_share_of_requests() {
+        let clock = TestClock::new();
+        let budget = Budget::with_clock(clock.clone());
+        let config = config();
+        assert!(!budget.try_retry(&config));
+        for _ in 0..10 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry(&config));
+        assert!(budget.try_retry(&config));
+        assert!(!budget.try_retry(&config));
+
+        // Requests (and retries) older than the window stop counting
+        clock.advance(Duration::from_secs(5));
+        for _ in 0..5 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry(&config));
+        assert!(!budget.try_retry(&config));
+        clock.advance(Duration::from_secs(6));
+        // Only the last five requests and the last retry are left
+        assert!(!budget.try_retry(&config));
+        for _ in 0..5 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry(&config));
+        assert!(!budget.try_retry(&config));
+    }
+
+    #[test]
+    fn budget_allows_a_minimum_rate() {
+        let budget = Budget::new();
+        let mut config = config();
+        config.budget_min_per_second = 1;
+        for _ in 0..BUDGET_WINDOW_SECS {
+            assert!(budget.try_retry(&config));
+        }
+        assert!(!budget.try_retry(&config));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::{PathRewrite, RouteConfig, DEFAULT_POOL};
+use regex::Regex;
+
+/// A route from the config, ready to match requests against
+struct Route {
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    pool: String,
+    rewrite: Option<PathRewrite>,
+}
+
+/// The path a route rewrote a request to is not a valid URI
+#[derive(Debug)]
+pub struct RewriteError(String);
+
+impl std::fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rewritten path is not valid: {}", self.0)
+    }
+}
+
+/// Picks the upstream pool for each request using the configured routes
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+/// Returns the host a request is for, without any port. This comes from the request URI if the
+/// client sent an absolute URI, and from the Host header otherwise.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if let Some(host) = request.uri().host() {
+        return Some(host.to_ascii_lowercase());
+    }
+    let host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+    let host = match host.rfind(':') {
+        // Leave the colons in IPv6 addresses alone
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Returns true if `path` is `prefix` or carries on from it with a new path segment
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+impl Route {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        if let Some(pattern) = &self.host {
+            if !host.is_some_and(|host| host_matches(pattern, host)) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.path_prefix {
+            if !path_has_prefix(path, prefix) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.path_regex {
+            if !regex.is_match(path) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Applies the route's rewrite (if it has one) to the request's path, keeping the query string.
+    /// Fails, leaving the request alone, if the rewritten path is not a valid URI.
+    fn rewrite(&self, request: &mut http::Request<Vec<u8>>) -> Result<(), RewriteError> {
+        let (rewrite, prefix) = match (&self.rewrite, &self.path_prefix) {
+            (Some(rewrite), Some(prefix)) => (rewrite, prefix),
+            _ => return Ok(()),
+        };
+        let rest = request.uri().path()[prefix.len()..].trim_start_matches('/');
+        let replacement = match rewrite {
+            PathRewrite::StripPrefix => "/",
+            PathRewrite::ReplacePrefix(replacement) => replacement,
+        };
+        let mut path = if rest.is_empty() {
+            replacement.to_string()
+        } else {
+            format!("{}/{}", replacement.trim_end_matches('/'), rest)
+        };
+        if let Some(query) = request.uri().query() {
+            path = format!("{}?{}", path, query);
+        }
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = Some(path.parse().map_err(|error: http::uri::InvalidUri| {
+            RewriteError(format!("{:?}: {}", path, error))
+        })?);
+        *request.uri_mut() =
+            http::Uri::from_parts(parts).map_err(|error| RewriteError(error.to_string()))?;
+        Ok(())
+    }
+}
+
+impl Router {
+    /// Builds a router for the given routes, which must have been validated already
+    pub fn new(routes: &[RouteConfig]) -> Router {
+        Router {
+            routes: routes
+                .iter()
+                .map(|route| Route {
+                    host: route.host.as_ref().map(|host| host.to_ascii_lowercase()),
+                    path_prefix: route.path_prefix.clone(),
+                    path_regex: route
+                        .path_regex
+                        .as_ref()
+                        .map(|regex| Regex::new(regex).expect("route regex was not validated")),
+                    pool: route.pool.clone(),
+                    rewrite: route.rewrite.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the name of the pool the request should go to, rewriting its path if the matching
+    /// route says to. Returns None if no route matches, or an error if the matching route's
+    /// rewrite doesn't produce a valid path. If there are no routes at all, every request goes to
+    /// the default pool.
+    pub fn route(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Result<Option<&str>, RewriteError> {
+        if self.routes.is_empty() {
+            return Ok(Some(DEFAULT_POOL));
+        }
+        let host = request_host(request);
+        let route = match self
+            .routes
+            .iter()
+            .find(|route| route.matches(host.as_deref(), request.uri().path()))
+        {
+            Some(route) => route,
+            None => return Ok(None),
+        };
+        route.rewrite(request)?;
+        Ok(Some(&route.pool))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: Option<&str>, path_prefix: Option<&str>, pool: &str) -> RouteConfig {
+        RouteConfig {
+            host: host.map(str::to_string),
+            path_prefix: path_prefix.map(str::to_string),
+            path_regex: None,
+            pool: pool.to_string(),
+            rewrite: None,
+        }
+    }
+
+    fn request(host: &str, uri: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .uri(uri)
+            .header("Host", host)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn everything_goes_to_the_default_pool_without_routes() {
+        let router = Router::new(&[]);
+        assert_eq!(
+            router
+                .route(&mut request("example.com", "/anything"))
+                .unwrap(),
+            Some(DEFAULT_POOL)
+        );
+    }
+
+    #[test]
+    fn matches_hosts_and_path_prefixes() {
+        let router = Router::new(&[
+            route(Some("api.example.com"), None, "api"),
+            route(Some("*.static.example.com"), None, "static"),
+            route(None, Some("/admin"), "admin"),
+        ]);
+        let pool_for = |host: &str, uri: &str| {
+            router
+                .route(&mut request(host, uri))
+                .unwrap()
+                .map(str::to_string)
+        };
+        assert_eq!(
+            pool_for("API.example.com:8080", "/"),
+            Some("api".to_string())
+        );
+        assert_eq!(
+            pool_for("eu.static.example.com", "/a.css"),
+            Some("static".to_string())
+        );
+        assert_eq!(pool_for("static.example.com", "/a.css"), None);
+        assert_eq!(
+            pool_for("example.com", "/admin/users"),
+            Some("admin".to_string())
+        );
+        assert_eq!(pool_for("example.com", "/admin"), Some("admin".to_string()));
+        assert_eq!(pool_for("example.com", "/administrator"), None);
+        // Earlier routes win
+        assert_eq!(
+            pool_for("api.example.com", "/admin"),
+            Some("api".to_string())
+        );
+    }
+
+    #[test]
+    fn matches_path_regexes() {
+        let mut images = route(None, None, "images");
+        images.path_regex = Some(r"\.(png|jpe?g)$".to_string());
+        let router = Router::new(&[images]);
+        assert_eq!(
+            router
+                .route(&mut request("example.com", "/cat.jpeg?size=2"))
+                .unwrap(),
+            Some("images")
+        );
+        assert_eq!(
+            router
+                .route(&mut request("example.com", "/cat.gif"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rewrites_path_prefixes() {
+        let mut strip = route(None, Some("/api"), "api");
+        strip.rewrite = Some(PathRewrite::StripPrefix);
+        let mut replace = route(None, Some("/old/"), "old");
+        replace.rewrite = Some(PathRewrite::ReplacePrefix("/new".to_string()));
+        let router = Router::new(&[strip, replace]);
+
+        let rewritten = |uri: &str| {
+            let mut request = request("example.com", uri);
+            router.route(&mut request).unwrap();
+            request.uri().to_string()
+        };
+        assert_eq!(rewritten("/api/users?page=2"), "/users?page=2");
+        assert_eq!(rewritten("/api"), "/");
+        assert_eq!(rewritten("/old/page"), "/new/page");
+        assert_eq!(rewritten("/old/"), "/new");
+    }
+
+    #[test]
+    fn reports_invalid_rewrites() {
+        // Config validation turns this prefix away, but routing shouldn't rely on that
+        let mut bad = route(None, Some("/api"), "api");
+        bad.rewrite = Some(PathRewrite::ReplacePrefix("/a b".to_string()));
+        let router = Router::new(&[bad]);
+        let mut request = request("example.com", "/api/users");
+        assert!(router.route(&mut request).is_err());
+        assert_eq!(request.uri(), "/api/users");
+    }
+}
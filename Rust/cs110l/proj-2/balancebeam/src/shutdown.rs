@@ -0,0 +1,83 @@
+use crate::timeout;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Tells client connections when balancebeam starts shutting down, and waits for them to finish.
+/// Each connection holds a Watcher while it is open; draining is done once every Watcher has been
+/// dropped.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown { sender, receiver }
+    }
+
+    /// Returns a Watcher for a new connection to hold on to until it closes
+    pub fn watch(&self) -> Watcher {
+        Watcher {
+            receiver: self.receiver.clone(),
+        }
+    }
+
+    /// Tells every connection to finish up, then waits up to `deadline` for them all to close.
+    /// Returns true if they did.
+    pub async fn drain(self, deadline: Option<Duration>) -> bool {
+        let Shutdown {
+            mut sender,
+            receiver,
+        } = self;
+        // Nobody may be listening, which is fine: then there is nothing to drain
+        let _ = sender.broadcast(true);
+        drop(receiver);
+        timeout::optional(deadline, sender.closed()).await.is_ok()
+    }
+}
+
+/// A connection's view of whether balancebeam is shutting down
+pub struct Watcher {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Watcher {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once balancebeam starts shutting down
+    pub async fn shutting_down(&mut self) {
+        while !self.is_shutting_down() {
+            if self.receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_watchers_to_drop() {
+        let shutdown = Shutdown::new();
+        let mut watcher = shutdown.watch();
+        assert!(!watcher.is_shutting_down());
+        let connection = tokio::spawn(async move {
+            watcher.shutting_down().await;
+            assert!(watcher.is_shutting_down());
+        });
+        assert!(shutdown.drain(Some(Duration::from_secs(5))).await);
+        connection.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_deadline() {
+        let shutdown = Shutdown::new();
+        let _busy = shutdown.watch();
+        assert!(!shutdown.drain(Some(Duration::from_millis(50))).await);
+    }
+}
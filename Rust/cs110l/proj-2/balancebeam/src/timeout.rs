@@ -0,0 +1,14 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Elapsed;
+
+/// Like tokio::time::timeout, but only imposes a time limit if `duration` is set
+pub async fn optional<F: Future>(
+    duration: Option<Duration>,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future).await,
+        None => Ok(future.await),
+    }
+}
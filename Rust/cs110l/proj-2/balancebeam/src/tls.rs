@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file couldn't be read
+    Read(String, std::io::Error),
+    /// A certificate file didn't contain any PEM certificates
+    NoCertificates(String),
+    /// A key file didn't contain a usable PKCS#8 or RSA private key
+    NoPrivateKey(String),
+    /// The number of certificate files didn't match the number of key files
+    Unpaired,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read(path, error) => write!(f, "could not read {}: {}", path, error),
+            Error::NoCertificates(path) => write!(f, "no certificates found in {}", path),
+            Error::NoPrivateKey(path) => write!(f, "no usable private key found in {}", path),
+            Error::Unpaired => write!(
+                f,
+                "every --tls-cert needs a matching --tls-key (given in the same order)"
+            ),
+        }
+    }
+}
+
+/// Picks the certificate to present to a client, based on the hostname it asked for with SNI. The
+/// first certificate that is valid for the hostname is used. Clients that don't send SNI, or ask
+/// for a hostname none of the certificates cover, get the first certificate.
+struct CertificateResolver {
+    certificates: Vec<CertifiedKey>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let matching = client_hello.server_name().and_then(|name| {
+            self.certificates
+                .iter()
+                .find(|certificate| certificate.cross_check_end_entity_cert(Some(name)).is_ok())
+        });
+        matching.or_else(|| self.certificates.first()).cloned()
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| Error::Read(path.to_string(), error))
+}
+
+/// Reads a certificate chain and its private key from PEM files
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, Error> {
+    let certificates = pemfile::certs(&mut open(cert_path)?)
+        .ok()
+        .filter(|certificates| !certificates.is_empty())
+        .ok_or_else(|| Error::NoCertificates(cert_path.to_string()))?;
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .first()
+        .and_then(|key| sign::any_supported_type(key).ok())
+        .ok_or_else(|| Error::NoPrivateKey(key_path.to_string()))?;
+
+    Ok(CertifiedKey::new(certificates, Arc::new(key)))
+}
+
+/// Builds the TLS config for client connections from pairs of certificate and key files. The i-th
+/// certificate goes with the i-th key.
+pub fn load_config(cert_paths: &[String], key_paths: &[String]) -> Result<ServerConfig, Error> {
+    if cert_paths.len() != key_paths.len() {
+        return Err(Error::Unpaired);
+    }
+    let certificates = cert_paths
+        .iter()
+        .zip(key_paths)
+        .map(|(cert_path, key_path)| load_certified_key(cert_path, key_path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = Arc::new(CertificateResolver { certificates });
+    Ok(config)
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// A connection from a client, which is encrypted if balancebeam is terminating TLS
+pub struct ClientStream {
+    stream: Stream,
+    /// A byte read by wait_for_data that hasn't been passed on to a reader yet
+    peeked: Option<u8>,
+}
+
+impl ClientStream {
+    pub fn plain(stream: TcpStream) -> ClientStream {
+        ClientStream {
+            stream: Stream::Plain(stream),
+            peeked: None,
+        }
+    }
+
+    pub fn tls(stream: TlsStream<TcpStream>) -> ClientStream {
+        ClientStream {
+            stream: Stream::Tls(Box::new(stream)),
+            peeked: None,
+        }
+    }
+
+    /// The underlying TCP connection
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match &self.stream {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    /// Waits until the client sends something (or hangs up). Whatever arrives is kept to be read
+    /// as normal afterwards. (We can't just peek at the TCP connection, because the TLS session may
+    /// already have decrypted data buffered.)
+    pub async fn wait_for_data(&mut self) -> Result<(), std::io::Error> {
+        if self.peeked.is_none() {
+            let mut byte = [0; 1];
+            if self.read(&mut byte).await? == 1 {
+                self.peeked = Some(byte[0]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the scheme the client used to connect ("http" or "https")
+    pub fn scheme(&self) -> &'static str {
+        match self.stream {
+            Stream::Plain(_) => "http",
+            Stream::Tls(_) => "https",
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if !buf.is_empty() {
+            if let Some(byte) = this.peeked.take() {
+                buf[0] = byte;
+                return Poll::Ready(Ok(1));
+            }
+        }
+        match &mut this.stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_bad_certificate_options() {
+        let paths = |path: &str| vec![path.to_string()];
+        assert!(matches!(
+            load_config(&paths("cert.pem"), &[]),
+            Err(Error::Unpaired)
+        ));
+        assert!(matches!(
+            load_config(
+                &paths("/nonexistent/cert.pem"),
+                &paths("/nonexistent/key.pem")
+            ),
+            Err(Error::Read(..))
+        ));
+
+        // A file with no PEM certificates in it
+        let empty =
+            std::env::temp_dir().join(format!("balancebeam-tls-test-{}", std::process::id()));
+        std::fs::write(&empty, "").unwrap();
+        let empty_path = paths(empty.to_str().unwrap());
+        assert!(matches!(
+            load_config(&empty_path, &empty_path),
+            Err(Error::NoCertificates(_))
+        ));
+        std::fs::remove_file(&empty).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Copies everything from `from` to `to`, then shuts down `to` so the other end sees the sender
+/// has finished. Returns the number of bytes copied.
+async fn copy_then_shutdown<R, W>(from: &mut R, to: &mut W) -> Result<u64, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(from, to).await?;
+    to.shutdown().await?;
+    Ok(copied)
+}
+
+/// Once a connection has switched away from HTTP (after a 101 Switching Protocols or a successful
+/// CONNECT), we no longer understand what the client and upstream send each other, so we just pass
+/// bytes along in both directions until both sides are done. If either direction fails, the other
+/// is abandoned too, since there's no telling what state the conversation is in. Returns the number
+/// of bytes sent from the client to the upstream and from the upstream to the client.
+pub async fn splice<C, U>(client: &mut C, upstream: &mut U) -> Result<(u64, u64), std::io::Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    futures::future::try_join(
+        copy_then_shutdown(&mut client_read, &mut upstream_write),
+        copy_then_shutdown(&mut upstream_read, &mut client_write),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn splices_both_directions() {
+        let (mut client, mut client_end) = tokio::io::duplex(64);
+        let (mut upstream, mut upstream_end) = tokio::io::duplex(64);
+        let spliced = tokio::spawn(async move { splice(&mut client_end, &mut upstream_end).await });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0_u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        upstream.write_all(b"pong!").await.unwrap();
+        let mut buf = [0_u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong!");
+
+        // Each side hanging up is passed on to the other
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        upstream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        upstream.shutdown().await.unwrap();
+        assert_eq!(spliced.await.unwrap().unwrap(), (4, 5));
+    }
+}
@@ -0,0 +1,60 @@
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::UpstreamConfig;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// An upstream server that we proxy requests to, along with the bookkeeping that load balancing
+/// strategies need about it.
+pub struct Upstream {
+    /// Address (host:port) of the server
+    pub address: String,
+    /// How many requests this upstream should get relative to the others when using weighted
+    /// balancing. This can change while the upstream is in use, through the admin API or a reload.
+    weight: AtomicU32,
+    /// Number of client connections currently being proxied to this upstream
+    active_connections: AtomicUsize,
+    /// Stops requests being sent to the upstream while it keeps failing
+    pub circuit_breaker: CircuitBreaker,
+}
+
+impl Upstream {
+    pub fn new(config: &UpstreamConfig) -> Upstream {
+        Upstream {
+            address: config.address.clone(),
+            weight: AtomicU32::new(config.weight),
+            active_connections: AtomicUsize::new(0),
+            circuit_breaker: CircuitBreaker::new(&config.address),
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::SeqCst)
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::SeqCst);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Records that a client connection is now being proxied to this upstream. The connection is
+    /// counted until the returned guard is dropped.
+    pub fn track_connection(&self) -> ActiveConnection<'_> {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection { upstream: self }
+    }
+}
+
+/// Keeps a connection counted in Upstream::active_connections for as long as it is alive
+pub struct ActiveConnection<'a> {
+    upstream: &'a Upstream,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.upstream
+            .active_connections
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
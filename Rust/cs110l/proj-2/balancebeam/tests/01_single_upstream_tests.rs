@@ -1,12 +1,21 @@
 mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
+    setup_with_args(&[]).await
+}
+
+async fn setup_with_args(extra_args: &[&str]) -> (BalanceBeam, EchoServer) {
     init_logging();
     let upstream = EchoServer::new().await;
-    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, extra_args).await;
     (balancebeam, upstream)
 }
 
@@ -99,3 +108,278 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure a client that never finishes sending its request doesn't prevent balancebeam from
+/// serving anybody else. Open a connection and send half a request line, then send a normal
+/// request on a separate connection while the first one is still hanging.
+#[tokio::test]
+async fn test_slow_client_does_not_block_others() {
+    let (balancebeam, upstream) = setup().await;
+
+    log::info!("Opening a connection that only sends part of a request");
+    let mut slow_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    slow_conn
+        .write_all(b"GET /slow HTTP/1.1\r\n")
+        .await
+        .expect("Failed to send partial request to balancebeam");
+
+    log::info!("Sending a complete request on a second connection");
+    let response_text = timeout(Duration::from_secs(5), balancebeam.get("/fast"))
+        .await
+        .expect("Request timed out. Is balancebeam handling connections one at a time?")
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /fast HTTP/1.1"));
+
+    drop(slow_conn);
+    log::info!("Checking that the origin server received 1 request");
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 1,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Send several requests, each on its own client connection, and make sure balancebeam reuses its
+/// connection to the upstream instead of opening a new one every time
+#[tokio::test]
+async fn test_upstream_connection_reuse() {
+    let n_requests = 10;
+    let (balancebeam, upstream) = setup().await;
+
+    for i in 0..n_requests {
+        let path = format!("/pooled-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let connections = upstream.connections_received();
+    log::info!(
+        "Upstream received {} connections for {} requests",
+        connections,
+        n_requests
+    );
+    assert!(
+        connections < n_requests,
+        "balancebeam doesn't seem to be reusing upstream connections"
+    );
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// With pooling turned off, every client connection should get its own upstream connection
+#[tokio::test]
+async fn test_upstream_connection_pooling_disabled() {
+    let n_requests = 5;
+    let (balancebeam, upstream) = setup_with_args(&["--upstream-pool-size", "0"]).await;
+
+    for i in 0..n_requests {
+        let path = format!("/unpooled-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(upstream.connections_received(), n_requests);
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// Restart the upstream so that every pooled connection to it is closed, and make sure requests
+/// keep working instead of failing on the dead pooled connections
+#[tokio::test]
+async fn test_pooled_connections_to_restarted_upstream() {
+    let (balancebeam, upstream) = setup().await;
+    let upstream_address = upstream.address.clone();
+
+    let response_text = balancebeam
+        .get("/before-restart")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /before-restart HTTP/1.1"));
+
+    log::info!("Restarting the upstream server");
+    Box::new(upstream).stop().await;
+    let upstream = EchoServer::new_at_address(upstream_address).await;
+
+    for i in 0..3 {
+        let path = format!("/after-restart-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Is it using closed pooled connections?"
+        );
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers the first request on each connection and keeps the connection
+/// open, but hangs up without answering any later request on it, as if it had closed the idle
+/// connection just as the request arrived. Returns its address and the request line of every
+/// request it received.
+async fn start_idle_closing_server() -> (String, Arc<Mutex<Vec<String>>>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let requests_received = Arc::new(Mutex::new(Vec::new()));
+    let requests = requests_received.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut first = true;
+                loop {
+                    // Read the head byte by byte, so nothing past it is read
+                    let mut head = Vec::new();
+                    let mut byte = [0_u8; 1];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut byte).await {
+                            Ok(1) => head.push(byte[0]),
+                            _ => return,
+                        }
+                    }
+                    let head = String::from_utf8(head).unwrap().to_lowercase();
+                    let content_length: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |value| value.trim().parse().unwrap());
+                    let mut body = vec![0_u8; content_length];
+                    if stream.read_exact(&mut body).await.is_err() {
+                        return;
+                    }
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push(head.lines().next().unwrap().to_string());
+                    if !first {
+                        return;
+                    }
+                    first = false;
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                    if stream.write_all(response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, requests_received)
+}
+
+/// A request that isn't safe to repeat should never go out on a pooled connection, since the
+/// upstream may act on it and then hang up, leaving no way to tell whether it's safe to resend
+#[tokio::test]
+async fn test_post_not_resent_after_stale_pooled_connection() {
+    init_logging();
+    let (upstream_address, requests_received) = start_idle_closing_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    // Leave a connection to the upstream in the pool
+    let client = reqwest::Client::new();
+    let response = client
+        .get(&format!("http://{}/warm-up", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let response = client
+        .post(&format!("http://{}/once", balancebeam.address))
+        .body("charge the card")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    log::info!("POST got {}", response.status());
+    let posts = requests_received
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request_line| request_line.starts_with("post "))
+        .count();
+    assert_eq!(posts, 1, "the upstream received the POST more than once");
+
+    log::info!("All done :)");
+}
+
+/// Upload and download bodies bigger than balancebeam used to be able to hold in memory
+#[tokio::test]
+async fn test_large_bodies() {
+    let (balancebeam, upstream) = setup().await;
+    let body = "0123456789".repeat(1_500_000);
+
+    log::info!("Sending a {} byte POST request", body.len());
+    let response_text = balancebeam
+        .post("/large-upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /large-upload HTTP/1.1"));
+    assert!(response_text.contains(&format!("content-length: {}", body.len())));
+    assert!(
+        response_text.ends_with(&format!("\n\n{}", body)),
+        "Response body was not forwarded intact"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Requests with bodies over --max-request-body-size should be turned away without reaching the
+/// upstream
+#[tokio::test]
+async fn test_max_request_body_size() {
+    let (balancebeam, upstream) = setup_with_args(&["--max-request-body-size", "1000"]).await;
+
+    let response_text = balancebeam
+        .post("/small-upload", &"a".repeat(1000))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /small-upload HTTP/1.1"));
+
+    let response_text = balancebeam
+        .post("/big-upload", &"a".repeat(1001))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "HTTP 413 Payload Too Large");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Responses with bodies over --max-response-body-size should not be forwarded
+#[tokio::test]
+async fn test_max_response_body_size() {
+    let (balancebeam, upstream) = setup_with_args(&["--max-response-body-size", "1000"]).await;
+
+    let response_text = balancebeam
+        .post("/small-download", "a")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /small-download HTTP/1.1"));
+
+    // The echo server's response includes the request body, so it is over the limit
+    let response_text = balancebeam
+        .post("/big-download", &"a".repeat(1000))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "HTTP 502 Bad Gateway");
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
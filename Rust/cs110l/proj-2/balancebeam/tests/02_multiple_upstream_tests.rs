@@ -2,6 +2,7 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::delay_for;
 
@@ -9,6 +10,21 @@ async fn setup_with_params(
     n_upstreams: usize,
     active_health_check_interval: Option<usize>,
     max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_args(
+        n_upstreams,
+        active_health_check_interval,
+        max_requests_per_minute,
+        &[],
+    )
+    .await
+}
+
+async fn setup_with_args(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
 ) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
@@ -23,10 +39,11 @@ async fn setup_with_params(
         .iter()
         .map(|addr| addr.as_str())
         .collect();
-    let balancebeam = BalanceBeam::new(
+    let balancebeam = BalanceBeam::new_with_args(
         &upstream_addresses,
         active_health_check_interval,
         max_requests_per_minute,
+        extra_args,
     )
     .await;
     (balancebeam, upstreams)
@@ -36,20 +53,32 @@ async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
 
-/// Send a bunch of requests to the load balancer, and ensure they are evenly distributed across the
-/// upstream servers
-#[tokio::test]
-async fn test_load_distribution() {
-    let n_upstreams = 3;
-    let n_requests = 90;
-    let (balancebeam, mut upstreams) = setup(n_upstreams).await;
-
+/// Sends `n_requests` requests to the load balancer, setting `header` on each one if given, then
+/// shuts down the upstreams and returns the number of requests each of them received
+async fn distribute_requests(
+    balancebeam: &BalanceBeam,
+    mut upstreams: Vec<Box<dyn Server>>,
+    n_requests: usize,
+    header: Option<(&str, &str)>,
+) -> Vec<usize> {
+    // All of the requests share one keep-alive connection to balancebeam, which should still
+    // balance every request on its own
+    let client = reqwest::Client::new();
     for i in 0..n_requests {
         let path = format!("/request-{}", i);
-        let response_text = balancebeam
-            .get(&path)
+        let mut request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests");
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let response_text = request
+            .send()
             .await
-            .expect("Error sending request to balancebeam");
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
         assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
         assert!(response_text.contains("x-sent-by: balancebeam-tests"));
         assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
@@ -63,19 +92,199 @@ async fn test_load_distribution() {
         "Number of requests received by each upstream: {:?}",
         request_counters
     );
-    let avg_req_count =
-        request_counters.iter().sum::<usize>() as f64 / request_counters.len() as f64;
-    log::info!("Average number of requests per upstream: {}", avg_req_count);
-    for upstream_req_count in request_counters {
-        if (upstream_req_count as f64 - avg_req_count).abs() > 0.4 * avg_req_count {
+    request_counters
+}
+
+/// Panics if any upstream's request count is more than 40% away from what it should have gotten
+fn assert_distribution(request_counters: &[usize], expected_counts: &[f64]) {
+    for (upstream_req_count, expected) in request_counters.iter().zip(expected_counts) {
+        if (*upstream_req_count as f64 - expected).abs() > 0.4 * expected {
             log::error!(
-                "Upstream request count {} differs too much from the average! Load doesn't seem \
-                evenly distributed.",
-                upstream_req_count
+                "Upstream request count {} differs too much from the expected {}! Load doesn't \
+                seem to be distributed correctly.",
+                upstream_req_count,
+                expected
             );
             panic!("Upstream request count differs too much");
         }
     }
+}
+
+/// Send a bunch of requests to the load balancer, and ensure they are evenly distributed across the
+/// upstream servers
+#[tokio::test]
+async fn test_load_distribution() {
+    let n_upstreams = 3;
+    let n_requests = 90;
+    let (balancebeam, upstreams) = setup(n_upstreams).await;
+
+    let request_counters = distribute_requests(&balancebeam, upstreams, n_requests, None).await;
+    let avg_req_count =
+        request_counters.iter().sum::<usize>() as f64 / request_counters.len() as f64;
+    log::info!("Average number of requests per upstream: {}", avg_req_count);
+    assert_distribution(&request_counters, &[avg_req_count; 3]);
+
+    log::info!("All done :)");
+}
+
+/// Round-robin balancing should hand out requests to the upstreams in turn
+#[tokio::test]
+async fn test_load_distribution_round_robin() {
+    let n_upstreams = 3;
+    let n_requests = 90;
+    // Keep active health checks out of the request counts
+    let (balancebeam, upstreams) = setup_with_args(
+        n_upstreams,
+        Some(600),
+        None,
+        &["--balance-strategy", "round-robin"],
+    )
+    .await;
+
+    let request_counters = distribute_requests(&balancebeam, upstreams, n_requests, None).await;
+    assert_eq!(request_counters, vec![30, 30, 30]);
+
+    log::info!("All done :)");
+}
+
+/// Least-connections balancing should spread sequential requests evenly, since every upstream is
+/// idle by the time the next request arrives
+#[tokio::test]
+async fn test_load_distribution_least_connections() {
+    let n_upstreams = 3;
+    let n_requests = 90;
+    let (balancebeam, upstreams) = setup_with_args(
+        n_upstreams,
+        None,
+        None,
+        &["--balance-strategy", "least-connections"],
+    )
+    .await;
+
+    let request_counters = distribute_requests(&balancebeam, upstreams, n_requests, None).await;
+    assert_distribution(&request_counters, &[30.0; 3]);
+
+    log::info!("All done :)");
+}
+
+/// Weighted balancing should send each upstream a share of the requests proportional to its weight
+#[tokio::test]
+async fn test_load_distribution_weighted_random() {
+    init_logging();
+    let n_requests = 200;
+    let weights = [1, 3];
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..weights.len() {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_specs: Vec<String> = upstreams
+        .iter()
+        .zip(weights.iter())
+        .map(|(upstream, weight)| format!("{}={}", upstream.address(), weight))
+        .collect();
+    let upstream_specs: Vec<&str> = upstream_specs.iter().map(|spec| spec.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_specs,
+        Some(600),
+        None,
+        &["--balance-strategy", "weighted-random"],
+    )
+    .await;
+
+    let request_counters = distribute_requests(&balancebeam, upstreams, n_requests, None).await;
+    assert_distribution(&request_counters, &[50.0, 150.0]);
+
+    log::info!("All done :)");
+}
+
+/// Consistent hashing on a header should send every request with the same header value to the same
+/// upstream
+#[tokio::test]
+async fn test_load_distribution_consistent_hash_header() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, upstreams) = setup_with_args(
+        n_upstreams,
+        Some(600),
+        None,
+        &[
+            "--balance-strategy",
+            "consistent-hash",
+            "--hash-header",
+            "x-user",
+        ],
+    )
+    .await;
+
+    let mut request_counters = distribute_requests(
+        &balancebeam,
+        upstreams,
+        n_requests,
+        Some(("x-user", "alice")),
+    )
+    .await;
+    request_counters.sort_unstable();
+    assert_eq!(request_counters, vec![0, 0, n_requests]);
+
+    log::info!("All done :)");
+}
+
+/// Consistent hashing on the client IP should send every request from the same client to the same
+/// upstream
+#[tokio::test]
+async fn test_load_distribution_consistent_hash_client_ip() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, upstreams) = setup_with_args(
+        n_upstreams,
+        Some(600),
+        None,
+        &["--balance-strategy", "consistent-hash"],
+    )
+    .await;
+
+    let mut request_counters = distribute_requests(&balancebeam, upstreams, n_requests, None).await;
+    request_counters.sort_unstable();
+    assert_eq!(request_counters, vec![0, 0, n_requests]);
+
+    log::info!("All done :)");
+}
+
+/// Open a few hundred client connections at once and make sure every one of them gets a response
+/// and that every request makes it to an upstream
+#[tokio::test]
+async fn test_many_concurrent_clients() {
+    let n_upstreams = 3;
+    let n_clients = 300;
+    // Sending this many requests takes a while, so push active health checks out of the way to
+    // keep them from being counted as requests below
+    let (balancebeam, mut upstreams) = setup_with_params(n_upstreams, Some(600), None).await;
+    let balancebeam = Arc::new(balancebeam);
+
+    let mut tasks = Vec::new();
+    for i in 0..n_clients {
+        let balancebeam = balancebeam.clone();
+        tasks.push(tokio::task::spawn(async move {
+            let path = format!("/client-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }));
+    }
+    for join_handle in tasks {
+        join_handle.await.expect("Task panicked");
+    }
+
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(
+        total_request_count, n_clients,
+        "Upstream servers did not receive the expected number of requests"
+    );
 
     log::info!("All done :)");
 }
@@ -121,6 +330,110 @@ async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Ser
     }
 }
 
+/// Sends requests over one keep-alive connection, killing the upstream that served the first one
+/// partway through. The rest of the requests on the connection should move to the other upstream.
+#[tokio::test]
+async fn test_keep_alive_failover() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        Some(600),
+        None,
+        &["--balance-strategy", "round-robin"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let send = |path: String| {
+        let request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests");
+        async move {
+            let response_text = request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .text()
+                .await
+                .expect("Balancebeam replied with a malformed response");
+            assert!(
+                response_text.contains(&format!("GET {} HTTP/1.1", path)),
+                "balancebeam returned unexpected response. Failover may not be working."
+            );
+        }
+    };
+
+    // Round-robin sends the first request to the first upstream
+    send("/before-kill".to_string()).await;
+    log::info!("Killing the upstream that served the first request");
+    upstreams.remove(0).stop().await;
+
+    for i in 0..6 {
+        send(format!("/after-kill-{}", i)).await;
+    }
+    assert_eq!(upstreams.pop().unwrap().stop().await, 6);
+
+    log::info!("All done :)");
+}
+
+/// Starts a server that accepts connections and immediately hangs up on them, simulating an
+/// upstream that fails partway through a request. Returns its address.
+async fn start_hang_up_server() -> String {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => drop(stream),
+                Err(_) => return,
+            }
+        }
+    });
+    address
+}
+
+/// Idempotent requests should be retried on another upstream when the first one fails partway
+/// through, while other requests should fail with 502 instead of possibly being applied twice
+#[tokio::test]
+async fn test_failover_mid_request() {
+    init_logging();
+    for (method, expected_status) in [("GET", 200), ("PUT", 200), ("POST", 502)].iter() {
+        let hang_up_address = start_hang_up_server().await;
+        let upstream = EchoServer::new().await;
+        // Round-robin sends the first request to the server that hangs up
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&hang_up_address, &upstream.address],
+            Some(600),
+            None,
+            &["--balance-strategy", "round-robin"],
+        )
+        .await;
+
+        log::info!("Sending a {} request to an upstream that hangs up", method);
+        let client = reqwest::Client::new();
+        let response = client
+            .request(
+                method.parse().unwrap(),
+                &format!("http://{}/mid-request", balancebeam.address),
+            )
+            .header("x-sent-by", "balancebeam-tests")
+            .body("hello")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            *expected_status,
+            "unexpected status for {} request",
+            method
+        );
+        let expected_upstream_requests = if *expected_status == 200 { 1 } else { 0 };
+        assert_eq!(Box::new(upstream).stop().await, expected_upstream_requests);
+    }
+
+    log::info!("All done :)");
+}
+
 /// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
 /// make sure requests continue to work
 #[tokio::test]
@@ -131,6 +444,36 @@ async fn test_passive_health_checks() {
     log::info!("All done :)");
 }
 
+/// Kill every upstream and make sure balancebeam responds with 502 Bad Gateway instead of hanging
+/// or dropping the connection
+#[tokio::test]
+async fn test_all_upstreams_dead() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) = setup(n_upstreams).await;
+
+    log::info!("Killing all of the upstream servers");
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    for i in 0..3 {
+        log::info!("Sending request #{} with no upstream servers alive", i);
+        let client = reqwest::Client::new();
+        let response = client
+            .get(&format!(
+                "http://{}/no-upstreams-{}",
+                balancebeam.address, i
+            ))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 502);
+    }
+
+    log::info!("All done :)");
+}
+
 /// Verify that the active health checks are monitoring HTTP status, rather than simply depending
 /// on whether connections can be established to determine whether an upstream is up:
 ///
@@ -240,14 +583,19 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
-/// Enable rate limiting and ensure that requests fail after sending more than the threshold
-#[tokio::test]
-async fn test_rate_limiting() {
+/// Enable rate limiting with the given algorithm and ensure that requests fail after sending more
+/// than the threshold
+async fn try_rate_limiting(algorithm: &str) {
     let n_upstreams = 1;
     let rate_limit_threshold = 5;
     let num_extra_requests: usize = 3;
-    let (balancebeam, mut upstreams) =
-        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        None,
+        Some(rate_limit_threshold),
+        &["--rate-limit-algorithm", algorithm],
+    )
+    .await;
 
     log::info!(
         "Sending some basic requests to the server, within the rate limit threshold. These \
@@ -291,6 +639,23 @@ async fn test_rate_limiting() {
         total_request_count += upstream.stop().await;
     }
     assert_eq!(total_request_count, rate_limit_threshold);
+}
 
+/// Enable rate limiting and ensure that requests fail after sending more than the threshold
+#[tokio::test]
+async fn test_rate_limiting() {
+    try_rate_limiting("fixed-window").await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_rate_limiting_sliding_window_log() {
+    try_rate_limiting("sliding-window-log").await;
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_rate_limiting_token_bucket() {
+    try_rate_limiting("token-bucket").await;
     log::info!("All done :)");
 }
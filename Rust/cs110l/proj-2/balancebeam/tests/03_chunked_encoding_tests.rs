@@ -0,0 +1,306 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// An upstream that speaks raw bytes over its sockets, so that tests can control exactly how its
+/// responses are encoded
+struct RawUpstream {
+    address: String,
+    connections_received: Arc<AtomicUsize>,
+    /// Every request received so far, exactly as it was sent
+    requests_received: Arc<Mutex<Vec<String>>>,
+}
+
+impl RawUpstream {
+    /// Starts an upstream that answers every request on a connection with `response`
+    async fn new(response: &'static [u8]) -> RawUpstream {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections_received = Arc::new(AtomicUsize::new(0));
+        let requests_received = Arc::new(Mutex::new(Vec::new()));
+        let connections = connections_received.clone();
+        let requests = requests_received.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    while let Some((head, body)) = read_message(&mut stream).await {
+                        let request = head + &String::from_utf8(body).unwrap();
+                        requests.lock().unwrap().push(request);
+                        if stream.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        RawUpstream {
+            address,
+            connections_received,
+            requests_received,
+        }
+    }
+}
+
+/// Reads from the stream until the data read so far ends with `terminator`. Returns None if the
+/// stream is closed (or goes quiet for too long) first.
+async fn read_until(stream: &mut TcpStream, terminator: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !data.ends_with(terminator) {
+        match timeout(Duration::from_secs(5), stream.read(&mut byte)).await {
+            Ok(Ok(1)) => data.push(byte[0]),
+            _ => return None,
+        }
+    }
+    Some(data)
+}
+
+/// Reads an HTTP message (not a response to HEAD) from the stream. Returns the message head
+/// (start line and headers, lowercased) and the raw bytes of the body, which are still
+/// chunk-encoded if the message is chunked. Returns None if the stream is closed first.
+async fn read_message(stream: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let head = read_until(stream, b"\r\n\r\n").await?;
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    let body = if head.contains("transfer-encoding: chunked") {
+        // Read up to the last chunk (the test bodies never contain "0\r\n" themselves), then
+        // through the trailers up to the blank line
+        let mut body = read_until(stream, b"0\r\n").await?;
+        loop {
+            let line = read_until(stream, b"\r\n").await?;
+            body.extend_from_slice(&line);
+            if line == b"\r\n" {
+                break;
+            }
+        }
+        body
+    } else {
+        let content_length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map_or(0, |value| value.trim().parse().unwrap());
+        let mut body = vec![0_u8; content_length];
+        stream.read_exact(&mut body).await.ok()?;
+        body
+    };
+    Some((head, body))
+}
+
+/// Reads a response from balancebeam, panicking if there isn't a complete one
+async fn read_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    read_message(stream)
+        .await
+        .expect("balancebeam did not send a complete response")
+}
+
+async fn setup(upstream_address: &str) -> (BalanceBeam, TcpStream) {
+    init_logging();
+    // Keep active health checks out of the request counts
+    let balancebeam = BalanceBeam::new(&[upstream_address], Some(600), None).await;
+    let client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    (balancebeam, client)
+}
+
+/// Chunked upstream responses should be forwarded to the client chunked, trailers and all, without
+/// stalling the keep-alive connections on either side
+#[tokio::test]
+async fn test_chunked_response_with_trailers() {
+    let upstream = RawUpstream::new(
+        b"HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5\r\nhello\r\n7;note=ignored\r\n, world\r\n0\r\nx-checksum: abc\r\n\r\n",
+    )
+    .await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    for i in 0..3 {
+        log::info!("Sending request #{} for a chunked response", i);
+        client
+            .write_all(format!("GET /chunked-{} HTTP/1.1\r\nHost: test\r\n\r\n", i).as_bytes())
+            .await
+            .unwrap();
+        let (head, body) = read_response(&mut client).await;
+        assert!(head.starts_with("http/1.1 200 ok"));
+        assert!(!head.contains("content-length"));
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "5\r\nhello\r\n7\r\n, world\r\n0\r\nx-checksum: abc\r\n\r\n"
+        );
+    }
+
+    assert_eq!(upstream.requests_received.lock().unwrap().len(), 3);
+    assert_eq!(
+        upstream.connections_received.load(Ordering::SeqCst),
+        1,
+        "balancebeam should keep using its connection after a chunked response"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Chunked request bodies should be forwarded to the upstream chunked, trailers and all
+#[tokio::test]
+async fn test_chunked_request_with_trailers() {
+    let upstream = RawUpstream::new(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(
+            b"POST /chunked-upload HTTP/1.1\r\n\
+            Host: test\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nhello\r\n7\r\n, world\r\n0\r\nx-checksum: abc\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut client).await;
+    assert!(head.starts_with("http/1.1 204"));
+
+    let requests = upstream.requests_received.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("post /chunked-upload http/1.1\r\n"));
+    assert!(requests[0].contains("transfer-encoding: chunked\r\n"));
+    assert!(
+        requests[0].ends_with("\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\nx-checksum: abc\r\n\r\n")
+    );
+
+    log::info!("All done :)");
+}
+
+/// A chunked request should reach an ordinary HTTP server intact. (The echo server doesn't accept
+/// trailers, so this request doesn't have any.)
+#[tokio::test]
+async fn test_chunked_request() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(
+            b"POST /chunked-upload HTTP/1.1\r\n\
+            Host: test\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, body) = read_response(&mut client).await;
+    assert!(head.starts_with("http/1.1 200 ok"));
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("POST /chunked-upload HTTP/1.1"));
+    assert!(body.contains("transfer-encoding: chunked"));
+    assert!(body.ends_with("\n\nhello, world"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// A request with a bad chunk size should be rejected with 400. (Its headers have already been
+/// streamed to the upstream by the time the bad chunk arrives, so the upstream sees a request that
+/// is cut short.)
+#[tokio::test]
+async fn test_malformed_chunk_size_in_request() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(
+            b"POST /bad-chunks HTTP/1.1\r\n\
+            Host: test\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            zz\r\nhello\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut client).await;
+    assert!(head.starts_with("http/1.1 400"));
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// An upstream response with a bad chunk size should be cut short. Its headers have already been
+/// streamed to the client by the time the bad chunk arrives, so all balancebeam can do is hang up.
+#[tokio::test]
+async fn test_malformed_chunk_size_in_response() {
+    let upstream = RawUpstream::new(
+        b"HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        nope\r\nhello\r\n0\r\n\r\n",
+    )
+    .await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(b"GET /bad-chunks HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(
+        read_message(&mut client).await.is_none(),
+        "balancebeam forwarded a malformed chunked body"
+    );
+
+    log::info!("All done :)");
+}
+
+/// A request with more than one Content-Length header could be read as a different request by the
+/// upstream, so it should be rejected with 400 without reaching the upstream
+#[tokio::test]
+async fn test_duplicate_content_length_in_request() {
+    let upstream = RawUpstream::new(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(
+            b"POST /smuggle HTTP/1.1\r\n\
+            Host: test\r\n\
+            Content-Length: 5\r\n\
+            Content-Length: 30\r\n\
+            \r\n\
+            helloGET /smuggled HTTP/1.1\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut client).await;
+    assert!(head.starts_with("http/1.1 400"));
+    assert!(upstream.requests_received.lock().unwrap().is_empty());
+
+    log::info!("All done :)");
+}
+
+/// An upstream response with more than one Content-Length header should be answered with 502
+#[tokio::test]
+async fn test_duplicate_content_length_in_response() {
+    let upstream = RawUpstream::new(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Length: 5\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        hello",
+    )
+    .await;
+    let (_balancebeam, mut client) = setup(&upstream.address).await;
+
+    client
+        .write_all(b"GET /two-lengths HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, _) = read_response(&mut client).await;
+    assert!(head.starts_with("http/1.1 502"));
+
+    log::info!("All done :)");
+}
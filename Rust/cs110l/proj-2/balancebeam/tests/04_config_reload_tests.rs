@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn upstream_config(address: &str) -> String {
+    format!("[[upstream]]\naddress = \"{}\"\n", address)
+}
+
+async fn setup(config_contents: &str) -> (BalanceBeam, ConfigFile) {
+    init_logging();
+    let config_file = ConfigFile::new(config_contents);
+    // Keep active health checks out of the request counts
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], Some(600), None, &["--config", config_file.path()]).await;
+    (balancebeam, config_file)
+}
+
+/// Sends a GET request over an open connection and returns the status line of the response
+async fn get(client: &mut TcpStream, path: &str) -> String {
+    client
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        assert_eq!(
+            client.read(&mut byte).await.unwrap(),
+            1,
+            "balancebeam closed the connection"
+        );
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |value| value.trim().parse().unwrap());
+    let mut body = vec![0_u8; content_length];
+    client.read_exact(&mut body).await.unwrap();
+    head.lines().next().unwrap().to_string()
+}
+
+/// Changing the upstreams in the config file and sending SIGHUP should move new requests to the
+/// new upstreams, without dropping client connections that are already open
+#[tokio::test]
+async fn test_reload_upstreams() {
+    let old_upstream = EchoServer::new().await;
+    let new_upstream = EchoServer::new().await;
+    let (balancebeam, config_file) = setup(&upstream_config(&old_upstream.address)).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    assert_eq!(get(&mut client, "/before-reload").await, "http/1.1 200 ok");
+
+    log::info!("Switching to the new upstream");
+    config_file.write(&upstream_config(&new_upstream.address));
+    balancebeam.reload_config().await;
+
+    // The client's connection should still be open, and its requests should go to the new upstream
+    assert_eq!(get(&mut client, "/after-reload").await, "http/1.1 200 ok");
+    assert_eq!(Box::new(old_upstream).stop().await, 1);
+    assert_eq!(Box::new(new_upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// A config file that fails to parse or validate should leave the current settings in place
+#[tokio::test]
+async fn test_invalid_config_keeps_current_settings() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, config_file) = setup(&upstream_config(&upstream.address)).await;
+
+    for contents in &[
+        "this is not toml",
+        "upstream = []",
+        "[health_check]\npath = \"no-leading-slash\"",
+    ] {
+        log::info!("Reloading invalid config {:?}", contents);
+        config_file.write(contents);
+        balancebeam.reload_config().await;
+        balancebeam
+            .get("/after-bad-reload")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Rate limits set in the config file should be applied on reload
+#[tokio::test]
+async fn test_reload_rate_limit() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, config_file) = setup(&upstream_config(&upstream.address)).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(get(&mut client, "/unlimited").await, "http/1.1 200 ok");
+    }
+
+    log::info!("Limiting clients to 2 requests per minute");
+    config_file.write(&format!(
+        "{}\n[rate_limit]\nmax_requests_per_minute = 2\n",
+        upstream_config(&upstream.address)
+    ));
+    balancebeam.reload_config().await;
+
+    for _ in 0..2 {
+        assert_eq!(get(&mut client, "/limited").await, "http/1.1 200 ok");
+    }
+    assert_eq!(
+        get(&mut client, "/limited").await,
+        "http/1.1 429 too many requests"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 5);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+
+/// Starts balancebeam with a metrics listener, returning balancebeam and the metrics address
+async fn setup(
+    upstreams: &[&str],
+    max_requests_per_minute: Option<usize>,
+    extra_args: &[&str],
+) -> (BalanceBeam, String) {
+    init_logging();
+    let metrics_address = free_address();
+    let mut args = vec!["--metrics-bind", &metrics_address];
+    args.extend_from_slice(extra_args);
+    // Keep active health checks out of the request counts
+    let balancebeam =
+        BalanceBeam::new_with_args(upstreams, Some(600), max_requests_per_minute, &args).await;
+    (balancebeam, metrics_address)
+}
+
+async fn scrape(metrics_address: &str, path: &str) -> (reqwest::StatusCode, String) {
+    let response = reqwest::get(&format!("http://{}{}", metrics_address, path))
+        .await
+        .expect("Error scraping metrics");
+    let status = response.status();
+    (status, response.text().await.unwrap())
+}
+
+/// The metrics endpoint should report requests, responses and rate limit rejections
+#[tokio::test]
+async fn test_request_metrics() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, metrics_address) = setup(&[&upstream.address], Some(3), &[]).await;
+
+    for i in 0..4 {
+        log::info!("Sending request #{}", i);
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let (status, metrics) = scrape(&metrics_address, "/metrics").await;
+    log::info!("Scraped metrics:\n{}", metrics);
+    assert_eq!(status, reqwest::StatusCode::OK);
+    let upstream_label = format!("upstream=\"{}\"", upstream.address);
+    for expected in &[
+        format!(
+            "balancebeam_upstream_requests_total{{{}}} 3",
+            upstream_label
+        ),
+        format!(
+            "balancebeam_upstream_responses_total{{{},code=\"200\"}} 3",
+            upstream_label
+        ),
+        format!(
+            "balancebeam_upstream_response_seconds_count{{{}}} 3",
+            upstream_label
+        ),
+        format!("balancebeam_upstream_up{{{}}} 1", upstream_label),
+        format!(
+            "balancebeam_upstream_active_connections{{{}}} 0",
+            upstream_label
+        ),
+        "balancebeam_rate_limited_total 1".to_string(),
+        "balancebeam_error_responses_total{code=\"429\"} 1".to_string(),
+        // One connection per request, plus the one the test harness makes to check that
+        // balancebeam is up
+        "balancebeam_client_connections_total 5".to_string(),
+    ] {
+        assert!(
+            metrics.lines().any(|line| line == expected),
+            "Metrics are missing {:?}",
+            expected
+        );
+    }
+
+    let (status, _) = scrape(&metrics_address, "/other").await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams that can't be reached should be reported as down
+#[tokio::test]
+async fn test_upstream_health_metrics() {
+    let live_upstream = EchoServer::new().await;
+    let dead_upstream = EchoServer::new().await;
+    let dead_address = dead_upstream.address.clone();
+    Box::new(dead_upstream).stop().await;
+    // With round-robin balancing, the first request goes to the dead upstream and fails over
+    let (balancebeam, metrics_address) = setup(
+        &[&dead_address, &live_upstream.address],
+        None,
+        &["--balance-strategy", "round-robin"],
+    )
+    .await;
+
+    balancebeam
+        .get("/fails-over")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let (_, metrics) = scrape(&metrics_address, "/metrics").await;
+    log::info!("Scraped metrics:\n{}", metrics);
+    for expected in &[
+        format!("balancebeam_upstream_up{{upstream=\"{}\"}} 0", dead_address),
+        format!(
+            "balancebeam_upstream_connect_failures_total{{upstream=\"{}\"}} 1",
+            dead_address
+        ),
+        format!(
+            "balancebeam_upstream_up{{upstream=\"{}\"}} 1",
+            live_upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_requests_total{{upstream=\"{}\"}} 1",
+            live_upstream.address
+        ),
+    ] {
+        assert!(
+            metrics.lines().any(|line| line == expected),
+            "Metrics are missing {:?}",
+            expected
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// The metrics listener should be held to the same header-read and keep-alive timeouts as clients
+#[tokio::test]
+async fn test_applies_client_timeouts() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, metrics_address) = setup(
+        &[&upstream.address],
+        None,
+        &["--header-read-timeout", "1", "--keep-alive-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Sitting idle on a metrics connection");
+    let mut idle = TcpStream::connect(&metrics_address).await.unwrap();
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), idle.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the idle metrics connection")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("Sending half a scrape's headers");
+    let mut slow = TcpStream::connect(&metrics_address).await.unwrap();
+    slow.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+    delay_for(Duration::from_millis(100)).await;
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), slow.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not give up on the slow scrape")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap();
+    log::info!("Metrics listener responded:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use rand::Rng;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Path to an access log in the temp directory, which is deleted when dropped
+struct LogFile {
+    path: PathBuf,
+}
+
+impl LogFile {
+    fn new() -> LogFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "balancebeam-test-{}.log",
+            rand::thread_rng().gen::<u64>()
+        ));
+        LogFile { path }
+    }
+
+    fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Returns the lines logged so far, giving balancebeam a moment to finish writing them
+    async fn lines(&self) -> Vec<String> {
+        delay_for(Duration::from_millis(200)).await;
+        std::fs::read_to_string(&self.path)
+            .expect("Could not read access log")
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn setup(
+    upstream: &EchoServer,
+    max_requests_per_minute: Option<usize>,
+    format: &str,
+) -> (BalanceBeam, LogFile) {
+    init_logging();
+    let log_file = LogFile::new();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(600),
+        max_requests_per_minute,
+        &[
+            "--access-log",
+            log_file.path(),
+            "--access-log-format",
+            format,
+        ],
+    )
+    .await;
+    (balancebeam, log_file)
+}
+
+/// Every request should get a JSON record naming the upstream it went to, or none if balancebeam
+/// answered it itself
+#[tokio::test]
+async fn test_json_access_log() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, log_file) = setup(&upstream, Some(2), "json").await;
+
+    balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .post("/second", "hello")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .get("/rate-limited")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let records: Vec<serde_json::Value> = log_file
+        .lines()
+        .await
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Access log line is not JSON"))
+        .collect();
+    assert_eq!(records.len(), 3);
+
+    assert_eq!(records[0]["client_ip"], "127.0.0.1");
+    assert_eq!(records[0]["upstream"], upstream.address.as_str());
+    assert_eq!(records[0]["method"], "GET");
+    assert_eq!(records[0]["uri"], "/first");
+    assert_eq!(records[0]["status"], 200);
+    assert_eq!(records[0]["bytes_in"], 0);
+    assert!(records[0]["bytes_out"].as_u64().unwrap() > 0);
+    assert!(records[0]["upstream_latency_ms"].is_number());
+
+    assert_eq!(records[1]["method"], "POST");
+    assert_eq!(records[1]["uri"], "/second");
+    assert_eq!(records[1]["bytes_in"], 5);
+
+    assert_eq!(records[2]["upstream"], serde_json::Value::Null);
+    assert_eq!(records[2]["status"], 429);
+    assert_eq!(records[2]["upstream_latency_ms"], serde_json::Value::Null);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Records in Common Log Format should start with the standard fields, followed by the upstream
+#[tokio::test]
+async fn test_common_log_format_access_log() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, log_file) = setup(&upstream, None, "clf").await;
+
+    let response_body = balancebeam
+        .get("/clf?x=1")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let lines = log_file.lines().await;
+    assert_eq!(lines.len(), 1);
+    log::info!("Access log record: {}", lines[0]);
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].contains(&format!(
+        "\"GET /clf?x=1 HTTP/1.1\" 200 {} {} 0 ",
+        response_body.len(),
+        upstream.address
+    )));
+
+    log::info!("All done :)");
+}
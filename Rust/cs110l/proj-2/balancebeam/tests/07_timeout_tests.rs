@@ -0,0 +1,136 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
+
+/// Starts an upstream that accepts connections but never responds to anything sent over them.
+/// Returns its address.
+async fn start_stalled_upstream() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind stalled upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            // Hold on to the connection so that it stays open
+            connections.push(stream);
+        }
+    });
+    address
+}
+
+async fn setup(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+    init_logging();
+    BalanceBeam::new_with_args(upstreams, Some(600), None, extra_args).await
+}
+
+/// Reads the head of a response and returns its status line, or None if balancebeam closed the
+/// connection first. Panics if nothing arrives within a few seconds.
+async fn read_status_line(client: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        let bytes_read = timeout(Duration::from_secs(5), client.read(&mut byte))
+            .await
+            .expect("Timed out waiting for balancebeam")
+            .unwrap_or(0);
+        if bytes_read == 0 {
+            return None;
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    Some(head.lines().next().unwrap().to_string())
+}
+
+/// An upstream that takes too long to respond should get the client a 504
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    let upstream_address = start_stalled_upstream().await;
+    let balancebeam = setup(&[&upstream_address], &["--upstream-response-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /stalls HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_status_line(&mut client).await.as_deref(),
+        Some("HTTP/1.1 504 Gateway Timeout")
+    );
+
+    log::info!("All done :)");
+}
+
+/// A client that starts a request but doesn't finish sending its headers should get a 408
+#[tokio::test]
+async fn test_header_read_timeout() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&[&upstream.address], &["--header-read-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: te")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_status_line(&mut client).await.as_deref(),
+        Some("HTTP/1.1 408 Request Timeout")
+    );
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// A client that stops sending a request body partway through should get a 408
+#[tokio::test]
+async fn test_body_read_timeout() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&[&upstream.address], &["--body-read-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"POST /slow HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nhel")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_status_line(&mut client).await.as_deref(),
+        Some("HTTP/1.1 408 Request Timeout")
+    );
+
+    log::info!("All done :)");
+}
+
+/// Keep-alive connections should be closed once they have been idle for the keep-alive timeout
+#[tokio::test]
+async fn test_keep_alive_timeout() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&[&upstream.address], &["--keep-alive-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /first HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        read_status_line(&mut client).await.as_deref(),
+        Some("HTTP/1.1 200 OK")
+    );
+
+    // Sit idle without sending another request. balancebeam should hang up once it has waited out
+    // the keep-alive timeout.
+    delay_for(Duration::from_secs(2)).await;
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the idle connection")
+        .unwrap();
+    log::info!("Connection closed after {} more bytes", rest.len());
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use rand::Rng;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, ClientConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// A self-signed certificate for one hostname, with its certificate and key written out to PEM
+/// files in the temp directory. The files are deleted when dropped.
+struct TestCertificate {
+    der: Vec<u8>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl TestCertificate {
+    fn new(hostname: &str) -> TestCertificate {
+        let certificate = rcgen::generate_simple_self_signed(vec![hostname.to_string()])
+            .expect("Could not generate certificate");
+        let prefix = format!(
+            "balancebeam-test-{}-{}",
+            hostname,
+            rand::thread_rng().gen::<u64>()
+        );
+        let cert_path = std::env::temp_dir().join(format!("{}.crt", prefix));
+        let key_path = std::env::temp_dir().join(format!("{}.key", prefix));
+        // Every serialization signs the certificate afresh, so take the DER from the PEM that
+        // balancebeam will be given rather than serializing again
+        let pem = certificate.serialize_pem().unwrap();
+        let der = pemfile::certs(&mut pem.as_bytes()).unwrap()[0].0.clone();
+        std::fs::write(&cert_path, pem).expect("Could not write certificate");
+        std::fs::write(&key_path, certificate.serialize_private_key_pem())
+            .expect("Could not write private key");
+        TestCertificate {
+            der,
+            cert_path,
+            key_path,
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        vec![
+            "--tls-cert".to_string(),
+            self.cert_path.to_str().unwrap().to_string(),
+            "--tls-key".to_string(),
+            self.key_path.to_str().unwrap().to_string(),
+        ]
+    }
+}
+
+impl Drop for TestCertificate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_path);
+        let _ = std::fs::remove_file(&self.key_path);
+    }
+}
+
+async fn setup(upstream: &EchoServer, certificates: &[&TestCertificate]) -> BalanceBeam {
+    init_logging();
+    let args: Vec<String> = certificates
+        .iter()
+        .flat_map(|certificate| certificate.args())
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    BalanceBeam::new_with_args(&[&upstream.address], Some(600), None, &args).await
+}
+
+/// Sends a GET request over HTTPS, asking for `hostname` with SNI and trusting only `trusted`.
+/// Returns the certificate balancebeam presented and the body of the response.
+async fn https_get(
+    balancebeam: &BalanceBeam,
+    hostname: &str,
+    trusted: &TestCertificate,
+    path: &str,
+) -> (Vec<u8>, String) {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add(&Certificate(trusted.der.clone()))
+        .unwrap();
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut stream = connector
+        .connect(DNSNameRef::try_from_ascii_str(hostname).unwrap(), stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    let presented = stream.get_ref().1.get_peer_certificates().unwrap()[0]
+        .0
+        .clone();
+
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, hostname).as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    assert!(
+        head.starts_with("http/1.1 200 ok"),
+        "Unexpected response {}",
+        head
+    );
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map(|value| value.trim().parse().unwrap())
+        .expect("Response has no Content-Length");
+    let mut body = vec![0_u8; content_length];
+    stream.read_exact(&mut body).await.unwrap();
+    (presented, String::from_utf8(body).unwrap())
+}
+
+/// balancebeam should terminate HTTPS and tell the upstream that the client used it
+#[tokio::test]
+async fn test_https_termination() {
+    let certificate = TestCertificate::new("alpha.test");
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&upstream, &[&certificate]).await;
+
+    let (_, response_body) = https_get(&balancebeam, "alpha.test", &certificate, "/secure").await;
+    log::info!("Response body:\n{}", response_body);
+    assert!(response_body.starts_with("GET /secure HTTP/1.1\n"));
+    assert!(response_body.contains("x-forwarded-proto: https\n"));
+
+    // Plain HTTP clients can't talk to a TLS listener
+    assert!(balancebeam.get("/insecure").await.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Each client should be given the certificate for the hostname it asked for with SNI
+#[tokio::test]
+async fn test_sni_certificate_selection() {
+    let alpha = TestCertificate::new("alpha.test");
+    let beta = TestCertificate::new("beta.test");
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&upstream, &[&alpha, &beta]).await;
+
+    let (presented, _) = https_get(&balancebeam, "beta.test", &beta, "/beta").await;
+    assert_eq!(presented, beta.der);
+    let (presented, _) = https_get(&balancebeam, "alpha.test", &alpha, "/alpha").await;
+    assert_eq!(presented, alpha.der);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a GET request for `path` with the given Host header on a new connection. Returns the
+/// status line and body of the response.
+async fn get(balancebeam: &BalanceBeam, host: &str, path: &str) -> (String, String) {
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host).as_bytes())
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |value| value.trim().parse().unwrap());
+    let mut body = vec![0_u8; content_length];
+    client.read_exact(&mut body).await.unwrap();
+    (
+        head.lines().next().unwrap().to_string(),
+        String::from_utf8(body).unwrap(),
+    )
+}
+
+/// Requests should go to the pool of the first route they match, with their path rewritten if the
+/// route says so. Requests that match no route should get a 404.
+#[tokio::test]
+async fn test_routes_to_pools() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let config_file = ConfigFile::new(&format!(
+        r#"
+        [[upstream]]
+        address = "{}"
+
+        [pools]
+        api = [{{ address = "{}" }}]
+
+        [[route]]
+        path_prefix = "/api"
+        strip_prefix = true
+        pool = "api"
+
+        [[route]]
+        host = "www.example.com"
+        "#,
+        default_upstream.address, api_upstream.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], Some(600), None, &["--config", config_file.path()]).await;
+
+    let (status, body) = get(&balancebeam, "anything.example.com", "/api/users?page=2").await;
+    assert_eq!(status, "http/1.1 200 ok");
+    assert!(
+        body.starts_with("GET /users?page=2 HTTP/1.1\n"),
+        "Path was not rewritten: {}",
+        body
+    );
+
+    let (status, body) = get(&balancebeam, "www.example.com:1100", "/index.html").await;
+    assert_eq!(status, "http/1.1 200 ok");
+    assert!(body.starts_with("GET /index.html HTTP/1.1\n"));
+
+    let (status, _) = get(&balancebeam, "other.example.com", "/index.html").await;
+    assert_eq!(status, "http/1.1 404 not found");
+
+    assert_eq!(Box::new(api_upstream).stop().await, 1);
+    assert_eq!(Box::new(default_upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
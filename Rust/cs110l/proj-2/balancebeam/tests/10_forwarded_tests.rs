@@ -0,0 +1,108 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends a GET request with the given extra header lines on a new connection. Returns the status
+/// line and body of the response.
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[&str]) -> (String, String) {
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: www.example.com\r\n", path);
+    for header in headers {
+        request += &format!("{}\r\n", header);
+    }
+    request += "\r\n";
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |value| value.trim().parse().unwrap());
+    let mut body = vec![0_u8; content_length];
+    client.read_exact(&mut body).await.unwrap();
+    (
+        head.lines().next().unwrap().to_string(),
+        String::from_utf8(body).unwrap(),
+    )
+}
+
+/// Forwarding headers from clients we don't trust should be replaced with our own
+#[tokio::test]
+async fn test_untrusted_forwarding_headers_are_replaced() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(600), None).await;
+
+    let (status, body) = get(
+        &balancebeam,
+        "/spoofed",
+        &[
+            "X-Forwarded-For: 1.2.3.4",
+            "X-Forwarded-Proto: https",
+            "Forwarded: for=1.2.3.4",
+        ],
+    )
+    .await;
+    log::info!("Upstream saw:\n{}", body);
+    assert_eq!(status, "http/1.1 200 ok");
+    for expected in &[
+        "x-forwarded-for: 127.0.0.1\n",
+        "x-forwarded-proto: http\n",
+        "x-forwarded-host: www.example.com\n",
+        "forwarded: for=127.0.0.1;proto=http;host=www.example.com\n",
+    ] {
+        assert!(
+            body.contains(expected),
+            "Upstream did not see {:?}",
+            expected
+        );
+    }
+    assert!(!body.contains("1.2.3.4"));
+
+    log::info!("All done :)");
+}
+
+/// Requests through a trusted proxy should be rate limited by the client the proxy forwarded them
+/// for, and keep the proxy's forwarding headers
+#[tokio::test]
+async fn test_trusted_proxy_client_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(600),
+        Some(1),
+        &["--trusted-proxy", "127.0.0.0/8"],
+    )
+    .await;
+
+    let (status, body) = get(
+        &balancebeam,
+        "/first",
+        &["X-Forwarded-For: 198.51.100.1", "X-Forwarded-Proto: https"],
+    )
+    .await;
+    log::info!("Upstream saw:\n{}", body);
+    assert_eq!(status, "http/1.1 200 ok");
+    assert!(body.contains("x-forwarded-for: 198.51.100.1, 127.0.0.1\n"));
+    assert!(body.contains("x-forwarded-proto: https\n"));
+
+    // Another client behind the same proxy has its own rate limit...
+    let (status, _) = get(&balancebeam, "/second", &["X-Forwarded-For: 198.51.100.2"]).await;
+    assert_eq!(status, "http/1.1 200 ok");
+    // ...but the first client has used up its allowance
+    let (status, _) = get(&balancebeam, "/third", &["X-Forwarded-For: 198.51.100.1"]).await;
+    assert_eq!(status, "http/1.1 429 too many requests");
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
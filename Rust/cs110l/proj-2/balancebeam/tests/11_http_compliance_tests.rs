@@ -0,0 +1,224 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Sends a raw request on `client` and reads back the response. Returns the (lowercased) response
+/// head and the body.
+async fn send(client: &mut TcpStream, request: &str) -> (String, String) {
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |value| value.trim().parse().unwrap());
+    let mut body = vec![0_u8; content_length];
+    client.read_exact(&mut body).await.unwrap();
+    (head, String::from_utf8(body).unwrap().to_lowercase())
+}
+
+/// Returns true if the other end has closed the connection
+async fn is_closed(client: &mut TcpStream) -> bool {
+    let mut buf = [0_u8; 1];
+    matches!(client.read(&mut buf).await, Ok(0) | Err(_))
+}
+
+/// Headers that only apply to the connection they were sent on, including any the client lists in
+/// its Connection header, should not be passed on, and both directions should get a Via header
+#[tokio::test]
+async fn test_strips_hop_by_hop_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(600), None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (head, body) = send(
+        &mut client,
+        "GET /hop HTTP/1.1\r\n\
+         Host: example.com\r\n\
+         Connection: keep-alive, X-Secret\r\n\
+         Keep-Alive: timeout=5\r\n\
+         X-Secret: hunter2\r\n\
+         Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+         TE: trailers\r\n\
+         X-Kept: yes\r\n\
+         \r\n",
+    )
+    .await;
+    log::info!("Upstream saw:\n{}", body);
+    assert!(head.starts_with("http/1.1 200 ok\r\n"));
+    assert!(head.contains("\r\nvia: 1.1 balancebeam\r\n"));
+    assert!(body.starts_with("get /hop http/1.1\n"));
+    assert!(body.contains("\nvia: 1.1 balancebeam\n"));
+    assert!(body.contains("\nx-kept: yes\n"));
+    for stripped in &[
+        "\nconnection:",
+        "\nkeep-alive:",
+        "\nx-secret:",
+        "\nproxy-authorization:",
+        "\nte:",
+    ] {
+        assert!(!body.contains(stripped), "{:?} was passed on", stripped);
+    }
+
+    // The client's keep-alive connection should still be usable
+    let (head, _) = send(
+        &mut client,
+        "GET /again HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(head.starts_with("http/1.1 200 ok\r\n"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 requests should be passed on as HTTP/1.0, and the client connection closed afterwards
+/// unless the client asked for it to be kept alive
+#[tokio::test]
+async fn test_http_10_keep_alive() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(600), None).await;
+
+    log::info!("Sending an HTTP/1.0 request without keep-alive");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (head, body) = send(&mut client, "GET /old HTTP/1.0\r\n\r\n").await;
+    assert!(head.contains(" 200 ok\r\n"));
+    assert!(head.contains("\r\nconnection: close\r\n"));
+    assert!(body.starts_with("get /old http/1.0\n"));
+    assert!(body.contains("\nvia: 1.0 balancebeam\n"));
+    assert!(is_closed(&mut client).await);
+
+    log::info!("Sending HTTP/1.0 requests with keep-alive");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        let (head, body) = send(
+            &mut client,
+            "GET /older HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        )
+        .await;
+        assert!(head.contains(" 200 ok\r\n"));
+        assert!(head.contains("\r\nconnection: keep-alive\r\n"));
+        assert!(body.starts_with("get /older http/1.0\n"));
+    }
+
+    log::info!("Asking an HTTP/1.1 connection to close");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (head, _) = send(
+        &mut client,
+        "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(head.starts_with("http/1.1 200 ok\r\n"));
+    assert!(head.contains("\r\nconnection: close\r\n"));
+    assert!(is_closed(&mut client).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// Requests sent back to back, without waiting for each response, should each be answered in turn
+#[tokio::test]
+async fn test_pipelined_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(600), None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(
+            b"POST /first HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello\
+              GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n\
+              GET /third HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    for expected in &["post /first ", "get /second ", "get /third "] {
+        let (head, body) = send(&mut client, "").await;
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert!(
+            body.starts_with(expected),
+            "expected {:?}, got {:?}",
+            expected,
+            body
+        );
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with `response`, whatever the request was.
+/// Returns its address.
+async fn start_raw_upstream(response: &'static [u8]) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                // The test requests have no bodies, so each one ends with a blank line
+                let mut head = Vec::new();
+                let mut byte = [0_u8; 1];
+                while let Ok(1) = stream.read(&mut byte).await {
+                    head.push(byte[0]);
+                    if head.ends_with(b"\r\n\r\n") {
+                        head.clear();
+                        if stream.write_all(response).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Interim 1xx responses should be passed on to the client ahead of the final response, and not be
+/// mistaken for the final response themselves
+#[tokio::test]
+async fn test_interim_responses() {
+    init_logging();
+    let upstream_address = start_raw_upstream(
+        b"HTTP/1.1 100 Continue\r\n\r\n\
+          HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+          HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(600), None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for i in 0..2 {
+        log::info!("Sending request #{}", i);
+        let (head, _) = send(
+            &mut client,
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 100 continue\r\n"));
+        let (head, _) = send(&mut client, "").await;
+        assert!(head.starts_with("http/1.1 103 "));
+        assert!(head.contains("\r\nlink: </style.css>; rel=preload\r\n"));
+        let (head, body) = send(&mut client, "").await;
+        assert!(head.starts_with("http/1.1 200 ok\r\n"));
+        assert_eq!(body, "done");
+    }
+
+    // HTTP/1.0 clients only get the final response
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (head, body) = send(&mut client, "GET /old HTTP/1.0\r\n\r\n").await;
+    assert!(head.starts_with("http/1.1 200 ok\r\n"));
+    assert_eq!(body, "done");
+
+    log::info!("All done :)");
+}
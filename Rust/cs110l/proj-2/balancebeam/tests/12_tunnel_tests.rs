@@ -0,0 +1,114 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Starts an upstream proxy that accepts every CONNECT request and then echoes back whatever comes
+/// through the tunnel. Returns its address.
+async fn start_connect_upstream() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind CONNECT upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let head = read_head(&mut stream).await;
+                log::info!("CONNECT upstream received:\n{}", head);
+                assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+        }
+    });
+    address
+}
+
+/// Reads a message head from the stream
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// A WebSocket handshake should pass through to the upstream, after which messages should flow both
+/// ways over the same connection
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], Some(600), None).await;
+
+    let client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let (mut websocket, response) = tokio_tungstenite::client_async("ws://localhost/chat", client)
+        .await
+        .expect("WebSocket handshake failed");
+    assert_eq!(response.status(), 101);
+    assert_eq!(response.headers()["via"], "1.1 balancebeam");
+
+    for message in &[
+        Message::Text("hello".to_string()),
+        Message::Binary(vec![0, 1, 2, 3]),
+        Message::Text("goodbye".to_string()),
+    ] {
+        websocket.send(message.clone()).await.unwrap();
+        let echoed = websocket.next().await.unwrap().unwrap();
+        assert_eq!(&echoed, message);
+    }
+    websocket.close(None).await.unwrap();
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// CONNECT requests should be refused unless --allow-connect is given, in which case the connection
+/// should be tunneled to the upstream once it accepts
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let upstream_address = start_connect_upstream().await;
+    let connect_request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+
+    log::info!("Sending CONNECT without --allow-connect");
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(600), None).await;
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(connect_request).await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+
+    log::info!("Sending CONNECT with --allow-connect");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], Some(600), None, &["--allow-connect"])
+            .await;
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(connect_request).await.unwrap();
+    let head = read_head(&mut client).await;
+    assert!(head.starts_with("HTTP/1.1 200 "));
+    for message in &[&b"not http at all"[..], b"\x16\x03\x01 still not http"] {
+        client.write_all(message).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, message);
+    }
+
+    // Hanging up our end of the tunnel should close the other end
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+
+use std::time::Duration;
+use tokio::time::delay_for;
+
+const CIRCUIT_BREAKER_ARGS: &[&str] = &[
+    "--circuit-breaker-failure-rate",
+    "50",
+    "--circuit-breaker-min-requests",
+    "2",
+    "--circuit-breaker-open-duration",
+    "1",
+];
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> reqwest::StatusCode {
+    reqwest::get(&format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+async fn scrape(metrics_address: &str) -> String {
+    reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error scraping metrics")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// Once an upstream has failed enough requests, requests should be turned away without reaching it
+/// until the breaker lets a trial request through
+#[tokio::test]
+async fn test_open_circuit_rejects_fast() {
+    init_logging();
+    let upstream = ErrorServer::new().await;
+    let metrics_address = free_address();
+    let mut args = vec!["--metrics-bind", &metrics_address];
+    args.extend_from_slice(CIRCUIT_BREAKER_ARGS);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], Some(600), None, &args).await;
+
+    for i in 0..2 {
+        log::info!("Sending failing request #{}", i);
+        assert_eq!(
+            get_status(&balancebeam, "/").await,
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+    log::info!("Sending requests to the open circuit");
+    for _ in 0..3 {
+        assert_eq!(
+            get_status(&balancebeam, "/").await,
+            reqwest::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+    let metrics = scrape(&metrics_address).await;
+    log::info!("Scraped metrics:\n{}", metrics);
+    let upstream_label = format!("upstream=\"{}\"", upstream.address);
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_circuit_state{{{},state=\"open\"}} 1\n",
+        upstream_label
+    )));
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_circuit_opened_total{{{}}} 1\n",
+        upstream_label
+    )));
+
+    log::info!("Waiting for the circuit to go half-open");
+    delay_for(Duration::from_millis(1100)).await;
+    // The trial request fails, so the circuit opens again
+    assert_eq!(
+        get_status(&balancebeam, "/").await,
+        reqwest::StatusCode::INTERNAL_SERVER_ERROR
+    );
+    assert_eq!(
+        get_status(&balancebeam, "/").await,
+        reqwest::StatusCode::SERVICE_UNAVAILABLE
+    );
+    let metrics = scrape(&metrics_address).await;
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_circuit_opened_total{{{}}} 2\n",
+        upstream_label
+    )));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// While one upstream's circuit is open, requests should go to the others
+#[tokio::test]
+async fn test_routes_around_open_circuit() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let working = EchoServer::new().await;
+    // Keep the circuit open for longer than the requests below take, so none of them is a trial
+    let mut args = CIRCUIT_BREAKER_ARGS.to_vec();
+    let open_duration = args
+        .iter()
+        .position(|arg| *arg == "--circuit-breaker-open-duration")
+        .unwrap();
+    args[open_duration + 1] = "30";
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &working.address],
+        Some(600),
+        None,
+        &args,
+    )
+    .await;
+
+    // Keep going until the failing upstream has had enough requests to trip its breaker
+    let mut failures = 0;
+    while failures < 2 {
+        if get_status(&balancebeam, "/").await != reqwest::StatusCode::OK {
+            failures += 1;
+        }
+    }
+    for i in 0..10 {
+        log::info!("Sending request #{} with the circuit open", i);
+        assert_eq!(get_status(&balancebeam, "/").await, reqwest::StatusCode::OK);
+    }
+
+    assert_eq!(Box::new(failing).stop().await, 2);
+    Box::new(working).stop().await;
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+
+async fn send(balancebeam: &BalanceBeam, method: reqwest::Method) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .request(method, &format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// Idempotent requests that get a status listed in --retry-on should be sent to another upstream,
+/// but other requests should get the failing upstream's response
+#[tokio::test]
+async fn test_retries_on_status() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let working = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address, &working.address],
+        Some(600),
+        None,
+        &[
+            "--balance-strategy",
+            "round-robin",
+            "--retry-on",
+            "500",
+            "--retry-max-attempts",
+            "2",
+        ],
+    )
+    .await;
+
+    for i in 0..6 {
+        log::info!("Sending GET request #{}", i);
+        assert_eq!(
+            send(&balancebeam, reqwest::Method::GET).await,
+            reqwest::StatusCode::OK
+        );
+    }
+    log::info!("Sending POST requests");
+    let mut statuses = vec![
+        send(&balancebeam, reqwest::Method::POST).await,
+        send(&balancebeam, reqwest::Method::POST).await,
+    ];
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        vec![
+            reqwest::StatusCode::OK,
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        ]
+    );
+
+    Box::new(failing).stop().await;
+    // Every request should have ended up at the working upstream exactly once, except for the
+    // POST that failed
+    assert_eq!(Box::new(working).stop().await, 7);
+
+    log::info!("All done :)");
+}
+
+/// Retries should stop once they use up the retry budget, however many attempts each request is
+/// allowed
+#[tokio::test]
+async fn test_retry_budget() {
+    init_logging();
+    let upstream = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(600),
+        None,
+        &[
+            "--retry-on",
+            "500",
+            "--retry-max-attempts",
+            "5",
+            "--retry-budget-percent",
+            "0",
+            "--retry-budget-min-per-second",
+            "1",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        log::info!("Sending request #{}", i);
+        assert_eq!(
+            send(&balancebeam, reqwest::Method::GET).await,
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    // The budget allows 10 retries in ten seconds: 4 each for the first two requests, then 2 for
+    // the third
+    assert_eq!(Box::new(upstream).stop().await, 3 + 10);
+
+    log::info!("All done :)");
+}
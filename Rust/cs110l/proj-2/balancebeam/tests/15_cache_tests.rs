@@ -0,0 +1,108 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Starts balancebeam with a cache in front of the upstream, logging to a file in the temp directory
+async fn setup(upstream: &EchoServer) -> (BalanceBeam, std::path::PathBuf) {
+    init_logging();
+    let mut log_path = std::env::temp_dir();
+    log_path.push(format!(
+        "balancebeam-test-{}.log",
+        rand::thread_rng().gen::<u64>()
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(600),
+        None,
+        &[
+            "--cache-size",
+            "1048576",
+            "--access-log",
+            log_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    (balancebeam, log_path)
+}
+
+/// Returns how the cache was involved in each logged request, giving balancebeam a moment to finish
+/// writing the log
+async fn cache_statuses(log_path: &std::path::Path) -> Vec<String> {
+    delay_for(Duration::from_millis(200)).await;
+    let statuses = std::fs::read_to_string(log_path)
+        .expect("Could not read access log")
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap().to_string())
+        .collect();
+    let _ = std::fs::remove_file(log_path);
+    statuses
+}
+
+/// Fresh responses should be served from the cache without reaching the upstream, and unsafe
+/// requests should remove them
+#[tokio::test]
+async fn test_serves_fresh_responses() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, log_path) = setup(&upstream).await;
+
+    for i in 0..3 {
+        log::info!("Sending request #{}", i);
+        let response_text = balancebeam
+            .get("/cacheable?max-age=60")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "cacheable response");
+    }
+    log::info!("Sending a POST to the cached resource");
+    balancebeam
+        .post("/cacheable?max-age=60", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .get("/cacheable?max-age=60")
+        .await
+        .expect("Error sending request to balancebeam");
+    // Responses that don't say they can be cached shouldn't be
+    balancebeam
+        .get("/uncacheable")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .get("/uncacheable")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    assert_eq!(
+        cache_statuses(&log_path).await,
+        vec!["miss", "hit", "hit", "-", "miss", "miss", "miss"]
+    );
+    assert_eq!(Box::new(upstream).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// Stale responses should be checked with the upstream, and served again if it says they haven't
+/// changed
+#[tokio::test]
+async fn test_revalidates_stale_responses() {
+    let upstream = EchoServer::new().await;
+    let (balancebeam, log_path) = setup(&upstream).await;
+
+    for i in 0..2 {
+        log::info!("Sending request #{}", i);
+        let response_text = balancebeam
+            .get("/cacheable?max-age=0")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "cacheable response");
+    }
+
+    assert_eq!(cache_statuses(&log_path).await, vec!["miss", "revalidated"]);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use std::io::Read;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Pieces of the body the chunked upstream sends, one per chunk
+const CHUNKS: [&str; 3] = [
+    "<p>first chunk</p>\n",
+    "<p>second chunk</p>\n",
+    "<p>last chunk</p>\n",
+];
+
+async fn setup(upstream: &str, types: &str) -> BalanceBeam {
+    init_logging();
+    BalanceBeam::new_with_args(
+        &[upstream],
+        Some(600),
+        None,
+        &[
+            "--compression",
+            "--compression-types",
+            types,
+            "--compression-min-size",
+            "0",
+        ],
+    )
+    .await
+}
+
+/// Starts an upstream that answers every request with an HTML page sent in CHUNKS with chunked
+/// transfer coding. Returns its address.
+async fn start_chunked_upstream() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind chunked upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0_u8; 1];
+                loop {
+                    while !head.ends_with(b"\r\n\r\n") {
+                        if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        head.push(byte[0]);
+                    }
+                    head.clear();
+                    let mut response = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n"
+                        .to_vec();
+                    for chunk in CHUNKS.iter() {
+                        response.extend_from_slice(
+                            format!("{:x}\r\n{}\r\n", chunk.len(), chunk).as_bytes(),
+                        );
+                    }
+                    response.extend_from_slice(b"0\r\n\r\n");
+                    if stream.write_all(&response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+async fn get(balancebeam: &BalanceBeam, accept_encoding: Option<&str>) -> reqwest::Response {
+    let mut request =
+        reqwest::Client::new().get(&format!("http://{}/compress-me", balancebeam.address));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Responses of an allowed type should be compressed with the coding the client prefers, and marked
+/// as varying with Accept-Encoding either way
+#[tokio::test]
+async fn test_compresses_allowed_types() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&upstream.address, "text/plain").await;
+
+    log::info!("Sending a request that accepts gzip");
+    let response = get(&balancebeam, Some("gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "Accept-Encoding");
+    assert!(!response.headers().contains_key("content-length"));
+    let compressed = response.bytes().await.unwrap();
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_string(&mut body)
+        .expect("Response body is not valid gzip");
+    assert!(body.starts_with("GET /compress-me HTTP/1.1\n"));
+
+    log::info!("Sending a request that prefers brotli");
+    let response = get(&balancebeam, Some("gzip;q=0.5, br")).await;
+    assert_eq!(response.headers()["content-encoding"], "br");
+    let compressed = response.bytes().await.unwrap();
+    let mut body = String::new();
+    brotli::Decompressor::new(&compressed[..], 4096)
+        .read_to_string(&mut body)
+        .expect("Response body is not valid brotli");
+    assert!(body.starts_with("GET /compress-me HTTP/1.1\n"));
+
+    log::info!("Sending a request that doesn't accept compression");
+    let response = get(&balancebeam, None).await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    assert_eq!(response.headers()["vary"], "Accept-Encoding");
+    let body = response.text().await.unwrap();
+    assert!(body.starts_with("GET /compress-me HTTP/1.1\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Responses whose type isn't on the allow-list should be passed on as they are
+#[tokio::test]
+async fn test_leaves_other_types_alone() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&upstream.address, "application/json").await;
+
+    let response = get(&balancebeam, Some("gzip, br")).await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    assert!(!response.headers().contains_key("vary"));
+    let body = response.text().await.unwrap();
+    assert!(body.starts_with("GET /compress-me HTTP/1.1\n"));
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Chunked responses should be compressed too, with the upstream's chunks decoded and the
+/// compressed body chunked afresh
+#[tokio::test]
+async fn test_compresses_chunked_responses() {
+    let upstream = start_chunked_upstream().await;
+    let balancebeam = setup(&upstream, "text/*").await;
+
+    log::info!("Sending a request that accepts gzip");
+    let response = get(&balancebeam, Some("gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["transfer-encoding"], "chunked");
+    let compressed = response.bytes().await.unwrap();
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_string(&mut body)
+        .expect("Response body is not valid gzip");
+    assert_eq!(body, CHUNKS.concat());
+
+    log::info!("Sending a request that doesn't accept compression");
+    let response = get(&balancebeam, None).await;
+    assert!(!response.headers().contains_key("content-encoding"));
+    assert_eq!(response.text().await.unwrap(), CHUNKS.concat());
+
+    log::info!("All done :)");
+}
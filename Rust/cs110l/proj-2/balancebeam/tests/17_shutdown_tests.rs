@@ -0,0 +1,168 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
+
+/// Starts an upstream that waits for `delay` before answering each request. Returns its address.
+async fn start_slow_upstream(delay: Duration) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind slow upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0_u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                delay_for(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+async fn setup(upstream: &str, drain_timeout: &str) -> BalanceBeam {
+    init_logging();
+    BalanceBeam::new_with_args(
+        &[upstream],
+        Some(600),
+        None,
+        &["--drain-timeout", drain_timeout],
+    )
+    .await
+}
+
+/// On SIGTERM, balancebeam should stop accepting connections, close idle ones, and exit
+/// successfully once in-flight requests have been answered
+#[tokio::test]
+async fn test_drains_in_flight_requests() {
+    let upstream = start_slow_upstream(Duration::from_millis(1000)).await;
+    let balancebeam = setup(&upstream, "10").await;
+    let address = balancebeam.address.clone();
+
+    let mut idle_client = TcpStream::connect(&address).await.unwrap();
+    let request_address = address.clone();
+    let request = tokio::spawn(async move {
+        let response = reqwest::get(&format!("http://{}/slow", request_address))
+            .await
+            .expect("In-flight request failed");
+        (response.status(), response.text().await.unwrap())
+    });
+    let connect_address = address.clone();
+    let new_connection = tokio::spawn(async move {
+        delay_for(Duration::from_millis(500)).await;
+        TcpStream::connect(&connect_address).await
+    });
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Shutting down balancebeam");
+    let status = balancebeam.shutdown(Duration::from_secs(5)).await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+
+    assert_eq!(
+        request.await.unwrap(),
+        (reqwest::StatusCode::OK, "slow".to_string())
+    );
+    assert!(
+        new_connection.await.unwrap().is_err(),
+        "balancebeam accepted a connection while shutting down"
+    );
+    let mut buf = [0_u8; 1];
+    assert_eq!(idle_client.read(&mut buf).await.unwrap_or(0), 0);
+
+    log::info!("All done :)");
+}
+
+/// A request that is in flight when shutdown starts should get the last response on its connection,
+/// and the response should say so rather than promise to keep the connection open
+#[tokio::test]
+async fn test_last_response_closes_connection() {
+    let upstream = start_slow_upstream(Duration::from_millis(1000)).await;
+    let balancebeam = setup(&upstream, "10").await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let response = tokio::spawn(async move {
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap().to_lowercase()
+    });
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Shutting down balancebeam with a keep-alive request in flight");
+    let status = balancebeam.shutdown(Duration::from_secs(5)).await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+    let response = response.await.unwrap();
+    log::info!("Client got:\n{}", response);
+    assert!(response.starts_with("http/1.1 200 ok\r\n"));
+    assert!(response.contains("\r\nconnection: close\r\n"));
+    assert!(response.ends_with("\r\n\r\nslow"));
+
+    log::info!("All done :)");
+}
+
+/// Keep-alive connections that are idle between requests should be closed straight away rather than
+/// holding up the shutdown
+#[tokio::test]
+async fn test_closes_idle_keep_alive_connections() {
+    let upstream = EchoServer::new().await;
+    let balancebeam = setup(&upstream.address, "10").await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /first HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0_u8; 1024];
+    while !String::from_utf8_lossy(&response).contains("GET /first") {
+        let bytes_read = client.read(&mut buf).await.unwrap();
+        assert!(bytes_read > 0, "balancebeam closed the connection early");
+        response.extend_from_slice(&buf[..bytes_read]);
+    }
+
+    log::info!("Shutting down balancebeam with an idle keep-alive connection open");
+    let status = balancebeam.shutdown(Duration::from_secs(5)).await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+    assert_eq!(client.read(&mut buf).await.unwrap_or(0), 0);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// If requests are still in flight at the drain deadline, balancebeam should exit anyway, with a
+/// status that says so
+#[tokio::test]
+async fn test_drain_deadline() {
+    let upstream = start_slow_upstream(Duration::from_secs(5)).await;
+    let balancebeam = setup(&upstream, "1").await;
+    let address = balancebeam.address.clone();
+
+    let request =
+        tokio::spawn(async move { reqwest::get(&format!("http://{}/slow", address)).await });
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Shutting down balancebeam");
+    let status = balancebeam.shutdown(Duration::from_secs(3)).await;
+    assert!(!status.success());
+    assert!(request.await.unwrap().is_err());
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,381 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
+
+const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// Starts balancebeam with the admin API enabled, returning the admin API's address
+async fn setup(upstreams: &[&str], extra_args: &[&str]) -> (BalanceBeam, String) {
+    init_logging();
+    let admin_address = free_address();
+    let mut args = vec!["--admin-bind", &admin_address, "--admin-token", ADMIN_TOKEN];
+    args.extend_from_slice(extra_args);
+    // Keep active health checks out of the request counts
+    let balancebeam = BalanceBeam::new_with_args(upstreams, Some(600), None, &args).await;
+    (balancebeam, admin_address)
+}
+
+/// Sends a request to the admin API, returning the status and the parsed JSON response
+async fn admin_request(
+    admin_address: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+    token: Option<&str>,
+) -> (reqwest::StatusCode, serde_json::Value) {
+    let mut request =
+        reqwest::Client::new().request(method, &format!("http://{}{}", admin_address, path));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(body) = body {
+        request = request
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+    }
+    let response = request.send().await.expect("Error sending admin request");
+    let status = response.status();
+    let body = response.text().await.unwrap();
+    log::info!("Admin API responded {}: {}", status, body);
+    (
+        status,
+        serde_json::from_str(&body).expect("Admin API returned invalid JSON"),
+    )
+}
+
+/// Returns the addresses of the upstreams in an admin API response
+fn addresses(upstreams: &serde_json::Value) -> Vec<&str> {
+    upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| upstream["address"].as_str().unwrap())
+        .collect()
+}
+
+/// Requests without the right bearer token should be turned away
+#[tokio::test]
+async fn test_requires_token() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&upstream.address], &[]).await;
+
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::GET,
+        "/upstreams",
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::GET,
+        "/upstreams",
+        None,
+        Some("wrong-token"),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::GET,
+        "/upstreams",
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(addresses(&upstreams), vec![upstream.address.as_str()]);
+    assert_eq!(upstreams[0]["healthy"], true);
+    assert_eq!(upstreams[0]["draining"], false);
+    assert_eq!(upstreams[0]["circuit_state"], "closed");
+
+    log::info!("All done :)");
+}
+
+/// Upstreams added through the admin API should get requests, and removed or drained ones shouldn't
+#[tokio::test]
+async fn test_changes_upstreams() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first.address], &[]).await;
+
+    log::info!("Adding the second upstream");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        Some(serde_json::json!({ "address": second.address, "weight": 2 })),
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(upstreams.as_array().unwrap().len(), 2);
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        Some(serde_json::json!({ "address": second.address })),
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+
+    log::info!("Changing the first upstream's weight");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::PUT,
+        &format!("/upstreams/{}/weight", first.address),
+        Some(serde_json::json!({ "weight": 3 })),
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    let weights: Vec<(&str, u64)> = upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["address"].as_str().unwrap(),
+                upstream["weight"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert!(weights.contains(&(first.address.as_str(), 3)));
+    assert!(weights.contains(&(second.address.as_str(), 2)));
+
+    log::info!("Draining the first upstream");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        &format!("/upstreams/{}/drain", first.address),
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    let drained = upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == first.address.as_str())
+        .unwrap();
+    assert_eq!(drained["draining"], true);
+    for i in 0..10 {
+        log::info!("Sending request #{}", i);
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Removing the first upstream");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        &format!("/upstreams/{}", first.address),
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(addresses(&upstreams), vec![second.address.as_str()]);
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        &format!("/upstreams/{}", first.address),
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    assert_eq!(Box::new(first).stop().await, 0);
+    assert_eq!(Box::new(second).stop().await, 10);
+
+    log::info!("All done :)");
+}
+
+/// Drained upstreams should get requests again once they are undrained
+#[tokio::test]
+async fn test_undrains_upstream() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first.address, &second.address], &[]).await;
+
+    log::info!("Draining the first upstream");
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        &format!("/upstreams/{}/drain", first.address),
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    for i in 0..10 {
+        log::info!("Sending request #{} while drained", i);
+        balancebeam
+            .get(&format!("/drained-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Undraining the first upstream");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        &format!("/upstreams/{}/drain", first.address),
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert!(upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|upstream| upstream["draining"] == false));
+    for i in 0..20 {
+        log::info!("Sending request #{} after undraining", i);
+        balancebeam
+            .get(&format!("/undrained-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        "/upstreams/127.0.0.1:1/drain",
+        None,
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    let first_requests = Box::new(first).stop().await;
+    let second_requests = Box::new(second).stop().await;
+    assert!(first_requests > 0, "undrained upstream got no requests");
+    assert_eq!(first_requests + second_requests, 30);
+
+    log::info!("All done :)");
+}
+
+/// A request without the token should be turned away before its body is read, and the connection
+/// closed since the unread body is still on it
+#[tokio::test]
+async fn test_refuses_body_without_token() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(&[&upstream.address], &[]).await;
+
+    let mut client = TcpStream::connect(&admin_address).await.unwrap();
+    // Promise a body but never send it
+    client
+        .write_all(b"POST /upstreams HTTP/1.1\r\nHost: admin\r\nContent-Length: 100\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("balancebeam waited for the body of an unauthorized request")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap().to_lowercase();
+    log::info!("Admin API responded:\n{}", response);
+    assert!(response.starts_with("http/1.1 401 unauthorized\r\n"));
+    assert!(response.contains("\r\nconnection: close\r\n"));
+
+    log::info!("All done :)");
+}
+
+/// Admin connections should be held to the same header-read and keep-alive timeouts as clients
+#[tokio::test]
+async fn test_applies_client_timeouts() {
+    let upstream = EchoServer::new().await;
+    let (_balancebeam, admin_address) = setup(
+        &[&upstream.address],
+        &["--header-read-timeout", "1", "--keep-alive-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Sitting idle on an admin connection");
+    let mut idle = TcpStream::connect(&admin_address).await.unwrap();
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), idle.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the idle admin connection")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("Sending half a request's headers");
+    let mut slow = TcpStream::connect(&admin_address).await.unwrap();
+    slow.write_all(b"GET /upstreams HTTP/1.1\r\n")
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(100)).await;
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), slow.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not give up on the slow admin request")
+        .unwrap();
+    let response = String::from_utf8(response).unwrap();
+    log::info!("Admin API responded:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+    log::info!("All done :)");
+}
+
+/// Changing an upstream's weight shouldn't replace the upstream, or an open circuit breaker would
+/// quietly close
+#[tokio::test]
+async fn test_weight_change_keeps_circuit_state() {
+    let upstream = ErrorServer::new().await;
+    let (balancebeam, admin_address) = setup(
+        &[&upstream.address],
+        &[
+            "--circuit-breaker-failure-rate",
+            "50",
+            "--circuit-breaker-min-requests",
+            "2",
+            "--circuit-breaker-open-duration",
+            "30",
+        ],
+    )
+    .await;
+
+    for i in 0..2 {
+        log::info!("Sending failing request #{}", i);
+        balancebeam
+            .get(&format!("/fail-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Changing the weight of the upstream with the open circuit");
+    let (status, upstreams) = admin_request(
+        &admin_address,
+        reqwest::Method::PUT,
+        &format!("/upstreams/{}/weight", upstream.address),
+        Some(serde_json::json!({ "weight": 5 })),
+        Some(ADMIN_TOKEN),
+    )
+    .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(upstreams[0]["weight"], 5);
+    assert_eq!(upstreams[0]["circuit_state"], "open");
+
+    let response = reqwest::get(&format!("http://{}/after", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
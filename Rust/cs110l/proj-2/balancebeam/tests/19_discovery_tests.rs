@@ -0,0 +1,159 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Sends `count` requests through balancebeam
+async fn send_requests(balancebeam: &BalanceBeam, count: usize) {
+    for i in 0..count {
+        log::info!("Sending request #{}", i);
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// A hostname upstream should become one upstream per address it resolves to, and upstreams should
+/// come and go as its records change
+#[tokio::test]
+async fn test_follows_dns_records() {
+    init_logging();
+    // Both upstreams listen on the same port, on different loopback addresses, so that they can
+    // sit behind one hostname
+    let first = EchoServer::new_at_address("127.0.0.2:0".to_string()).await;
+    let port = first.address.rsplit(':').next().unwrap().to_string();
+    let second = EchoServer::new_at_address(format!("127.0.0.3:{}", port)).await;
+    let hosts_file = ConfigFile::new("127.0.0.2 backend.test\n");
+    let upstream = format!("backend.test:{}", port);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        Some(600),
+        None,
+        &[
+            "--dns-hosts-file",
+            hosts_file.path(),
+            "--dns-refresh-interval",
+            "1",
+            "--balance-strategy",
+            "round-robin",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 2).await;
+
+    log::info!("Adding a second address for the upstream");
+    hosts_file.write("127.0.0.2 backend.test\n127.0.0.3 backend.test\n");
+    delay_for(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, 4).await;
+
+    log::info!("Taking away the first address");
+    hosts_file.write("127.0.0.3 backend.test\n");
+    delay_for(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, 3).await;
+
+    assert_eq!(Box::new(first).stop().await, 4);
+    assert_eq!(Box::new(second).stop().await, 5);
+
+    log::info!("All done :)");
+}
+
+/// Failing lookups shouldn't take upstreams out of rotation
+#[tokio::test]
+async fn test_keeps_addresses_when_lookups_fail() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit(':').next().unwrap();
+    let hosts_file = ConfigFile::new("127.0.0.1 backend.test\n");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("backend.test:{}", port)],
+        Some(600),
+        None,
+        &[
+            "--dns-hosts-file",
+            hosts_file.path(),
+            "--dns-refresh-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 2).await;
+    log::info!("Breaking DNS");
+    drop(hosts_file);
+    delay_for(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, 2).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+
+    log::info!("All done :)");
+}
+
+/// A hostname added through the admin API should be expanded into its addresses straight away,
+/// rather than proxied to as a single upstream until the next DNS refresh
+#[tokio::test]
+async fn test_expands_hostnames_added_through_admin_api() {
+    init_logging();
+    let original = EchoServer::new().await;
+    let first = EchoServer::new_at_address("127.0.0.2:0".to_string()).await;
+    let port = first.address.rsplit(':').next().unwrap().to_string();
+    let second = EchoServer::new_at_address(format!("127.0.0.3:{}", port)).await;
+    let hosts_file = ConfigFile::new("127.0.0.2 backend.test\n127.0.0.3 backend.test\n");
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&original.address],
+        Some(600),
+        None,
+        &[
+            "--dns-hosts-file",
+            hosts_file.path(),
+            "--dns-refresh-interval",
+            "600",
+            "--balance-strategy",
+            "round-robin",
+            "--admin-bind",
+            &admin_address,
+            "--admin-token",
+            "test-admin-token",
+        ],
+    )
+    .await;
+
+    log::info!("Adding the hostname upstream");
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/upstreams", admin_address))
+        .bearer_auth("test-admin-token")
+        .body(serde_json::json!({ "address": format!("backend.test:{}", port) }).to_string())
+        .send()
+        .await
+        .expect("Error sending admin request");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let mut addresses: Vec<&str> = upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| upstream["address"].as_str().unwrap())
+        .collect();
+    addresses.sort_unstable();
+    let mut expected = vec![
+        original.address.as_str(),
+        first.address.as_str(),
+        second.address.as_str(),
+    ];
+    expected.sort_unstable();
+    assert_eq!(addresses, expected);
+
+    send_requests(&balancebeam, 6).await;
+    assert_eq!(Box::new(original).stop().await, 2);
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 2);
+
+    log::info!("All done :)");
+}
@@ -1,13 +1,73 @@
-use rand::Rng;
-use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
 use tokio::time::delay_for;
 
+/// How long balancebeam gets to start up or reload its config before we give up on it
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// How many times balancebeam has reported reloading its config file (successfully or not)
+    reloads: Arc<AtomicUsize>,
+}
+
+/// Returns a local address with a port that nothing was listening on a moment ago
+pub fn free_address() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Could not find a free port")
+        .to_string()
+}
+
+/// Prints each line of output from balancebeam, counting the ones that report a config reload. We
+/// want to intercept and log this output (instead of letting the child inherit stderr and print
+/// directly to the terminal) so that the output can be suppressed if the test passes and displayed
+/// if it fails.
+fn forward_output<R: AsyncRead + Unpin + Send + 'static>(output: R, reloads: Arc<AtomicUsize>) {
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(output).lines();
+        while let Some(line) = reader
+            .next_line()
+            .await
+            .expect("I/O error reading from child output")
+        {
+            println!("Balancebeam output: {}", line);
+            if line.contains("Reloaded configuration from") || line.contains("Not reloading") {
+                reloads.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+}
+
+/// Waits until something is accepting connections on each of `addresses`. Returns false if the
+/// child exits first, which it does if it can't bind to one of them.
+async fn wait_until_listening(child: &mut Child, addresses: &[String]) -> bool {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    for address in addresses {
+        loop {
+            assert!(
+                Instant::now() < deadline,
+                "balancebeam did not start listening on {}",
+                address
+            );
+            tokio::select! {
+                _ = &mut *child => return false,
+                connected = TcpStream::connect(address) => {
+                    if connected.is_ok() {
+                        break;
+                    }
+                }
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+    }
+    true
 }
 
 impl BalanceBeam {
@@ -19,15 +79,71 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Like new(), but passes extra_args through to balancebeam after all the other arguments. Waits
+    /// until balancebeam is listening on its own address and any --metrics-bind or --admin-bind
+    /// address in extra_args. If it can't bind to its own address, it is started again on another.
+    #[allow(dead_code)]
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        for _ in 0..3 {
+            let address = free_address();
+            let reloads = Arc::new(AtomicUsize::new(0));
+            let mut child = BalanceBeam::spawn(
+                &address,
+                upstreams,
+                active_health_check_interval,
+                max_requests_per_minute,
+                extra_args,
+                &reloads,
+            );
+            let mut addresses = vec![address.clone()];
+            addresses.extend(
+                extra_args
+                    .windows(2)
+                    .filter(|pair| pair[0] == "--metrics-bind" || pair[0] == "--admin-bind")
+                    .map(|pair| pair[1].to_string()),
+            );
+            if wait_until_listening(&mut child, &addresses).await {
+                return BalanceBeam {
+                    child,
+                    address,
+                    reloads,
+                };
+            }
+            log::warn!("balancebeam exited while starting up on {}", address);
+        }
+        panic!("balancebeam failed to start");
+    }
+
+    fn spawn(
+        address: &str,
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
+        reloads: &Arc<AtomicUsize>,
+    ) -> Child {
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
+        cmd.arg("--bind").arg(address);
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
@@ -39,49 +155,67 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
-
-        // Print output from the child. We want to intercept and log this output (instead of letting
-        // the child inherit stderr and print directly to the terminal) so that the output can be
-        // suppressed if the test passes and displayed if it fails.
-        let stdout = child
-            .stdout
-            .take()
-            .expect("Child process somehow missing stdout pipe!");
-        tokio::spawn(async move {
-            let mut stdout_reader = BufReader::new(stdout).lines();
-            while let Some(line) = stdout_reader
-                .next_line()
-                .await
-                .expect("I/O error reading from child stdout")
-            {
-                println!("Balancebeam output: {}", line);
-            }
-        });
-        let stderr = child
-            .stderr
-            .take()
-            .expect("Child process somehow missing stderr pipe!");
-        tokio::spawn(async move {
-            let mut stderr_reader = BufReader::new(stderr).lines();
-            while let Some(line) = stderr_reader
-                .next_line()
-                .await
-                .expect("I/O error reading from child stderr")
-            {
-                println!("Balancebeam output: {}", line);
-            }
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
         });
 
-        // Hack: wait for executable to start running
-        delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        forward_output(
+            child
+                .stdout
+                .take()
+                .expect("Child process somehow missing stdout pipe!"),
+            reloads.clone(),
+        );
+        forward_output(
+            child
+                .stderr
+                .take()
+                .expect("Child process somehow missing stderr pipe!"),
+            reloads.clone(),
+        );
+        child
+    }
+
+    /// Sends balancebeam SIGHUP so that it re-reads its config file, and waits until it says it has
+    /// (or that it kept its current config)
+    #[allow(dead_code)]
+    pub async fn reload_config(&self) {
+        let reloads = self.reloads.load(Ordering::SeqCst);
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.child.id() as i32),
+            nix::sys::signal::Signal::SIGHUP,
+        )
+        .expect("Could not send SIGHUP to balancebeam");
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while self.reloads.load(Ordering::SeqCst) == reloads {
+            assert!(
+                Instant::now() < deadline,
+                "balancebeam did not reload its config"
+            );
+            delay_for(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Sends balancebeam SIGTERM and waits for it to finish draining its connections and exit.
+    /// Returns its exit status. Panics if it takes longer than `within`.
+    #[allow(dead_code)]
+    pub async fn shutdown(self, within: Duration) -> std::process::ExitStatus {
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.child.id() as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        )
+        .expect("Could not send SIGTERM to balancebeam");
+        tokio::time::timeout(within, self.child)
+            .await
+            .expect("Timed out waiting for balancebeam to exit")
+            .expect("Error waiting for balancebeam to exit")
     }
 
     #[allow(dead_code)]
@@ -0,0 +1,36 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A config file in the temp directory that is deleted when dropped
+#[allow(dead_code)]
+pub struct ConfigFile {
+    path: PathBuf,
+}
+
+#[allow(dead_code)]
+impl ConfigFile {
+    pub fn new(contents: &str) -> ConfigFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "balancebeam-test-{}.toml",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let config_file = ConfigFile { path };
+        config_file.write(contents);
+        config_file
+    }
+
+    pub fn write(&self, contents: &str) {
+        std::fs::write(&self.path, contents).expect("Could not write config file");
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
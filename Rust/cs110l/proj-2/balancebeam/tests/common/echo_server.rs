@@ -1,14 +1,18 @@
-use crate::common::server::Server;
+use crate::common::server::{self, Server};
 use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::handshake::server::create_response;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
 
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub connections_received: atomic::AtomicUsize,
 }
 
 async fn echo(
@@ -18,6 +22,16 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    if req
+        .headers()
+        .get(hyper::header::UPGRADE)
+        .is_some_and(|protocol| protocol.as_bytes().eq_ignore_ascii_case(b"websocket"))
+    {
+        return Ok(echo_websocket(req));
+    }
+    if req.uri().path() == "/cacheable" {
+        return Ok(cacheable(req));
+    }
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -29,7 +43,75 @@ async fn echo(
     req_text += "\n";
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(req_as_bytes))
+        .unwrap())
+}
+
+/// Answers requests for /cacheable?max-age=N with a response that caches may keep for N seconds and
+/// then revalidate with its ETag
+fn cacheable(req: Request<Body>) -> Response<Body> {
+    let max_age = req
+        .uri()
+        .query()
+        .and_then(|query| query.strip_prefix("max-age="))
+        .unwrap_or("0");
+    let etag = "\"cacheable\"";
+    let not_modified = req
+        .headers()
+        .get(hyper::header::IF_NONE_MATCH)
+        .is_some_and(|value| value == etag);
+    Response::builder()
+        .status(if not_modified {
+            hyper::StatusCode::NOT_MODIFIED
+        } else {
+            hyper::StatusCode::OK
+        })
+        .header(hyper::header::CACHE_CONTROL, format!("max-age={}", max_age))
+        .header(hyper::header::ETAG, etag)
+        .body(if not_modified {
+            Body::empty()
+        } else {
+            Body::from("cacheable response")
+        })
+        .unwrap()
+}
+
+/// Accepts a WebSocket handshake, then sends every text or binary message the client sends straight
+/// back to it
+fn echo_websocket(req: Request<Body>) -> Response<Body> {
+    let mut handshake = hyper::http::Request::new(());
+    *handshake.method_mut() = req.method().clone();
+    *handshake.version_mut() = req.version();
+    *handshake.headers_mut() = req.headers().clone();
+    let response = match create_response(&handshake) {
+        Ok(response) => response,
+        Err(error) => {
+            return Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(Body::from(error.to_string()))
+                .unwrap()
+        }
+    };
+    tokio::spawn(async move {
+        let upgraded = match req.into_body().on_upgrade().await {
+            Ok(upgraded) => upgraded,
+            Err(error) => {
+                log::error!("EchoServer WebSocket upgrade failed: {}", error);
+                return;
+            }
+        };
+        let mut websocket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        while let Some(Ok(message)) = websocket.next().await {
+            if (message.is_text() || message.is_binary()) && websocket.send(message).await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    let (parts, ()) = response.into_parts();
+    Response::from_parts(parts, Body::empty())
 }
 
 pub struct EchoServer {
@@ -41,23 +123,27 @@ pub struct EchoServer {
 
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        EchoServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let incoming = server::bind(&bind_addr_string).await;
+        let address = incoming.local_addr().to_string();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
             let service = make_service_fn(|_| {
                 let server_task_state = server_task_state.clone();
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
                 async move {
                     Ok::<_, hyper::Error>(service_fn(move |req| {
                         let server_task_state = server_task_state.clone();
@@ -65,7 +151,7 @@ impl EchoServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::builder(incoming)
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -80,9 +166,17 @@ impl EchoServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
+
+    /// Returns the number of TCP connections that have been opened to this server so far
+    #[allow(dead_code)]
+    pub fn connections_received(&self) -> usize {
+        self.state
+            .connections_received
+            .load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]
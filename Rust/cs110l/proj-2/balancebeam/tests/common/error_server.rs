@@ -1,8 +1,7 @@
-use crate::common::server::Server;
+use crate::common::server::{self, Server};
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -22,6 +21,7 @@ async fn return_error() -> Result<Response<Body>, hyper::Error> {
 pub struct ErrorServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
     pub address: String,
     state: Arc<ServerState>,
 }
@@ -29,13 +29,13 @@ pub struct ErrorServer {
 impl ErrorServer {
     #[allow(dead_code)]
     pub async fn new() -> ErrorServer {
-        let mut rng = rand::thread_rng();
-        ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        ErrorServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> ErrorServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let incoming = server::bind(&bind_addr_string).await;
+        let address = incoming.local_addr().to_string();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -56,7 +56,7 @@ impl ErrorServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::builder(incoming)
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -71,7 +71,7 @@ impl ErrorServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
 }
@@ -1,12 +1,18 @@
 mod balancebeam;
+mod config_file;
 mod echo_server;
 mod error_server;
 mod server;
 
 use std::sync;
 
+#[allow(unused_imports)]
+pub use balancebeam::free_address;
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use config_file::ConfigFile;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use server::Server;
 
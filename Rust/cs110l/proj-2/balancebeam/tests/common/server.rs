@@ -1,7 +1,28 @@
 use async_trait::async_trait;
+use hyper::server::conn::AddrIncoming;
+use std::time::Duration;
+use tokio::time::delay_for;
 
 #[async_trait]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
+    #[allow(dead_code)]
     fn address(&self) -> String;
 }
+
+/// Binds a test server to `address` (port 0 picks a free port). A server restarted at the address
+/// of one that was just stopped may find the port still taken for a moment, for instance by an
+/// outgoing connection that happened to pick it, so keep trying for a few seconds before giving up.
+pub async fn bind(address: &str) -> AddrIncoming {
+    let socket_address = address.parse().unwrap();
+    for _ in 0..50 {
+        match AddrIncoming::bind(&socket_address) {
+            Ok(incoming) => return incoming,
+            Err(err) => {
+                log::info!("Could not bind test server to {} yet: {}", address, err);
+                delay_for(Duration::from_millis(100)).await;
+            }
+        }
+    }
+    panic!("Could not bind test server to {}", address);
+}